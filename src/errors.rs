@@ -1 +1,14 @@
-error_chain!{}
+error_chain!{
+    errors {
+        /// The client has no session to act on.
+        UnknownSession(mac: String) {
+            description("client is not authorized")
+            display("client is not authorized: {}", mac)
+        }
+        /// The session never expires, there is nothing to extend.
+        UnlimitedSession(mac: String) {
+            description("the session does not expire")
+            display("the session does not expire: {}", mac)
+        }
+    }
+}
@@ -8,12 +8,16 @@ extern crate error_chain;
 extern crate futures;
 extern crate hyper;
 extern crate iptables;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate regex;
 #[macro_use]
 extern crate serde_json;
 extern crate tokio_core;
+#[macro_use]
 extern crate serde;
+extern crate toml;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
@@ -21,21 +25,39 @@ extern crate lazy_static;
 extern crate handlebars;
 extern crate carrier;
 extern crate percent_encoding;
+extern crate hmac;
+extern crate sha2;
+extern crate base64;
+extern crate inotify;
+extern crate signal_hook;
+extern crate openssl;
+extern crate tokio_openssl;
+extern crate tokio_io;
+extern crate libc;
+extern crate clap;
 
 #[cfg(test)]
 extern crate tokio_proto;
+#[cfg(test)]
+extern crate tempdir;
 
 pub mod errors;
 mod sentry;
 mod time_control;
 
-pub use sentry::sentry_main;
+pub use sentry::{cli_main, sentry_main};
 pub use time_control::check_public_wifi;
 pub use time_control::TimeControl;
 pub use time_control::PUBLIC_WIFI_TIME_CONTROL_PATH;
 
 
 fn main() {
-    sentry::sentry_main(None).unwrap();
+    if let Err(e) = cli_main() {
+        eprintln!("error: {}", e);
+        for cause in e.iter().skip(1) {
+            eprintln!("caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
 }
 
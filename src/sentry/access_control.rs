@@ -1,192 +1,522 @@
 use errors::*;
+use sentry::firewall::{Client, Counter, Firewall, Rate};
+use sentry::neighbors::Neighbors;
+use sentry::store::{Session, Store};
 
-use std::fs::File;
-use std::io::Read;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
-use iptables;
-
-use regex::Regex;
-
-use chrono::Duration;
 use chrono::offset::Utc;
 
-const IPT_CHAIN: &str = "prerouting_public_rule";
-const IPT_TABLE: &str = "nat";
-const CONFIG_FILE: &str = "/etc/zealot_rule_valid_time";
-
-lazy_static! {
-    static ref MAC_SOURCE_REGEX: Regex = Regex::new(
-        r"--mac-source\s([a-fA-F0-9:]{17})").unwrap();
-    static ref TIMESTAMP_REGEX: Regex = Regex::new(
-        r#""timestamp=(\d+)""#).unwrap();
-}
-
-#[derive(PartialEq, Debug)]
-struct Rule<'a> {
-    mac_source: &'a str,
-    timestamp: i64,
-}
-
-impl<'rule> Rule<'rule> {
-    fn parse<'a>(rule: &'a str) -> Option<Rule<'a>> {
-        let mac_source_capt = MAC_SOURCE_REGEX.captures(rule);
-        let timestamp_capt = TIMESTAMP_REGEX.captures(rule);
-
-        if mac_source_capt.is_none() || timestamp_capt.is_none() {
-            return None;
+/// Brings the firewall in line with the session store, returns the expired sessions.
+///
+/// Expired sessions are removed from both. Valid sessions missing in the firewall,
+/// e.g. after a reboot or a firewall reload, are installed again. Clients only known
+/// to the firewall are adopted into the store, in the zone they are found in. Clients
+/// let through in another zone than the one of their session are removed there, and
+/// sessions of zones the firewall does not manage anymore end like expired ones.
+///
+/// The store is not locked while the firewall is listed or changed. Clients only known
+/// to the firewall are not adopted if sessions were removed meanwhile, they may be on
+/// their way out, the next reconcile adopts the ones that stay.
+///
+/// # Arguments
+///
+/// `firewall` - The firewall backend holding the authorized clients.
+/// `store` - The session store.
+/// `valid_times` - The time it takes until an adopted access is expired, by zone name,
+///                 if the firewall does not know the expiry of the client. Accesses
+///                 adopted in other zones do not expire.
+pub fn reconcile(
+    firewall: &Firewall,
+    store: &Mutex<Store>,
+    valid_times: &HashMap<String, i64>,
+) -> Result<Vec<Session>> {
+    let now = Utc::now().timestamp();
+    let zones = firewall.zones();
+    // an entry of a session removed while listing is on its way out of the firewall
+    let removals = store.lock().unwrap().removals();
+    let mut entries = Vec::new();
+    for zone in &zones {
+        for entry in firewall.list(zone)? {
+            entries.push((zone.as_str(), entry));
+        }
+    }
+    let mut revoke = Vec::new();
+    let mut restore = Vec::new();
+    let mut expired = Vec::new();
+
+    let mut locked = store.lock().unwrap();
+    let adopt = locked.removals() == removals;
+    for &(zone, ref entry) in &entries {
+        match locked.get(&entry.mac).map(|session| session.zone_name() == zone) {
+            Some(true) => {}
+            Some(false) => {
+                info!("session moved out of zone {}: {}", zone, entry.mac);
+                revoke.push((zone.to_owned(), entry.mac.clone()));
+            }
+            None if !adopt => {}
+            None => locked.insert(Session {
+                mac: entry.mac.clone(),
+                ip: None,
+                hostname: None,
+                authorized: entry.timestamp,
+                expires: entry
+                    .expires
+                    .or_else(|| valid_times.get(zone).map(|v| entry.timestamp + v)),
+                quota: None,
+                packets: 0,
+                bytes: 0,
+                rate: Rate::default(),
+                zone: Some(zone.to_owned()),
+            })?,
         }
+    }
 
-        let timestamp_capt = timestamp_capt.and_then(|t| t.get(1));
-        if let Some(Ok(timestamp)) = timestamp_capt.map(|t| t.as_str().parse::<i64>()) {
-            Some(Rule {
-                mac_source: mac_source_capt.unwrap().get(1).unwrap().as_str(),
-                timestamp: timestamp,
-            })
-        } else {
-            None
+    for session in locked.sessions() {
+        let zone = session.zone_name();
+        let installed = entries
+            .iter()
+            .any(|&(z, ref entry)| z == zone && entry.mac.eq_ignore_ascii_case(&session.mac));
+
+        if session.is_expired(now) || !zones.iter().any(|z| z == zone) {
+            info!("session expired: {}", session.mac);
+            if installed {
+                revoke.push((zone.to_owned(), session.mac.clone()));
+            }
+            expired.push(session);
+        } else if !installed {
+            info!("session restored: {}", session.mac);
+            restore.push(session);
         }
     }
+    drop(locked);
 
-    fn to_string(&self) -> String {
-        format!(
-            r#"-m mac --mac-source {} -m comment --comment timestamp={} -j ACCEPT"#,
-            self.mac_source,
-            self.timestamp
-        )
+    for (zone, mac) in revoke {
+        firewall.revoke(&zone, &mac)?;
+    }
+    for session in restore {
+        // revoked in the meantime
+        if store.lock().unwrap().get(&session.mac) != Some(&session) {
+            continue;
+        }
+        firewall.authorize(
+            session.zone_name(),
+            &session.mac,
+            session.authorized,
+            session.expires,
+        )?;
     }
 
-    fn is_expired(&self, valid_time: Duration) -> bool {
-        self.timestamp + valid_time.num_seconds() < Utc::now().timestamp()
+    // sessions renewed in the meantime stay
+    let mut locked = store.lock().unwrap();
+    for session in &expired {
+        if locked.get(&session.mac) == Some(session) {
+            locked.remove(&session.mac)?;
+        }
     }
+    locked.flush_if_due()?;
+    Ok(expired)
 }
 
-fn read_valid_time() -> Duration {
-    if let Ok(mut file) = File::open(CONFIG_FILE) {
-        let mut time_str = String::new();
+/// The time of the last traffic of the authorized clients, to end idle sessions.
+#[derive(Default, Debug)]
+pub struct Activity {
+    last_active: Mutex<HashMap<String, i64>>,
+}
 
-        if file.read_to_string(&mut time_str).is_ok() {
-            if let Ok(time) = time_str.parse::<i64>() {
-                return Duration::seconds(time);
+impl Activity {
+    /// Records which clients are active at `now`, returns the sessions without traffic
+    /// for more than `timeout` seconds.
+    ///
+    /// Clients are tracked from their first check on, a restart of sentry restarts
+    /// their idle time.
+    pub fn idle(
+        &self,
+        sessions: &[Session],
+        neighbors: &Neighbors,
+        timeout: Option<i64>,
+        now: i64,
+    ) -> Vec<Session> {
+        let mut last_active = self.last_active.lock().unwrap();
+        let mut idle = Vec::new();
+        let mut current = HashMap::new();
+
+        for session in sessions {
+            let mac = session.mac.to_lowercase();
+            let last = if neighbors.is_active(&mac) {
+                now
+            } else {
+                last_active.get(&mac).cloned().unwrap_or(now)
+            };
+
+            if timeout.map_or(false, |timeout| now - last > timeout) {
+                idle.push(session.clone());
+            } else {
+                current.insert(mac, last);
             }
         }
+
+        *last_active = current;
+        idle
     }
+}
 
-    // default to 24 hours
-    Duration::hours(24)
+/// The traffic of the authorized clients, counted by the firewall.
+#[derive(Default, Debug)]
+pub struct Usage {
+    /// The clients the firewall counts and their counters at the last update, `None`
+    /// until the accounting is set up.
+    counted: Mutex<Option<(Vec<Client>, HashMap<String, Counter>)>>,
 }
 
-/// Checks for expired accesses in iptables
-///
-/// # Arguments
-///
-/// `valid_time` - The time it takes until an access is expired.
-pub fn check_for_expired(valid_time: i64) -> Result<()> {
-    let valid_time = Duration::seconds(valid_time);
+impl Usage {
+    /// Adds the traffic since the last update to the sessions in the store, returns
+    /// the sessions that used up their quota.
+    ///
+    /// The accounting of the firewall is replaced when the sessions or the addresses of
+    /// their clients change, or when the firewall lost it, e.g. by a reload. It is kept
+    /// if the counters can not be read, the traffic is counted by the next update.
+    pub fn update(
+        &self,
+        firewall: &Firewall,
+        store: &Mutex<Store>,
+        neighbors: &Neighbors,
+    ) -> Result<Vec<Session>> {
+        let mut counted = self.counted.lock().unwrap();
+        let mut lost = counted.is_none();
+        let counters = match *counted {
+            Some(_) => firewall
+                .counters()
+                .chain_err(|| "unable to read the traffic counters")?,
+            None => HashMap::new(),
+        };
+        let mut store = store.lock().unwrap();
+
+        if let Some((ref clients, ref mut last)) = *counted {
+            for client in clients {
+                let counter = match counters.get(&client.mac) {
+                    Some(&counter) => counter,
+                    None => {
+                        lost = true;
+                        continue;
+                    }
+                };
+                let previous = last.insert(client.mac.clone(), counter).unwrap_or_default();
+                let (packets, bytes) = if counter.bytes >= previous.bytes
+                    && counter.packets >= previous.packets
+                {
+                    (counter.packets - previous.packets, counter.bytes - previous.bytes)
+                } else {
+                    (counter.packets, counter.bytes)
+                };
+
+                if bytes > 0 || packets > 0 {
+                    if let Some(mut session) = store.get(&client.mac).cloned() {
+                        session.packets += packets;
+                        session.bytes += bytes;
+                        store.insert(session)?;
+                    }
+                }
+            }
+        }
 
-    let ipt = iptables::new(false).unwrap();
+        let sessions = store.sessions();
+        drop(store);
+        let clients: Vec<Client> = sessions
+            .iter()
+            .map(|session| client(session, neighbors))
+            .collect();
 
-    let rules = ipt.list(IPT_TABLE, IPT_CHAIN)
-        .chain_err(|| "Could not list the chain rules!")?;
+        if lost || counted.as_ref().map_or(true, |c| c.0 != clients) {
+            firewall.set_accounting(&clients)?;
+            *counted = Some((clients, HashMap::new()));
+        }
 
-    for rule in rules {
-        if let Some(rule) = Rule::parse(&rule) {
-            if rule.is_expired(valid_time) {
-                eprintln!(" session expired: {}", rule.mac_source);
-                ipt.delete(IPT_TABLE, IPT_CHAIN, &rule.to_string())
-                    .chain_err(|| format!("Error deleting rule: {}", rule.to_string()))?;
-            }
+        Ok(sessions.into_iter().filter(|s| s.is_over_quota()).collect())
+    }
+
+    /// Starts counting the traffic of a new session, without touching the accounting of
+    /// the other clients.
+    pub fn add(&self, firewall: &Firewall, session: &Session, neighbors: &Neighbors) -> Result<()> {
+        let client = client(session, neighbors);
+        let mut counted = self.counted.lock().unwrap();
+        firewall.set_client_accounting(&client)?;
+
+        if let Some((ref mut clients, ref mut last)) = *counted {
+            clients.retain(|c| c.mac != client.mac);
+            last.remove(&client.mac);
+            clients.push(client);
+            clients.sort_by(|a, b| a.mac.cmp(&b.mac));
+        }
+        Ok(())
+    }
+
+    /// Stops counting the traffic of an ended session, without touching the accounting
+    /// of the other clients.
+    pub fn remove(&self, firewall: &Firewall, mac: &str) -> Result<()> {
+        let mac = mac.to_lowercase();
+        let mut counted = self.counted.lock().unwrap();
+        firewall.remove_accounting(&mac)?;
+
+        if let Some((ref mut clients, ref mut last)) = *counted {
+            clients.retain(|c| c.mac != mac);
+            last.remove(&mac);
         }
+        Ok(())
     }
+}
 
-    Ok(())
+/// The client of `session` as the firewall counts it.
+fn client(session: &Session, neighbors: &Neighbors) -> Client {
+    let mac = session.mac.to_lowercase();
+    let mut ips = neighbors.ips(&mac);
+    ips.sort();
+    Client {
+        mac,
+        ips,
+        rate: session.rate,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[test]
-    fn test_rule_parse() {
-        let expected_rule = Rule {
-            mac_source: "DE:AD:BE:EF:DE:AD",
-            timestamp: 233445,
-        };
-
-        let rule = Rule::parse(
-            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD
-                     -m comment --comment \"timestamp=233445\" -j ACCEPT",
-        ).expect("Error parsing the rule");
-
-        assert_eq!(expected_rule, rule);
+    use sentry::firewall::Entry;
+    use sentry::firewall::testing::FakeFirewall;
+    use sentry::neighbors::testing::FakeNeighbors;
+
+    use tempdir::TempDir;
+
+    fn session(mac: &str, authorized: i64, expires: Option<i64>) -> Session {
+        Session {
+            mac: mac.to_owned(),
+            ip: None,
+            hostname: None,
+            authorized: authorized,
+            expires: expires,
+            quota: None,
+            packets: 0,
+            bytes: 0,
+            rate: Default::default(),
+            zone: None,
+        }
     }
 
     #[test]
-    fn test_rule_parse_fail() {
-        assert!(
-            Rule::parse(
-                "-A prerouting_public_rule -m mac
-                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
-            ).is_none()
+    fn test_reconcile() {
+        let dir = TempDir::new("access_control").unwrap();
+        let store = Mutex::new(Store::open(
+            dir.path().join("tmp/sessions.json"),
+            dir.path().join("etc/sessions.json"),
+        ));
+        let firewall = FakeFirewall::default();
+        let now = Utc::now().timestamp();
+
+        // wiped by a firewall reload
+        store
+            .lock()
+            .unwrap()
+            .insert(session("DE:AD:BE:EF:00:11", now, Some(now + 3600)))
+            .unwrap();
+        // expired
+        store
+            .lock()
+            .unwrap()
+            .insert(session("DE:AD:BE:EF:00:22", now - 7200, Some(now - 3600)))
+            .unwrap();
+        firewall
+            .authorize("public", "DE:AD:BE:EF:00:22", now - 7200, None)
+            .unwrap();
+        // unknown to the store
+        firewall.authorize("public", "DE:AD:BE:EF:00:33", now, None).unwrap();
+        firewall
+            .authorize("public", "DE:AD:BE:EF:00:44", now, Some(now + 60))
+            .unwrap();
+
+        let valid_times = [("public".to_owned(), 3600)].iter().cloned().collect();
+        let expired = reconcile(&firewall, &store, &valid_times).unwrap();
+        assert_eq!(
+            expired,
+            vec![session("DE:AD:BE:EF:00:22", now - 7200, Some(now - 3600))]
         );
-    }
 
-    #[test]
-    fn test_rule_parse_fail_mac_wrong() {
-        assert!(
-            Rule::parse(
-                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:EG:DE:AD
-                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
-            ).is_none()
+        let mut macs: Vec<String> = firewall
+            .list("public")
+            .unwrap()
+            .into_iter()
+            .map(|entry: Entry| entry.mac)
+            .collect();
+        macs.sort();
+        assert_eq!(
+            macs,
+            vec!["DE:AD:BE:EF:00:11", "DE:AD:BE:EF:00:33", "DE:AD:BE:EF:00:44"]
         );
 
-        assert!(
-            Rule::parse(
-                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:DE:AD
-                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
-            ).is_none()
+        assert_eq!(
+            store.lock().unwrap().sessions(),
+            vec![
+                session("DE:AD:BE:EF:00:11", now, Some(now + 3600)),
+                Session {
+                    zone: Some("public".to_owned()),
+                    ..session("DE:AD:BE:EF:00:33", now, Some(now + 3600))
+                },
+                Session {
+                    zone: Some("public".to_owned()),
+                    ..session("DE:AD:BE:EF:00:44", now, Some(now + 60))
+                },
+            ]
         );
     }
 
     #[test]
-    fn test_rule_parse_fail_timestamp_wrong() {
-        assert!(
-            Rule::parse(
-                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:DE:AD:DE
-                     -m comment --comment \"timestamp=hi\" -j ACCEPT"
-            ).is_none()
+    fn test_reconcile_zones() {
+        let dir = TempDir::new("access_control").unwrap();
+        let store = Mutex::new(Store::open(
+            dir.path().join("tmp/sessions.json"),
+            dir.path().join("etc/sessions.json"),
+        ));
+        let firewall = FakeFirewall::with_zones(&["lobby", "staff"]);
+        let now = Utc::now().timestamp();
+        let in_zone = |mac, zone: &str| Session {
+            zone: Some(zone.to_owned()),
+            ..session(mac, now, None)
+        };
+        let macs = |zone| -> Vec<String> {
+            firewall.list(zone).unwrap().into_iter().map(|e| e.mac).collect()
+        };
+
+        // moved from the staff to the lobby network
+        store.lock().unwrap().insert(in_zone("DE:AD:BE:EF:00:11", "lobby")).unwrap();
+        firewall.authorize("staff", "DE:AD:BE:EF:00:11", now, None).unwrap();
+        // of a zone that was removed
+        store.lock().unwrap().insert(in_zone("DE:AD:BE:EF:00:22", "conference")).unwrap();
+
+        let expired = reconcile(&firewall, &store, &HashMap::new()).unwrap();
+        assert_eq!(expired, vec![in_zone("DE:AD:BE:EF:00:22", "conference")]);
+        assert_eq!(macs("lobby"), vec!["DE:AD:BE:EF:00:11"]);
+        assert!(macs("staff").is_empty());
+
+        // authorized in one zone, a client stays captive in the others
+        firewall.authorize("lobby", "DE:AD:BE:EF:00:33", now, None).unwrap();
+        // adopted with the session length of its zone
+        firewall.authorize("staff", "DE:AD:BE:EF:00:44", now, None).unwrap();
+        let valid_times = [("staff".to_owned(), 600)].iter().cloned().collect();
+        reconcile(&firewall, &store, &valid_times).unwrap();
+        assert_eq!(macs("staff"), vec!["DE:AD:BE:EF:00:44"]);
+        assert_eq!(store.lock().unwrap().get("DE:AD:BE:EF:00:33"), Some(&in_zone("DE:AD:BE:EF:00:33", "lobby")));
+        assert_eq!(
+            store.lock().unwrap().get("DE:AD:BE:EF:00:44"),
+            Some(&Session { expires: Some(now + 600), ..in_zone("DE:AD:BE:EF:00:44", "staff") })
         );
     }
 
     #[test]
-    fn test_rule_expired() {
-        let duration = Duration::hours(1);
-        let time = Utc::now().timestamp();
-        let mut rule = Rule {
-            mac_source: "",
-            timestamp: time - duration.num_seconds() - 10,
+    fn test_usage() {
+        let dir = TempDir::new("access_control").unwrap();
+        let store = Mutex::new(Store::open(
+            dir.path().join("tmp/sessions.json"),
+            dir.path().join("etc/sessions.json"),
+        ));
+        let firewall = FakeFirewall::default();
+        let neighbors = FakeNeighbors::default();
+        let usage = Usage::default();
+        let count = |mac: &str, packets, bytes| {
+            firewall
+                .counters
+                .lock()
+                .unwrap()
+                .insert(mac.to_owned(), Counter { packets, bytes });
         };
 
-        assert!(rule.is_expired(duration));
-
-        rule = Rule {
-            mac_source: "",
-            timestamp: time,
-        };
-
-        assert!(!rule.is_expired(duration));
+        store
+            .lock()
+            .unwrap()
+            .insert(Session {
+                quota: Some(1000),
+                ..session("DE:AD:BE:EF:00:11", 1000, None)
+            })
+            .unwrap();
+        store.lock().unwrap().insert(session("DE:AD:BE:EF:00:22", 1000, None)).unwrap();
+
+        assert!(usage.update(&firewall, &store, &neighbors).unwrap().is_empty());
+        assert_eq!(firewall.accounting.lock().unwrap().len(), 2);
+
+        count("de:ad:be:ef:00:11", 10, 600);
+        assert!(usage.update(&firewall, &store, &neighbors).unwrap().is_empty());
+        count("de:ad:be:ef:00:11", 15, 1100);
+        let over = usage.update(&firewall, &store, &neighbors).unwrap();
+        assert_eq!(over.len(), 1);
+        assert_eq!((over[0].packets, over[0].bytes), (15, 1100));
+
+        // a new address of the client restarts the counters
+        neighbors.insert("192.168.8.2", "DE:AD:BE:EF:00:22");
+        count("de:ad:be:ef:00:22", 1, 100);
+        usage.update(&firewall, &store, &neighbors).unwrap();
+        assert_eq!(
+            firewall.accounting.lock().unwrap()[1].ips,
+            vec!["192.168.8.2".parse::<std::net::IpAddr>().unwrap()]
+        );
+        count("de:ad:be:ef:00:22", 2, 300);
+        usage.update(&firewall, &store, &neighbors).unwrap();
+        let session = store.lock().unwrap().get("de:ad:be:ef:00:22").cloned().unwrap();
+        assert_eq!((session.packets, session.bytes), (3, 400));
+
+        // lost by a firewall reload
+        firewall.counters.lock().unwrap().clear();
+        usage.update(&firewall, &store, &neighbors).unwrap();
+        assert_eq!(firewall.counters.lock().unwrap().len(), 2);
+
+        // new and ended sessions leave the counters of the others alone
+        count("de:ad:be:ef:00:11", 20, 1500);
+        let added = self::session("DE:AD:BE:EF:00:33", 1000, None);
+        store.lock().unwrap().insert(added.clone()).unwrap();
+        usage.add(&firewall, &added, &neighbors).unwrap();
+        store.lock().unwrap().remove("DE:AD:BE:EF:00:22").unwrap();
+        usage.remove(&firewall, "DE:AD:BE:EF:00:22").unwrap();
+        usage.update(&firewall, &store, &neighbors).unwrap();
+        let counters = firewall.counters.lock().unwrap().clone();
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters["de:ad:be:ef:00:11"].bytes, 1500);
+        assert_eq!(counters["de:ad:be:ef:00:33"], Counter::default());
+
+        // unreadable counters keep the accounting and the counts so far
+        let bytes = |mac| store.lock().unwrap().get(mac).unwrap().bytes;
+        let before = bytes("DE:AD:BE:EF:00:11");
+        *firewall.counters_fail.lock().unwrap() = true;
+        assert!(usage.update(&firewall, &store, &neighbors).is_err());
+        assert_eq!(firewall.counters.lock().unwrap()["de:ad:be:ef:00:11"].bytes, 1500);
+        *firewall.counters_fail.lock().unwrap() = false;
+        count("de:ad:be:ef:00:11", 21, 1600);
+        usage.update(&firewall, &store, &neighbors).unwrap();
+        assert_eq!(bytes("DE:AD:BE:EF:00:11"), before + 100);
     }
 
     #[test]
-    fn test_rule_to_string() {
-        let rule = Rule {
-            mac_source: "DE:AD:BE:DE:AD:DE",
-            timestamp: 3456,
-        };
-
-        let expected_result = "-m mac --mac-source DE:AD:BE:DE:AD:DE -m comment --comment \
-                               timestamp=3456 -j ACCEPT";
+    fn test_idle() {
+        let activity = Activity::default();
+        let neighbors = FakeNeighbors::default();
+        let sessions = vec![
+            session("DE:AD:BE:EF:00:11", 1000, None),
+            session("DE:AD:BE:EF:00:22", 1000, None),
+        ];
+        neighbors
+            .active
+            .lock()
+            .unwrap()
+            .insert("de:ad:be:ef:00:11".to_owned());
+
+        assert!(activity.idle(&sessions, &neighbors, Some(600), 1000).is_empty());
+        assert!(activity.idle(&sessions, &neighbors, Some(600), 1600).is_empty());
+        assert_eq!(
+            activity.idle(&sessions, &neighbors, Some(600), 1601),
+            vec![session("DE:AD:BE:EF:00:22", 1000, None)]
+        );
 
-        assert_eq!(expected_result, rule.to_string());
+        // without a timeout the activity is still tracked
+        neighbors.active.lock().unwrap().clear();
+        assert!(activity.idle(&sessions[..1], &neighbors, None, 2000).is_empty());
+        assert!(activity.idle(&sessions[..1], &neighbors, Some(600), 2201).is_empty());
+        assert_eq!(activity.idle(&sessions[..1], &neighbors, Some(600), 2202).len(), 1);
     }
-
 }
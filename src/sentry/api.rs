@@ -0,0 +1,246 @@
+//! The local management api, a json http api for on-device tooling.
+//!
+//! It has no authentication, `Settings::validate` only lets it listen on loopback.
+//!
+//! ```text
+//! GET  /health
+//! GET  /metrics
+//! GET  /sessions
+//! POST /sessions/<mac>/authorize[?duration=<seconds>][&quota=<bytes>][&rate_up=<kbit/s>][&rate_down=<kbit/s>]
+//! POST /sessions/<mac>/revoke
+//! POST /sessions/<mac>/extend?seconds=<seconds>      (sessions with an expiry only)
+//! POST /reload
+//! POST /bypass/on
+//! POST /bypass/off
+//! ```
+//!
+//! The bypass is not persisted, it ends when sentry restarts.
+
+use errors::*;
+use sentry::firewall::Rate;
+use sentry::ip;
+use sentry::sentry::Sentry;
+use sentry::service::query_param;
+
+use hyper::{self, Method, StatusCode};
+use hyper::header::{ContentLength, ContentType};
+use hyper::server::{self, Request, Response};
+
+use futures;
+use serde_json::{self, Value};
+
+pub const DEFAULT_API_ADDRESS: &str = "127.0.0.1:8445";
+
+fn json_response(status: StatusCode, body: &Value) -> Response {
+    let body = body.to_string();
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::json())
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn to_response(result: Result<Value>) -> Response {
+    match result {
+        Ok(body) => json_response(StatusCode::Ok, &body),
+        Err(Error(ErrorKind::UnknownSession(_), _)) => {
+            error_response(StatusCode::NotFound, "client is not authorized")
+        }
+        Err(Error(ErrorKind::UnlimitedSession(_), _)) => {
+            error_response(StatusCode::Conflict, "the session does not expire")
+        }
+        Err(e) => {
+            warn!("api request failed: {}", e);
+            error_response(StatusCode::InternalServerError, &e.to_string())
+        }
+    }
+}
+
+#[derive(Clone, new, Debug)]
+pub struct Api {
+    sentry: Sentry,
+}
+
+impl Api {
+    fn sessions(&self) -> Result<Value> {
+        serde_json::to_value(self.sentry.sessions()?)
+            .map(|sessions| json!({ "sessions": sessions }))
+            .chain_err(|| "unable to serialize the sessions")
+    }
+
+    fn session_action(&self, mac: &str, action: &str, query: &str) -> Response {
+        if !ip::is_mac(mac) {
+            return error_response(StatusCode::BadRequest, "invalid mac address");
+        }
+
+        let number = |name| query_param(query, name).map(|v| v.parse());
+        let result = match action {
+            "authorize" => {
+                let duration = match number("duration") {
+                    Some(Ok(duration)) => Some(duration),
+                    None => None,
+                    Some(Err(_)) => {
+                        return error_response(StatusCode::BadRequest, "invalid duration")
+                    }
+                };
+                let quota = match query_param(query, "quota").map(|q| q.parse()) {
+                    Some(Ok(quota)) => Some(quota),
+                    None => None,
+                    Some(Err(_)) => return error_response(StatusCode::BadRequest, "invalid quota"),
+                };
+                let rate = match (number("rate_up").transpose(), number("rate_down").transpose()) {
+                    (Ok(up), Ok(down)) => Rate { up, down },
+                    _ => return error_response(StatusCode::BadRequest, "invalid rate"),
+                };
+                self.sentry.authorize_mac(mac, None, duration, quota, rate)
+            }
+            "revoke" => self.sentry.revoke_mac(mac),
+            "extend" => match number("seconds") {
+                Some(Ok(seconds)) => self.sentry.extend_mac(mac, i64::from(seconds)),
+                _ => return error_response(StatusCode::BadRequest, "invalid seconds"),
+            },
+            _ => return error_response(StatusCode::NotFound, "not found"),
+        };
+
+        to_response(result.map(|_| json!({})))
+    }
+
+    fn route(&self, req: &Request) -> Response {
+        let query = req.uri().query().unwrap_or("");
+        let path: Vec<&str> = req.path().split('/').filter(|s| !s.is_empty()).collect();
+
+        match (req.method(), path.as_slice()) {
+            (&Method::Get, &["health"]) => to_response(self.sentry.sessions().map(|sessions| {
+                json!({
+                    "status": "ok",
+                    "identity": self.sentry.identity,
+                    "sessions": sessions.len(),
+                })
+            })),
+            (&Method::Get, &["metrics"]) => match self.sentry.sessions() {
+                Ok(sessions) => {
+                    let body = self.sentry.metrics.render(sessions.len());
+                    Response::new()
+                        .with_header(ContentType::plaintext())
+                        .with_header(ContentLength(body.len() as u64))
+                        .with_body(body)
+                }
+                Err(e) => error_response(StatusCode::InternalServerError, &e.to_string()),
+            },
+            (&Method::Get, &["sessions"]) => to_response(self.sessions()),
+            (&Method::Post, &["sessions", mac, action]) => self.session_action(mac, action, query),
+            (&Method::Post, &["reload"]) => to_response(self.sentry.reload().map(|_| json!({}))),
+            (&Method::Post, &["bypass", "on"]) => {
+                to_response(self.sentry.set_bypass(true).map(|_| json!({})))
+            }
+            (&Method::Post, &["bypass", "off"]) => {
+                to_response(self.sentry.set_bypass(false).map(|_| json!({})))
+            }
+            _ => error_response(StatusCode::NotFound, "not found"),
+        }
+    }
+}
+
+impl server::Service for Api {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = futures::future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        futures::future::ok(self.route(&req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::firewall::testing::FakeFirewall;
+    use sentry::sentry::testing;
+
+    use std::sync::Arc;
+
+    use std::sync::atomic::Ordering;
+
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    const TEST_MAC: &str = "DE:AD:BE:EF:00:11";
+
+    fn call(api: &Api, method: Method, uri: &str) -> StatusCode {
+        api.route(&Request::new(method, uri.parse().unwrap())).status()
+    }
+
+    #[test]
+    fn test_sessions() {
+        let dir = TempDir::new("api").unwrap();
+        let core = Core::new().unwrap();
+        let api = Api::new(testing::sentry(&core, dir.path()));
+
+        let authorize = format!("/sessions/{}/authorize?duration=60&quota=1000", TEST_MAC);
+        assert_eq!(call(&api, Method::Post, &authorize), StatusCode::Ok);
+        let session = api.sentry.sessions().unwrap()[0].clone();
+        assert_eq!(session.expires, Some(session.authorized + 60));
+        assert_eq!(session.quota, Some(1000));
+
+        let extend = format!("/sessions/{}/extend?seconds=60", TEST_MAC);
+        assert_eq!(call(&api, Method::Post, &extend), StatusCode::Ok);
+        assert_eq!(
+            api.sentry.sessions().unwrap()[0].expires,
+            Some(session.authorized + 120)
+        );
+
+        assert_eq!(call(&api, Method::Get, "/sessions"), StatusCode::Ok);
+        assert_eq!(call(&api, Method::Get, "/health"), StatusCode::Ok);
+        assert_eq!(call(&api, Method::Get, "/metrics"), StatusCode::Ok);
+        assert_eq!(api.sentry.metrics.authorizations.load(Ordering::Relaxed), 1);
+
+        let revoke = format!("/sessions/{}/revoke", TEST_MAC);
+        assert_eq!(call(&api, Method::Post, &revoke), StatusCode::Ok);
+        assert!(api.sentry.sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bypass() {
+        let dir = TempDir::new("api").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        let firewall = Arc::new(FakeFirewall::default());
+        sentry.firewall = firewall.clone();
+        let api = Api::new(sentry);
+
+        assert_eq!(call(&api, Method::Post, "/bypass/on"), StatusCode::Ok);
+        assert!(*firewall.bypassed.lock().unwrap());
+        assert_eq!(call(&api, Method::Post, "/bypass/off"), StatusCode::Ok);
+        assert!(!*firewall.bypassed.lock().unwrap());
+        assert_eq!(call(&api, Method::Post, "/bypass/maybe"), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let dir = TempDir::new("api").unwrap();
+        let core = Core::new().unwrap();
+        let api = Api::new(testing::sentry(&core, dir.path()));
+
+        let cases = [
+            (Method::Post, "/sessions/nope/authorize", StatusCode::BadRequest),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/authorize?duration=x", StatusCode::BadRequest),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/authorize?quota=-1", StatusCode::BadRequest),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/authorize?rate_up=fast", StatusCode::BadRequest),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/extend", StatusCode::BadRequest),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/unknown", StatusCode::NotFound),
+            (Method::Post, "/sessions/DE:AD:BE:EF:00:11/extend?seconds=60", StatusCode::NotFound),
+            (Method::Get, "/reload", StatusCode::NotFound),
+            (Method::Get, "/", StatusCode::NotFound),
+        ];
+
+        for &(ref method, uri, status) in &cases {
+            assert_eq!(call(&api, method.clone(), uri), status, "{} {}", method, uri);
+        }
+        assert!(api.sentry.sessions().unwrap().is_empty());
+    }
+}
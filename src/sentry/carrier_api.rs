@@ -0,0 +1,283 @@
+//! The sessions of this device as carrier endpoints.
+//!
+//! Lets the backend authorize clients after their browser is gone, e.g. when a
+//! payment finishes asynchronously. Only the identities listed in the `backends`
+//! key of the genesis `captif` config may call them.
+//!
+//! | route                 | headers                           |
+//! |-----------------------|-----------------------------------|
+//! | `/v1/sentry/authorize`| `mac`, `ip` (opt.), `duration` (opt.), `quota` (opt.), `rate_up` (opt.), `rate_down` (opt.) |
+//! | `/v1/sentry/revoke`   | `mac`                             |
+//! | `/v1/sentry/sessions` |                                   |
+//! | `/v1/sentry/events`   | `since` (opt.), the last sequence number seen |
+//!
+//! The events are only kept if `carrier` is enabled in the `events` config.
+
+use errors::*;
+use sentry::firewall::Rate;
+use sentry::ip;
+use sentry::sentry::Sentry;
+
+use std::sync::Mutex;
+use std::str;
+
+use serde_json::{self, Value};
+
+use carrier;
+use carrier::osaka;
+
+const ROUTE_AUTHORIZE: &str = "/v1/sentry/authorize";
+const ROUTE_REVOKE: &str = "/v1/sentry/revoke";
+const ROUTE_SESSIONS: &str = "/v1/sentry/sessions";
+const ROUTE_EVENTS: &str = "/v1/sentry/events";
+
+const STATUS_OK: u16 = 200;
+const STATUS_BAD_REQUEST: u16 = 400;
+const STATUS_FORBIDDEN: u16 = 403;
+const STATUS_NOT_FOUND: u16 = 404;
+const STATUS_ERROR: u16 = 500;
+
+struct Api {
+    sentry: Sentry,
+    backends: Vec<String>,
+}
+
+lazy_static! {
+    // carrier routes are plain functions, they find sentry here
+    static ref API: Mutex<Option<Api>> = Mutex::new(None);
+}
+
+fn error(status: u16, msg: &str) -> (u16, Value) {
+    (status, json!({ "error": msg }))
+}
+
+/// Answers a request of the backend identified by `caller`, returns status and body.
+fn handle<H>(api: &Api, caller: &str, route: &str, header: H) -> (u16, Value)
+where
+    H: Fn(&str) -> Option<String>,
+{
+    if !api.backends.iter().any(|b| b == caller) {
+        return error(STATUS_FORBIDDEN, "caller is not a backend of this device");
+    }
+
+    let mac = header("mac").filter(|mac| ip::is_mac(mac));
+
+    let result = match route {
+        ROUTE_AUTHORIZE => match mac {
+            Some(mac) => {
+                let ip = header("ip");
+                let number = |name| header(name).map(|v| v.parse());
+                let duration = match number("duration").transpose() {
+                    Ok(duration) => duration,
+                    Err(_) => return error(STATUS_BAD_REQUEST, "invalid duration"),
+                };
+                let quota = match header("quota").map(|q| q.parse()).transpose() {
+                    Ok(quota) => quota,
+                    Err(_) => return error(STATUS_BAD_REQUEST, "invalid quota"),
+                };
+                let rate = match (number("rate_up").transpose(), number("rate_down").transpose()) {
+                    (Ok(up), Ok(down)) => Rate { up, down },
+                    _ => return error(STATUS_BAD_REQUEST, "invalid rate"),
+                };
+                api.sentry
+                    .authorize_mac(&mac, ip.as_ref().map(|ip| ip.as_str()), duration, quota, rate)
+                    .map(|_| Value::Null)
+            }
+            None => return error(STATUS_BAD_REQUEST, "missing or invalid mac"),
+        },
+        ROUTE_REVOKE => match mac {
+            Some(mac) => api.sentry.revoke_mac(&mac).map(|_| Value::Null),
+            None => return error(STATUS_BAD_REQUEST, "missing or invalid mac"),
+        },
+        ROUTE_SESSIONS => api.sentry.sessions().and_then(|sessions| {
+            serde_json::to_value(sessions)
+                .map(|sessions| json!({ "sessions": sessions }))
+                .chain_err(|| "unable to serialize the sessions")
+        }),
+        ROUTE_EVENTS => match api.sentry.events.buffer {
+            Some(ref buffer) => {
+                let since = header("since").and_then(|s| s.parse().ok()).unwrap_or(0);
+                let events: Vec<Value> = buffer
+                    .since(since)
+                    .into_iter()
+                    .filter_map(|(seq, event)| {
+                        let mut event = serde_json::to_value(event).ok()?;
+                        event["seq"] = json!(seq);
+                        Some(event)
+                    })
+                    .collect();
+                Ok(json!({ "events": events }))
+            }
+            None => return error(STATUS_NOT_FOUND, "events are not enabled"),
+        },
+        _ => return error(STATUS_NOT_FOUND, "no such route"),
+    };
+
+    match result {
+        Ok(body) => (STATUS_OK, body),
+        Err(e) => error(STATUS_ERROR, &format!("{}", e)),
+    }
+}
+
+fn route(
+    _poll: osaka::Poll,
+    headers: carrier::headers::Headers,
+    caller: &carrier::identity::Identity,
+    mut stream: carrier::endpoint::Stream,
+) -> Option<osaka::Task<()>> {
+    let path = str::from_utf8(headers.path().unwrap_or_default())
+        .unwrap_or_default()
+        .to_owned();
+    let header = |name: &str| {
+        headers
+            .get(name.as_bytes())
+            .and_then(|v| str::from_utf8(v).ok())
+            .map(|v| v.to_owned())
+    };
+
+    let (status, body) = match *API.lock().unwrap() {
+        Some(ref api) => handle(api, &caller.to_string(), &path, header),
+        None => error(STATUS_ERROR, "sentry is not ready"),
+    };
+
+    if status == STATUS_OK {
+        stream.send(carrier::headers::Headers::ok().encode());
+        stream.send(serde_json::to_vec(&body).unwrap_or_default());
+    } else {
+        let msg = body["error"].as_str().unwrap_or_default().to_owned();
+        stream.send(carrier::headers::Headers::with_error(status, &msg).encode());
+    }
+
+    None
+}
+
+/// Publishes the routes with carrier, blocks as long as the publisher runs.
+pub fn run(sentry: Sentry, backends: Vec<String>) -> Result<()> {
+    *API.lock().unwrap() = Some(Api {
+        sentry: sentry,
+        backends: backends,
+    });
+
+    let config = carrier::config::load().map_err(|e| format!("{:?}", e))?;
+    let poll = osaka::Poll::new();
+    carrier::publisher::new(config)
+        .route(ROUTE_AUTHORIZE, None, route)
+        .route(ROUTE_REVOKE, None, route)
+        .route(ROUTE_SESSIONS, None, route)
+        .route(ROUTE_EVENTS, None, route)
+        .publish(poll)
+        .run()
+        .map_err(|e| format!("carrier publisher failed: {:?}", e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::sentry::testing;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    const BACKEND: &str = "backend identity";
+    const TEST_MAC: &str = "DE:AD:BE:EF:00:11";
+
+    fn api(core: &Core, dir: &TempDir) -> Api {
+        Api {
+            sentry: testing::sentry(core, dir.path()),
+            backends: vec![BACKEND.to_owned()],
+        }
+    }
+
+    fn call(api: &Api, caller: &str, route: &str, headers: &[(&str, &str)]) -> (u16, Value) {
+        let headers: HashMap<String, String> = headers
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        handle(api, caller, route, |name| headers.get(name).cloned())
+    }
+
+    #[test]
+    fn test_authorize_revoke() {
+        let dir = TempDir::new("carrier_api").unwrap();
+        let core = Core::new().unwrap();
+        let api = api(&core, &dir);
+
+        let (status, _) = call(
+            &api,
+            BACKEND,
+            ROUTE_AUTHORIZE,
+            &[("mac", TEST_MAC), ("duration", "60")],
+        );
+        assert_eq!(status, STATUS_OK);
+
+        let (status, body) = call(&api, BACKEND, ROUTE_SESSIONS, &[]);
+        assert_eq!(status, STATUS_OK);
+        assert_eq!(body["sessions"][0]["mac"], json!(TEST_MAC));
+        assert_eq!(
+            body["sessions"][0]["expires"].as_i64().unwrap(),
+            body["sessions"][0]["authorized"].as_i64().unwrap() + 60
+        );
+
+        let (status, _) = call(&api, BACKEND, ROUTE_REVOKE, &[("mac", TEST_MAC)]);
+        assert_eq!(status, STATUS_OK);
+        assert!(api.sentry.sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_events() {
+        use sentry::events::{self, Events};
+
+        let dir = TempDir::new("carrier_api").unwrap();
+        let core = Core::new().unwrap();
+        let mut api = api(&core, &dir);
+
+        let (status, _) = call(&api, BACKEND, ROUTE_EVENTS, &[]);
+        assert_eq!(status, STATUS_NOT_FOUND);
+
+        let config = events::Config {
+            ubus: false,
+            jsonl: None,
+            carrier: true,
+        };
+        api.sentry.events = Arc::new(Events::new("identity", &config));
+
+        call(&api, BACKEND, ROUTE_AUTHORIZE, &[("mac", TEST_MAC)]);
+        call(&api, BACKEND, ROUTE_REVOKE, &[("mac", TEST_MAC)]);
+
+        let (status, body) = call(&api, BACKEND, ROUTE_EVENTS, &[]);
+        assert_eq!(status, STATUS_OK);
+        assert_eq!(body["events"][0]["event"], json!("authorized"));
+        assert_eq!(body["events"][1]["event"], json!("revoked"));
+        assert_eq!(body["events"][1]["mac"], json!(TEST_MAC));
+
+        let (_, body) = call(&api, BACKEND, ROUTE_EVENTS, &[("since", "1")]);
+        assert_eq!(body["events"].as_array().unwrap().len(), 1);
+        assert_eq!(body["events"][0]["seq"], json!(2));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let dir = TempDir::new("carrier_api").unwrap();
+        let core = Core::new().unwrap();
+        let api = api(&core, &dir);
+
+        let (status, _) = call(&api, "stranger", ROUTE_AUTHORIZE, &[("mac", TEST_MAC)]);
+        assert_eq!(status, STATUS_FORBIDDEN);
+
+        let (status, _) = call(&api, BACKEND, ROUTE_AUTHORIZE, &[("mac", "nope")]);
+        assert_eq!(status, STATUS_BAD_REQUEST);
+
+        for header in &[("duration", "1h"), ("quota", "-1"), ("rate_up", "fast"), ("rate_down", "")] {
+            let (status, _) = call(&api, BACKEND, ROUTE_AUTHORIZE, &[("mac", TEST_MAC), *header]);
+            assert_eq!(status, STATUS_BAD_REQUEST, "{:?}", header);
+        }
+
+        let (status, _) = call(&api, BACKEND, "/v1/sentry/reboot", &[]);
+        assert_eq!(status, STATUS_NOT_FOUND);
+
+        assert!(api.sentry.sessions().unwrap().is_empty());
+    }
+}
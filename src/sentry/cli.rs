@@ -0,0 +1,475 @@
+//! The command line of sentry.
+//!
+//! ```text
+//! sentry [run] [--port <port>]
+//! sentry sessions list
+//! sentry sessions authorize <mac> [--duration <seconds>] [--quota <bytes>]
+//!                                 [--rate-up <kbit/s>] [--rate-down <kbit/s>]
+//! sentry sessions revoke <mac>
+//! sentry sessions extend <mac> <seconds>
+//! sentry check-config
+//! sentry bypass on|off
+//! sentry wifi-schedule check
+//! ```
+//!
+//! `--config <path>` reads the settings from another file, `--genesis <path>` the
+//! captif config and `--log-level <level>` overrides the `log.level` setting, for all
+//! commands. Only `run` logs to syslog, the other commands log to stderr.
+//!
+//! The `sessions` and `bypass` commands talk to the running sentry through the
+//! management api, see `api`. Without a running sentry, `bypass` changes the firewall
+//! directly. The bypass is not persisted, sentry removes it when it starts.
+
+use errors::*;
+use sentry::config::{self, Captif, Portal};
+use sentry::firewall;
+use sentry::ip;
+use sentry::logging;
+use sentry::sentry_main;
+use sentry::settings::{self, Settings};
+use sentry::store::Session;
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use hyper::{self, Client, Method};
+use hyper::client::Request;
+
+use futures::{Future, Stream};
+
+use tokio_core::reactor::Core;
+
+use chrono::Local;
+
+use serde_json::{self, Value};
+
+fn is_number(value: String) -> ::std::result::Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("not a number: {}", value))
+}
+
+fn is_mac(value: String) -> ::std::result::Result<(), String> {
+    if ip::is_mac(&value) {
+        Ok(())
+    } else {
+        Err(format!("not a mac address: {}", value))
+    }
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let mac = Arg::with_name("mac").required(true).validator(is_mac);
+    let number = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .validator(is_number)
+            .help(help)
+    };
+
+    App::new("sentry")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("The captive portal of the device")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .global(true)
+                .takes_value(true)
+                .help("The settings file, toml or uci"),
+        )
+        .arg(
+            Arg::with_name("genesis")
+                .long("genesis")
+                .global(true)
+                .takes_value(true)
+                .help("The genesis config with the captif config"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .global(true)
+                .takes_value(true)
+                .help("The log level, optionally followed by levels of targets"),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs sentry, the default command")
+                .arg(number("port", "The port of the captive listener")),
+        )
+        .subcommand(
+            SubCommand::with_name("sessions")
+                .about("Manages the sessions of the running sentry")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("Lists the sessions"))
+                .subcommand(
+                    SubCommand::with_name("authorize")
+                        .about("Authorizes a client")
+                        .arg(mac.clone())
+                        .arg(number("duration", "The session length in seconds"))
+                        .arg(number("quota", "The data quota in bytes"))
+                        .arg(number("rate-up", "The upload limit in kbit/s"))
+                        .arg(number("rate-down", "The download limit in kbit/s")),
+                )
+                .subcommand(
+                    SubCommand::with_name("revoke")
+                        .about("Ends the session of a client")
+                        .arg(mac.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("extend")
+                        .about("Extends the session of a client")
+                        .arg(mac)
+                        .arg(
+                            Arg::with_name("seconds")
+                                .required(true)
+                                .validator(is_number),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Checks the settings and the captif config"),
+        )
+        .subcommand(
+            SubCommand::with_name("bypass")
+                .about("Lets every client pass without authorization, or stops doing so")
+                .arg(
+                    Arg::with_name("state")
+                        .required(true)
+                        .possible_values(&["on", "off"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("wifi-schedule")
+                .about("Switches the wifi of the zones after their schedules")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("check").about("Checks the schedule once")),
+        )
+}
+
+/// The value of a global argument, clap passes them down to the innermost subcommand.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    match matches.subcommand() {
+        (_, Some(sub)) => global_value(sub, name),
+        _ => matches.value_of(name),
+    }
+}
+
+fn load_settings(matches: &ArgMatches) -> Result<Settings> {
+    let mut settings = match global_value(matches, "config") {
+        Some(path) => settings::load_file(Path::new(path))?,
+        None => settings::load()?,
+    };
+
+    if let Some(genesis) = global_value(matches, "genesis") {
+        settings.genesis_path = genesis.to_owned();
+        settings.genesis_fallback_path = genesis.to_owned();
+    }
+    if let Some(level) = global_value(matches, "log-level") {
+        settings.log.level = level.to_owned();
+    }
+    if let Some(port) = global_value(matches, "port") {
+        settings.listen_port = port.parse().chain_err(|| "invalid port")?;
+    }
+
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// The settings layered with the captif config, if there is a valid one.
+fn layered(settings: &Settings) -> (Settings, Option<Captif>) {
+    match config::load_captif(settings) {
+        Ok(captif) => (settings.layer(&captif), Some(captif)),
+        Err(_) => (settings.clone(), None),
+    }
+}
+
+/// The address the management api of the running sentry is reachable at.
+fn api_address(settings: &Settings) -> Result<SocketAddr> {
+    let (settings, _) = layered(settings);
+    settings
+        .api_address
+        .parse()
+        .chain_err(|| format!("invalid api address: {}", settings.api_address))
+}
+
+/// Calls the management api of the running sentry, `None` if sentry is not running.
+fn call(address: &SocketAddr, method: Method, path: &str) -> Result<Option<Value>> {
+    let mut core = Core::new().chain_err(|| "Could not initialize event loop")?;
+    let uri = format!("http://{}{}", address, path)
+        .parse()
+        .chain_err(|| format!("invalid api path: {}", path))?;
+
+    let work = Client::new(&core.handle())
+        .request(Request::new(method, uri))
+        .and_then(|resp| {
+            let status = resp.status();
+            resp.body().concat2().map(move |body| (status, body))
+        });
+
+    let (status, body) = match core.run(work) {
+        Ok(response) => response,
+        Err(hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).chain_err(|| format!("unable to reach sentry at {}", address)),
+    };
+
+    let body: Value = serde_json::from_slice(&body).chain_err(|| "invalid answer of sentry")?;
+    if !status.is_success() {
+        bail!("{}", body["error"].as_str().unwrap_or("request failed"));
+    }
+    Ok(Some(body))
+}
+
+/// Pads the columns of `rows` to the same width.
+fn table(rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| rows.iter().filter_map(|row| row.get(i)).map(|c| c.len()).max().unwrap_or(0))
+        .collect();
+
+    let mut result = String::new();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:<1$}", cell, width))
+            .collect();
+        result.push_str(cells.join("  ").trim_end());
+        result.push('\n');
+    }
+    result
+}
+
+fn render_sessions(sessions: &[Session], now: i64) -> String {
+    let or_dash = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_owned());
+
+    let mut rows = vec![
+        ["MAC", "IP", "HOSTNAME", "ZONE", "REMAINING", "BYTES"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+    ];
+    for session in sessions {
+        let remaining = match session.expires {
+            Some(expires) if expires <= now => "expired".to_owned(),
+            Some(expires) => format!("{}s", expires - now),
+            None => "-".to_owned(),
+        };
+        let bytes = match session.quota {
+            Some(quota) => format!("{}/{}", session.bytes, quota),
+            None => session.bytes.to_string(),
+        };
+
+        rows.push(vec![
+            session.mac.clone(),
+            or_dash(&session.ip),
+            or_dash(&session.hostname),
+            or_dash(&session.zone),
+            remaining,
+            bytes,
+        ]);
+    }
+
+    table(&rows)
+}
+
+fn sessions(settings: &Settings, matches: &ArgMatches) -> Result<()> {
+    let address = api_address(settings)?;
+    let running = |method, path: &str| -> Result<Value> {
+        call(&address, method, path)?
+            .ok_or_else(|| format!("sentry is not running, nothing answers at {}", address).into())
+    };
+
+    match matches.subcommand() {
+        ("list", _) => {
+            let mut answer = running(Method::Get, "/sessions")?;
+            let sessions: Vec<Session> = serde_json::from_value(answer["sessions"].take())
+                .chain_err(|| "invalid sessions")?;
+            print!("{}", render_sessions(&sessions, Local::now().timestamp()));
+        }
+        ("authorize", Some(m)) => {
+            let query: Vec<String> = [
+                ("duration", "duration"),
+                ("quota", "quota"),
+                ("rate-up", "rate_up"),
+                ("rate-down", "rate_down"),
+            ].iter()
+                .filter_map(|&(arg, param)| m.value_of(arg).map(|v| format!("{}={}", param, v)))
+                .collect();
+            let mac = m.value_of("mac").unwrap();
+            running(
+                Method::Post,
+                &format!("/sessions/{}/authorize?{}", mac, query.join("&")),
+            )?;
+        }
+        ("revoke", Some(m)) => {
+            running(
+                Method::Post,
+                &format!("/sessions/{}/revoke", m.value_of("mac").unwrap()),
+            )?;
+        }
+        ("extend", Some(m)) => {
+            let (mac, seconds) = (m.value_of("mac").unwrap(), m.value_of("seconds").unwrap());
+            running(
+                Method::Post,
+                &format!("/sessions/{}/extend?seconds={}", mac, seconds),
+            )?;
+        }
+        (command, _) => bail!("unknown command: {}", command),
+    }
+
+    Ok(())
+}
+
+fn bypass(settings: &Settings, enabled: bool) -> Result<()> {
+    let path = if enabled { "/bypass/on" } else { "/bypass/off" };
+    if call(&api_address(settings)?, Method::Post, path)?.is_some() {
+        return Ok(());
+    }
+
+    eprintln!("sentry is not running, changing the firewall directly");
+    if enabled {
+        eprintln!("the bypass only lasts until sentry starts");
+    }
+    let (settings, captif) = layered(settings);
+    let zones = captif.map(|c| c.zone_names()).unwrap_or_default();
+    let firewall = firewall::new(&settings.firewall, &zones, settings.tls_listen_port);
+    firewall::set_bypass(&*firewall, enabled)
+}
+
+fn check_config(settings: &Settings) -> Result<()> {
+    let captif = config::load_captif(settings)?;
+    settings
+        .layer(&captif)
+        .validate()
+        .chain_err(|| "invalid settings in the captif config")?;
+
+    println!(
+        "settings and captif config are valid, zones: {}",
+        captif.zone_names().join(", ")
+    );
+    Ok(())
+}
+
+/// Switches the wifi of the zones after their schedules, like the running sentry does.
+fn wifi_schedule(settings: &Settings) -> Result<()> {
+    let captif = config::load_captif(settings)?;
+    let settings = settings.layer(&captif);
+    Portal::new(&captif, &settings)?.check_schedules(&settings.timezone)
+}
+
+/// Runs the command given on the command line, `run` if there is none.
+pub fn cli_main() -> Result<()> {
+    let matches = app().get_matches();
+    let settings = load_settings(&matches)?;
+
+    let mut log = settings.log.clone();
+    if matches.subcommand_name().unwrap_or("run") != "run" {
+        log.output = logging::Output::Stderr;
+    }
+    logging::init(&log)?;
+
+    match matches.subcommand() {
+        ("sessions", Some(m)) => sessions(&settings, m),
+        ("check-config", _) => check_config(&settings),
+        ("bypass", Some(m)) => bypass(&settings, m.value_of("state") == Some("on")),
+        ("wifi-schedule", _) => wifi_schedule(&settings),
+        _ => sentry_main(settings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tempdir::TempDir;
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        app().get_matches_from_safe(args.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_app() {
+        let m = matches(&["sentry", "sessions", "--config", "a.toml", "list"]);
+        assert_eq!(global_value(&m, "config"), Some("a.toml"));
+        let m = matches(&["sentry", "--genesis", "g.json", "run", "--port", "9444"]);
+        assert_eq!(global_value(&m, "genesis"), Some("g.json"));
+        assert_eq!(m.subcommand_matches("run").unwrap().value_of("port"), Some("9444"));
+        assert_eq!(global_value(&matches(&["sentry"]), "config"), None);
+        let m = matches(&["sentry", "--log-level", "debug", "check-config"]);
+        assert_eq!(global_value(&m, "log-level"), Some("debug"));
+
+        let invalid: &[&[&str]] = &[
+            &["sentry", "sessions"],
+            &["sentry", "sessions", "revoke", "nope"],
+            &["sentry", "sessions", "extend", "DE:AD:BE:EF:00:11", "-5"],
+            &["sentry", "sessions", "authorize", "DE:AD:BE:EF:00:11", "--quota", "1&x=2"],
+            &["sentry", "bypass", "maybe"],
+            &["sentry", "run", "--port", "https"],
+        ];
+        for args in invalid {
+            assert!(app().get_matches_from_safe(args.to_vec()).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_load_settings() {
+        let dir = TempDir::new("cli").unwrap();
+        let path = dir.path().join("sentry.toml");
+        fs::write(&path, "listen_port = 9444\n").unwrap();
+        let config = path.to_str().unwrap();
+
+        let settings = load_settings(&matches(&[
+            "sentry", "--config", config, "--genesis", "g.json", "run", "--port", "9555",
+        ])).unwrap();
+        assert_eq!(settings.listen_port, 9555);
+        assert_eq!(settings.genesis_path, "g.json");
+
+        // the overrides are validated like the settings
+        let invalid: &[&[&str]] = &[
+            &["sentry", "--config", config, "run", "--port", "8443"],
+            &["sentry", "--config", config, "--log-level", "loud", "check-config"],
+        ];
+        for args in invalid {
+            assert!(load_settings(&matches(args)).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_render_sessions() {
+        let session = Session {
+            mac: "de:ad:be:ef:00:11".to_owned(),
+            ip: Some("192.168.8.100".to_owned()),
+            hostname: None,
+            authorized: 1000,
+            expires: Some(4600),
+            quota: Some(5000),
+            packets: 12,
+            bytes: 3400,
+            rate: Default::default(),
+            zone: Some("lobby".to_owned()),
+        };
+        let unlimited = Session {
+            expires: None,
+            quota: None,
+            hostname: Some("laptop".to_owned()),
+            ..session.clone()
+        };
+
+        assert_eq!(
+            render_sessions(&[session, unlimited], 1000),
+            "MAC                IP             HOSTNAME  ZONE   REMAINING  BYTES\n\
+             de:ad:be:ef:00:11  192.168.8.100  -         lobby  3600s      3400/5000\n\
+             de:ad:be:ef:00:11  192.168.8.100  laptop    lobby  -          3400\n"
+        );
+    }
+}
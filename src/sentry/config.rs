@@ -1,10 +1,477 @@
+use errors::*;
+use sentry::degraded;
+use sentry::events;
+use sentry::firewall;
+use sentry::settings::Settings;
+use sentry::tls::TlsMode;
+use sentry::walled_garden::{Network, WalledGarden};
+use time_control::{self, TimeControl};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use hyper;
+use serde_json;
+
+pub const GENESIS_PATH: &str = "/etc/config/genesis/current.json";
+pub const GENESIS_FALLBACK_PATH: &str = "/etc/config/genesis/stable.json";
+/// The zone of configs without `zones`.
+pub const DEFAULT_ZONE: &str = "public";
+
 #[derive(Deserialize, Debug)]
 pub struct Captif {
+    /// The portal url of the zones without one.
+    #[serde(default)]
     pub url: String,
     pub expires: Option<u32>,
+    /// Seconds without traffic after which a session ends.
+    pub idle_timeout: Option<u32>,
+    /// Bytes a session may transfer, up and down, before it ends.
+    pub data_quota: Option<u64>,
+    /// The rate limits of a session, in kbit/s.
+    #[serde(default)]
+    pub rate: firewall::Rate,
+    /// The firewall backend, the one of the settings if not set.
+    pub firewall: Option<firewall::Backend>,
+    /// The key shared with the portal to sign authorization tokens.
+    pub token_key: Option<String>,
+    /// Accept the secret or `tos_accepted=true` instead of tokens, for old portals.
+    #[serde(default)]
+    pub legacy_auth: bool,
+    /// The carrier identities allowed to manage the sessions of this device.
+    #[serde(default)]
+    pub backends: Vec<String>,
+    /// The address of the local management api, the one of the settings if not set.
+    pub api_address: Option<String>,
+    /// What to do while there is no valid captif config, the policy of the settings
+    /// if not set.
+    pub failure_policy: Option<degraded::FailurePolicy>,
+    /// Domains, wildcard domains and networks unauthorized clients may reach.
+    #[serde(default)]
+    pub walled_garden: Vec<String>,
+    /// How https connections of unauthorized clients are handled.
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// The uri of the captive portal api announced by dhcp, as RFC 8908 requires an
+    /// https uri of `service::CAPTIVE_API_PATH` on the `tls_listen_port` of the device.
+    /// Its host has to resolve to the device, sentry serves the api to connections for
+    /// it with the `tls_certificate` of the settings, whatever the `tls` mode.
+    pub captive_api_url: Option<String>,
+    /// Where the session events go, read at startup. The sinks of the settings if not set.
+    pub events: Option<events::Config>,
+    /// The captive networks, a single `public` zone if empty. Their firewall chains
+    /// are set up at startup, changing the zones takes a restart.
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+}
+
+impl Captif {
+    /// The names of the firewall zones sentry manages.
+    pub fn zone_names(&self) -> Vec<String> {
+        if self.zones.is_empty() {
+            vec![DEFAULT_ZONE.to_owned()]
+        } else {
+            self.zones.iter().map(|zone| zone.name.clone()).collect()
+        }
+    }
+
+    /// The keys only read at startup that differ in `other`, changing them takes a
+    /// restart of sentry.
+    pub fn startup_changes(&self, other: &Captif) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.token_key != other.token_key {
+            changed.push("token_key");
+        }
+        if self.legacy_auth != other.legacy_auth {
+            changed.push("legacy_auth");
+        }
+        if self.backends != other.backends {
+            changed.push("backends");
+        }
+        if self.events != other.events {
+            changed.push("events");
+        }
+        if self.api_address != other.api_address {
+            changed.push("api_address");
+        }
+        if self.failure_policy != other.failure_policy {
+            changed.push("failure_policy");
+        }
+        if self.firewall != other.firewall {
+            changed.push("firewall");
+        }
+        changed
+    }
+}
+
+/// A captive network with a portal of its own.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ZoneConfig {
+    /// The firewall zone, clients are accepted in its chains. Only a-z, 0-9 and _.
+    pub name: String,
+    /// The bridge of the network, the `bridge` of the settings if not set.
+    pub bridge: Option<String>,
+    /// The subnets of the network, for clients whose interface is not known.
+    #[serde(default)]
+    pub subnets: Vec<String>,
+    /// The portal url, the `url` of the captif config if not set.
+    pub url: Option<String>,
+    /// The session length, the `expires` of the captif config if not set.
+    pub expires: Option<u32>,
+    /// The uci `wifi-iface` sections of the network, switched by `schedule`. Required
+    /// with a schedule.
+    #[serde(default)]
+    pub radios: Vec<String>,
+    /// The hours the wifi of the network is up.
+    pub schedule: Option<TimeControl>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Genesis {
     pub captif: Option<Captif>
 }
+
+/// Reads the current genesis config, or the stable one if there is no current.
+pub fn load_genesis(settings: &Settings) -> Result<Genesis> {
+    let path = if Path::new(&settings.genesis_path).exists() {
+        &settings.genesis_path
+    } else {
+        &settings.genesis_fallback_path
+    };
+
+    let mut f = File::open(path).chain_err(|| format!("unable to open {}", path))?;
+    let mut s = Vec::new();
+    f.read_to_end(&mut s)
+        .chain_err(|| format!("unable to read {}", path))?;
+    serde_json::de::from_slice(&s).chain_err(|| format!("unable to parse {}", path))
+}
+
+/// Reads the captif config and checks that sentry can run with it.
+pub fn load_captif(settings: &Settings) -> Result<Captif> {
+    let captif = load_genesis(settings)?.captif.ok_or("no captif config")?;
+    let settings = settings.layer(&captif);
+    settings
+        .validate()
+        .chain_err(|| "invalid settings in the captif config")?;
+    Portal::new(&captif, &settings)?;
+    Ok(captif)
+}
+
+/// The longest name of an iptables chain.
+const IPTABLES_CHAIN_MAX_LEN: usize = 28;
+
+/// Checks that a zone name can be part of the names of firewall chains and sets.
+fn check_zone_name(name: &str, settings: &Settings) -> Result<()> {
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        bail!("zone name {:?} may only contain a-z, 0-9 and _", name);
+    }
+
+    let chain = settings.firewall.iptables_chain.replace("{zone}", name);
+    if settings.firewall.backend == firewall::Backend::Iptables
+        && chain.len() > IPTABLES_CHAIN_MAX_LEN
+    {
+        bail!("zone name {} is too long for the iptables chain {}", name, chain);
+    }
+
+    Ok(())
+}
+
+/// Checks that the captive portal api is announced with https, as RFC 8908 requires,
+/// and that sentry serves it there.
+fn check_captive_api_url(url: &str, settings: &Settings) -> Result<()> {
+    let uri = hyper::Uri::from_str(url).chain_err(|| "invalid captive api url")?;
+
+    match (uri.scheme(), uri.host()) {
+        (Some("https"), Some(_)) => {}
+        _ => bail!("the captive api url {} is not an https url", url),
+    }
+    if uri.port() != Some(settings.tls_listen_port) {
+        bail!("the captive api url {} is not on the tls_listen_port {}", url, settings.tls_listen_port);
+    }
+    if settings.tls_certificate.is_none() {
+        bail!("the captive api url takes the tls_certificate setting");
+    }
+
+    Ok(())
+}
+
+fn get_redirect_host(redirect_url: &str) -> Result<String> {
+    let uri = hyper::Uri::from_str(redirect_url)
+        .chain_err(|| "unable to convert redirect url to an uri")?;
+
+    uri.host()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| "unable to extract the host from the redirect url".into())
+}
+
+/// A captive network and its portal.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Zone {
+    pub name: String,
+    pub bridge: String,
+    pub subnets: Vec<Network>,
+    pub redirect_url: String,
+    pub redirect_host: String,
+    pub expires: Option<u32>,
+    pub radios: Vec<String>,
+    pub schedule: Option<TimeControl>,
+}
+
+impl Zone {
+    fn new(config: &ZoneConfig, captif: &Captif, settings: &Settings) -> Result<Zone> {
+        check_zone_name(&config.name, settings)?;
+        let url = config.url.as_ref().unwrap_or(&captif.url);
+        let subnets = config
+            .subnets
+            .iter()
+            .map(|subnet| Network::parse(subnet))
+            .collect::<Result<Vec<Network>>>()
+            .chain_err(|| format!("Error parsing the subnets of zone {}!", config.name))?;
+        if let Some(ref schedule) = config.schedule {
+            schedule
+                .validate()
+                .chain_err(|| format!("Error in the schedule of zone {}!", config.name))?;
+            if config.radios.is_empty() {
+                bail!("zone {} has a schedule but no radios", config.name);
+            }
+        }
+
+        Ok(Zone {
+            name: config.name.clone(),
+            bridge: config
+                .bridge
+                .clone()
+                .unwrap_or_else(|| settings.bridge(&config.name)),
+            subnets,
+            redirect_url: url.clone(),
+            redirect_host: get_redirect_host(url)
+                .chain_err(|| format!("Error extracting redirect host of zone {}!", config.name))?,
+            expires: config.expires.or(captif.expires),
+            radios: config.radios.clone(),
+            schedule: config.schedule.clone(),
+        })
+    }
+}
+
+/// The part of the captif config that can be swapped while sentry runs.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Portal {
+    /// The captive networks, never empty.
+    pub zones: Vec<Zone>,
+    pub idle_timeout: Option<u32>,
+    pub data_quota: Option<u64>,
+    pub rate: firewall::Rate,
+    pub walled_garden: WalledGarden,
+    pub tls: TlsMode,
+    pub captive_api_url: Option<String>,
+}
+
+impl Portal {
+    pub fn new(captif: &Captif, settings: &Settings) -> Result<Portal> {
+        let zones = if captif.zones.is_empty() {
+            let public = ZoneConfig {
+                name: DEFAULT_ZONE.to_owned(),
+                bridge: None,
+                subnets: Vec::new(),
+                url: None,
+                expires: None,
+                radios: Vec::new(),
+                schedule: None,
+            };
+            vec![Zone::new(&public, captif, settings)?]
+        } else {
+            captif
+                .zones
+                .iter()
+                .map(|zone| Zone::new(zone, captif, settings))
+                .collect::<Result<Vec<Zone>>>()?
+        };
+
+        let mut names = HashSet::new();
+        if let Some(zone) = zones.iter().find(|zone| !names.insert(&zone.name)) {
+            bail!("zone {} is declared twice", zone.name);
+        }
+        if let Some(ref url) = captif.captive_api_url {
+            check_captive_api_url(url, settings)?;
+        }
+
+        Ok(Portal {
+            zones,
+            idle_timeout: captif.idle_timeout,
+            data_quota: captif.data_quota,
+            rate: captif.rate,
+            walled_garden: WalledGarden::parse(&captif.walled_garden)
+                .chain_err(|| "Error parsing the walled garden!")?,
+            tls: captif.tls,
+            captive_api_url: captif.captive_api_url.clone(),
+        })
+    }
+
+    /// The zone with the given name, the first zone if there is none.
+    pub fn zone(&self, name: Option<&str>) -> &Zone {
+        name.and_then(|name| self.zones.iter().find(|zone| zone.name == name))
+            .unwrap_or(&self.zones[0])
+    }
+
+    /// The zone of a client, by the interface it is on or else by its address. With a
+    /// single zone every client belongs to it, otherwise clients of other networks
+    /// belong to none.
+    pub fn zone_for(&self, ip: &IpAddr, device: Option<&str>) -> Option<&Zone> {
+        if self.zones.len() == 1 {
+            return self.zones.first();
+        }
+
+        device
+            .and_then(|device| self.zones.iter().find(|zone| zone.bridge == device))
+            .or_else(|| {
+                self.zones
+                    .iter()
+                    .find(|zone| zone.subnets.iter().any(|subnet| subnet.contains(ip)))
+            })
+    }
+
+    /// The host of the captive portal api, if it is announced.
+    pub fn captive_api_host(&self) -> Option<String> {
+        let url = self.captive_api_url.as_ref()?;
+        hyper::Uri::from_str(url).ok()?.host().map(|host| host.to_owned())
+    }
+
+    /// Switches the wifi of the zones with a schedule on or off, see
+    /// `time_control::check_wifi`. Every zone is checked, even if one fails.
+    pub fn check_schedules(&self, default_timezone: &str) -> Result<()> {
+        let errors: Vec<String> = self
+            .zones
+            .iter()
+            .filter_map(|zone| {
+                let schedule = zone.schedule.as_ref()?;
+                time_control::check_wifi(&zone.radios, schedule, default_timezone)
+                    .err()
+                    .map(|e| format!("zone {}: {}", zone.name, e))
+            })
+            .collect();
+
+        if !errors.is_empty() {
+            bail!("unable to switch the wifi of {}", errors.join(", "));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captif(url: &str) -> Captif {
+        serde_json::from_value(json!({ "url": url, "expires": 3600 })).unwrap()
+    }
+
+    #[test]
+    fn test_portal() {
+        let settings = Settings::default();
+        assert_eq!(
+            Portal::new(&captif("http://portal.example.com/{{identity}}"), &settings).unwrap(),
+            Portal {
+                zones: vec![Zone {
+                    name: "public".to_owned(),
+                    bridge: "br-public".to_owned(),
+                    subnets: Vec::new(),
+                    redirect_url: "http://portal.example.com/{{identity}}".to_owned(),
+                    redirect_host: "portal.example.com".to_owned(),
+                    expires: Some(3600),
+                    radios: Vec::new(),
+                    schedule: None,
+                }],
+                idle_timeout: None,
+                data_quota: None,
+                rate: firewall::Rate::default(),
+                walled_garden: WalledGarden::default(),
+                tls: TlsMode::Reset,
+                captive_api_url: None,
+            }
+        );
+
+        assert!(Portal::new(&captif("/no/host"), &settings).is_err());
+
+        let mut walled = captif("http://portal.example.com/");
+        walled.walled_garden = vec!["not a domain".to_owned()];
+        assert!(Portal::new(&walled, &settings).is_err());
+
+        let mut api = captif("http://portal.example.com/");
+        let tls = Settings {
+            tls_certificate: Some("/etc/sentry/api.pem".to_owned()),
+            tls_key: Some("/etc/sentry/api.key".to_owned()),
+            ..Settings::default()
+        };
+        api.captive_api_url = Some("https://captive.example.com:8443/captive-portal/api".to_owned());
+        let portal = Portal::new(&api, &tls).unwrap();
+        assert_eq!(portal.captive_api_host(), Some("captive.example.com".to_owned()));
+        // sentry does not serve the api without a certificate
+        assert!(Portal::new(&api, &settings).is_err());
+        api.captive_api_url = Some("https://captive.example.com/captive-portal/api".to_owned());
+        assert!(Portal::new(&api, &tls).is_err());
+        api.captive_api_url = Some("http://192.168.8.1:8444/captive-portal/api".to_owned());
+        assert!(Portal::new(&api, &tls).is_err());
+        api.captive_api_url = Some("https:///captive-portal/api".to_owned());
+        assert!(Portal::new(&api, &tls).is_err());
+    }
+
+    #[test]
+    fn test_zones() {
+        let zones = |zones| -> Result<Portal> {
+            let captif = serde_json::from_value(json!({
+                "url": "http://portal.example.com/",
+                "expires": 3600,
+                "zones": zones,
+            })).unwrap();
+            Portal::new(&captif, &Settings::default())
+        };
+
+        let portal = zones(json!([
+            { "name": "lobby", "subnets": ["10.1.0.0/16", "2001:db8:1::/48"] },
+            {
+                "name": "conference",
+                "bridge": "br-conf",
+                "url": "http://conference.example.com/",
+                "expires": 600,
+                "radios": ["wconf"],
+                "schedule": { "up_time": [[8, 9]], "timezone": "UTC" },
+            },
+        ])).unwrap();
+        assert_eq!(portal.zones[0].bridge, "br-lobby");
+        assert_eq!(portal.zones[0].expires, Some(3600));
+        assert_eq!(portal.zones[1].redirect_host, "conference.example.com");
+        assert_eq!(portal.zones[1].expires, Some(600));
+
+        let ip = |s: &str| s.parse().unwrap();
+        let zone_for = |addr, device| portal.zone_for(&ip(addr), device).map(|z| z.name.as_str());
+        assert_eq!(zone_for("10.1.2.3", None), Some("lobby"));
+        assert_eq!(zone_for("2001:db8:1::2c1", None), Some("lobby"));
+        assert_eq!(zone_for("10.1.2.3", Some("br-conf")), Some("conference"));
+        assert_eq!(zone_for("192.168.8.1", None), None);
+        assert_eq!(zone_for("192.168.8.1", Some("br-lan")), None);
+        assert_eq!(portal.zone(Some("conference")).name, "conference");
+        assert_eq!(portal.zone(Some("staff")).name, "lobby");
+
+        let single = zones(json!([{ "name": "lobby", "subnets": ["10.1.0.0/16"] }])).unwrap();
+        assert_eq!(single.zone_for(&ip("192.168.8.1"), None).unwrap().name, "lobby");
+
+        assert!(zones(json!([{ "name": "lobby" }, { "name": "lobby" }])).is_err());
+        assert!(zones(json!([{ "name": "Lobby" }])).is_err());
+        assert!(zones(json!([{ "name": "staff-wifi" }])).is_err());
+        assert!(zones(json!([{ "name": "" }])).is_err());
+        // prerouting_conference_hall_rule is too long for iptables
+        assert!(zones(json!([{ "name": "conference_hall" }])).is_err());
+        assert!(zones(json!([{ "name": "staff_2" }])).is_ok());
+        assert!(zones(json!([{ "name": "lobby", "subnets": ["10.1.0.0/99"] }])).is_err());
+        assert!(zones(json!([{ "name": "lobby", "url": "/no/host" }])).is_err());
+        let schedule = json!({ "up_time": [], "timezone": "Mars/Olympus" });
+        assert!(zones(json!([{ "name": "lobby", "radios": ["w"], "schedule": schedule }])).is_err());
+        let schedule = json!({ "up_time": [[8, 9]] });
+        assert!(zones(json!([{ "name": "lobby", "schedule": schedule }])).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! What sentry does while there is no valid captif config.
+//!
+//! The failure policy, the firewall backend and the zones of the last valid config are
+//! kept in `FALLBACK_PATH`, so they also apply when the config is broken right after a
+//! reboot. Without a fallback, the ones of the settings apply.
+//! While degraded, the config is retried every `degraded_retry_interval` seconds and
+//! sentry comes up normally as soon as it is valid.
+
+use errors::*;
+use sentry::access_control;
+use sentry::config::{self, Captif};
+use sentry::firewall::{self, Firewall};
+use sentry::proxy;
+use sentry::settings::Settings;
+use sentry::store::Store;
+use sentry::ubus;
+use sentry::bind;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper;
+use hyper::server::{self, Http, Request, Response};
+
+use futures::{self, Future, Stream};
+use futures::future::Either;
+
+use tokio_core::reactor::{Core, Interval};
+
+use serde_json;
+
+pub const FALLBACK_PATH: &str = "/etc/sentry/fallback.json";
+
+/// Who may pass while there is no valid captif config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Everyone passes.
+    Open,
+    /// Authorized clients keep passing, everyone else gets the offline page.
+    Walled,
+    /// Nobody passes, the sessions are restored once sentry is up again.
+    Closed,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> FailurePolicy {
+        FailurePolicy::Open
+    }
+}
+
+/// The settings of the last valid captif config needed to degrade.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Fallback {
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    #[serde(default)]
+    pub firewall: firewall::Backend,
+    /// The names of the captive zones.
+    #[serde(default)]
+    pub zones: Vec<String>,
+}
+
+impl Fallback {
+    /// The fallback of the settings layered with the captif config.
+    pub fn new(settings: &Settings, captif: &Captif) -> Fallback {
+        Fallback {
+            failure_policy: settings.failure_policy,
+            firewall: settings.firewall.backend,
+            zones: captif.zone_names(),
+        }
+    }
+
+    /// Reads the fallback, if there is a valid one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<Fallback> {
+        File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+    }
+
+    /// Writes the fallback, unless it is unchanged.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if Fallback::load(path).as_ref() == Some(self) {
+            return Ok(());
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).chain_err(|| format!("unable to create {:?}", dir))?;
+        }
+
+        let data = serde_json::to_vec(self).chain_err(|| "unable to serialize the fallback")?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, &data).chain_err(|| format!("unable to write {:?}", tmp))?;
+        fs::rename(&tmp, path).chain_err(|| format!("unable to replace {:?}", path))
+    }
+}
+
+/// Announces the state of sentry on ubus.
+pub fn announce(state: &str, policy: Option<FailurePolicy>, reason: &str) {
+    let policy = policy.map(|p| format!("{:?}", p).to_lowercase());
+
+    let mut map: HashMap<&str, &str> = HashMap::new();
+    map.insert("state", state);
+    if let Some(ref policy) = policy {
+        map.insert("policy", policy);
+    }
+    if !reason.is_empty() {
+        map.insert("reason", reason);
+    }
+    ubus::send_message("/sentry/state", &map);
+}
+
+/// Applies the failure policy to the firewall.
+pub fn enter(firewall: &Firewall, store: &Mutex<Store>, policy: FailurePolicy) -> Result<()> {
+    match policy {
+        FailurePolicy::Open => firewall.bypass(),
+        FailurePolicy::Walled => {
+            firewall.unbypass()?;
+            access_control::reconcile(firewall, store, &HashMap::new()).map(|_| ())
+        }
+        FailurePolicy::Closed => {
+            firewall.unbypass()?;
+            for zone in firewall.zones() {
+                for entry in firewall.list(&zone)? {
+                    firewall.revoke(&zone, &entry.mac)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Undoes the failure policy, sessions are restored by the next reconcile.
+pub fn leave(firewall: &Firewall) -> Result<()> {
+    firewall.unbypass()
+}
+
+/// The captive service while degraded, answers everything with the offline page.
+struct Offline;
+
+impl server::Service for Offline {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = futures::future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, _req: Request) -> Self::Future {
+        futures::future::ok(proxy::serve_offline_page())
+    }
+}
+
+/// The captif config, once sentry can run with it.
+fn retry_captif(settings: &Settings) -> Option<Captif> {
+    config::load_captif(settings)
+        .map_err(|e| warn!("sentry still degraded: {}", e))
+        .ok()
+}
+
+/// Keeps sentry degraded until the captif config is valid, then returns it.
+///
+/// `listen_port` is the port of the captive listener, it serves the offline page meanwhile.
+pub fn run(
+    settings: &Settings,
+    listen_port: u16,
+    store: &Mutex<Store>,
+    reason: &Error,
+) -> Result<Captif> {
+    let fallback = Fallback::load(FALLBACK_PATH).unwrap_or_else(|| Fallback {
+        failure_policy: settings.failure_policy,
+        firewall: settings.firewall.backend,
+        zones: Vec::new(),
+    });
+    let policy = fallback.failure_policy;
+    let firewall = firewall::new(
+        &firewall::Config {
+            backend: fallback.firewall,
+            ..settings.firewall.clone()
+        },
+        &fallback.zones,
+        // nothing listens for tls meanwhile, https is refused right away
+        settings.tls_listen_port,
+    );
+
+    error!("sentry degraded ({:?}), reason: {}", policy, reason);
+    announce("degraded", Some(policy), &reason.to_string());
+    if let Err(e) = enter(&*firewall, store, policy) {
+        error!("unable to apply the failure policy: {}", e);
+    }
+
+    let mut core = Core::new().chain_err(|| "Could not initialize event loop")?;
+    let handle = core.handle();
+
+    let listener = bind(listen_port, &handle).chain_err(|| "unable to listen")?;
+    let mut http = Http::new();
+    let server_handle = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, addr)| {
+        http.keep_alive(false)
+            .bind_connection(&server_handle, socket, addr, Offline);
+        Ok(())
+    });
+
+    let retry_firewall = firewall.clone();
+    let retry_settings = settings.clone();
+    let retry = Interval::new(Duration::from_secs(settings.degraded_retry_interval), &handle)
+        .chain_err(|| "unable to create the retry timer")?
+        .filter_map(move |()| {
+            if policy == FailurePolicy::Walled {
+                // expire the sessions of the clients still let through
+                access_control::reconcile(&*retry_firewall, store, &HashMap::new()).ok();
+            }
+
+            retry_captif(&retry_settings)
+        })
+        .into_future();
+
+    match core.run(retry.select2(server)) {
+        Ok(Either::A(((Some(captif), _), _))) => {
+            info!("captif config valid, leaving degraded mode");
+            // the backend of the new config may differ, undo the policy where it was applied
+            if let Err(e) = leave(&*firewall) {
+                error!("unable to undo the failure policy: {}", e);
+            }
+            Ok(captif)
+        }
+        Ok(_) => bail!("the degraded listener stopped"),
+        Err(Either::A(((e, _), _))) | Err(Either::B((e, _))) => {
+            Err(e).chain_err(|| "error running the degraded event loop")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::firewall::testing::FakeFirewall;
+
+    use tempdir::TempDir;
+
+    const TEST_MAC: &str = "de:ad:be:ef:00:11";
+
+    fn setup(dir: &TempDir) -> (FakeFirewall, Mutex<Store>) {
+        let firewall = FakeFirewall::default();
+        firewall.authorize("public", TEST_MAC, 1000, None).unwrap();
+
+        let store = Mutex::new(Store::open(
+            dir.path().join("tmp/sessions.json"),
+            dir.path().join("etc/sessions.json"),
+        ));
+        access_control::reconcile(&firewall, &store, &HashMap::new()).unwrap();
+        (firewall, store)
+    }
+
+    #[test]
+    fn test_enter_leave() {
+        let dir = TempDir::new("degraded").unwrap();
+
+        let (firewall, store) = setup(&dir);
+        enter(&firewall, &store, FailurePolicy::Open).unwrap();
+        assert!(*firewall.bypassed.lock().unwrap());
+        leave(&firewall).unwrap();
+        assert!(!*firewall.bypassed.lock().unwrap());
+
+        let (firewall, store) = setup(&dir);
+        enter(&firewall, &store, FailurePolicy::Walled).unwrap();
+        assert!(!*firewall.bypassed.lock().unwrap());
+        assert_eq!(firewall.list("public").unwrap().len(), 1);
+
+        let (firewall, store) = setup(&dir);
+        enter(&firewall, &store, FailurePolicy::Closed).unwrap();
+        assert!(!*firewall.bypassed.lock().unwrap());
+        assert!(firewall.list("public").unwrap().is_empty());
+
+        // the sessions come back once sentry is up again
+        access_control::reconcile(&firewall, &store, &HashMap::new()).unwrap();
+        assert_eq!(firewall.list("public").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fallback_roundtrip() {
+        let dir = TempDir::new("degraded").unwrap();
+        let path = dir.path().join("etc/fallback.json");
+
+        assert_eq!(Fallback::load(&path), None);
+
+        let fallback = Fallback {
+            failure_policy: FailurePolicy::Walled,
+            firewall: firewall::Backend::Nftables,
+            zones: vec!["lobby".to_owned(), "conference".to_owned()],
+        };
+        fallback.save(&path).unwrap();
+        assert_eq!(Fallback::load(&path), Some(fallback));
+    }
+
+    #[test]
+    fn test_retry_captif() {
+        let dir = TempDir::new("degraded").unwrap();
+        let genesis = dir.path().join("genesis.json");
+        let settings = Settings {
+            genesis_path: genesis.to_str().unwrap().to_owned(),
+            ..Settings::default()
+        };
+        let write = |api_address| {
+            let captif = json!({
+                "url": "http://portal.example.com/",
+                "api_address": api_address,
+            });
+            fs::write(&genesis, json!({ "captif": captif }).to_string()).unwrap();
+        };
+
+        // a layered setting sentry can not run with keeps it degraded
+        write("0.0.0.0:8445");
+        assert!(retry_captif(&settings).is_none());
+
+        write("127.0.0.1:8445");
+        assert!(retry_captif(&settings).is_some());
+    }
+}
@@ -0,0 +1,156 @@
+//! Configures dnsmasq for sentry.
+//!
+//! dnsmasq adds the addresses of walled garden domains to a firewall set, so
+//! unauthorized clients can reach them directly, https included. It also announces
+//! the captive portal api to the clients of the captive zones by dhcp and dhcpv6
+//! (RFC 8910).
+
+use errors::*;
+use sentry::config::Zone;
+use sentry::firewall::{DnsSet, Firewall};
+use sentry::ubus;
+use sentry::walled_garden::WalledGarden;
+
+use std::fs;
+use std::path::Path;
+
+pub const CONF_PATH: &str = "/tmp/dnsmasq.d/sentry-walled-garden.conf";
+pub const CAPTIVE_CONF_PATH: &str = "/tmp/dnsmasq.d/sentry-captive.conf";
+/// The dhcp option carrying the captive portal api uri.
+const DHCP_OPTION_CAPTIVE_PORTAL: u8 = 114;
+/// The dhcpv6 option carrying the captive portal api uri.
+const DHCPV6_OPTION_CAPTIVE_PORTAL: u8 = 103;
+
+fn render(set: &DnsSet, domains: &[&str]) -> String {
+    let mut conf = String::from("# generated by sentry, changes are overwritten\n");
+    for domain in domains {
+        conf.push_str(&set.dnsmasq_option(domain));
+        conf.push('\n');
+    }
+    conf
+}
+
+/// The options are tagged with the zone names, dnsmasq tags the dhcp ranges with
+/// the names of their networks.
+fn render_captive(uri: &str, zones: &[Zone]) -> String {
+    let mut conf = String::from("# generated by sentry, changes are overwritten\n");
+    for zone in zones {
+        conf.push_str(&format!(
+            "dhcp-option=tag:{0},{1},\"{3}\"\n\
+             dhcp-option=tag:{0},option6:{2},\"{3}\"\n",
+            zone.name, DHCP_OPTION_CAPTIVE_PORTAL, DHCPV6_OPTION_CAPTIVE_PORTAL, uri
+        ));
+    }
+    conf
+}
+
+/// Writes `conf` to `path`, returns whether it changed. An empty `conf` removes the file.
+fn write_conf(path: &Path, conf: Option<&str>) -> Result<bool> {
+    let current = fs::read_to_string(path).ok();
+    if current.as_ref().map(|c| c.as_str()) == conf {
+        return Ok(false);
+    }
+
+    match conf {
+        Some(conf) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).chain_err(|| format!("unable to create {:?}", dir))?;
+            }
+            fs::write(path, conf).chain_err(|| format!("unable to write {:?}", path))?;
+        }
+        None => fs::remove_file(path).chain_err(|| format!("unable to remove {:?}", path))?,
+    }
+
+    Ok(true)
+}
+
+/// Configures dnsmasq for the domains of the walled garden, restarts it if needed.
+pub fn update(firewall: &Firewall, garden: &WalledGarden) -> Result<()> {
+    let domains = garden.dns_domains();
+    let conf = if domains.is_empty() {
+        None
+    } else {
+        Some(render(&firewall.ensure_dns_set()?, &domains))
+    };
+
+    apply(Path::new(CONF_PATH), conf)
+}
+
+/// Announces the captive portal api uri in the zones by dhcp, or stops announcing it.
+pub fn update_captive_api(uri: Option<&str>, zones: &[Zone]) -> Result<()> {
+    apply(
+        Path::new(CAPTIVE_CONF_PATH),
+        uri.map(|uri| render_captive(uri, zones)),
+    )
+}
+
+fn apply(path: &Path, conf: Option<String>) -> Result<()> {
+    if write_conf(path, conf.as_ref().map(|c| c.as_str()))? {
+        ubus::restart_service("dnsmasq").chain_err(|| "unable to restart dnsmasq")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_render() {
+        let set = DnsSet::Nftset {
+            v4: "inet#fw4#sentry_walled_garden_dns".to_owned(),
+            v6: "inet#fw4#sentry_walled_garden_dns6".to_owned(),
+        };
+
+        assert_eq!(
+            render(&set, &["pay.example.com", "example.net"]),
+            "# generated by sentry, changes are overwritten\n\
+             nftset=/pay.example.com/4#inet#fw4#sentry_walled_garden_dns,\
+             6#inet#fw4#sentry_walled_garden_dns6\n\
+             nftset=/example.net/4#inet#fw4#sentry_walled_garden_dns,\
+             6#inet#fw4#sentry_walled_garden_dns6\n"
+        );
+    }
+
+    #[test]
+    fn test_render_captive() {
+        let zone = |name: &str| Zone {
+            name: name.to_owned(),
+            bridge: format!("br-{}", name),
+            subnets: Vec::new(),
+            redirect_url: "http://portal.example.com/".to_owned(),
+            redirect_host: "portal.example.com".to_owned(),
+            expires: None,
+            radios: Vec::new(),
+            schedule: None,
+        };
+
+        assert_eq!(
+            render_captive(
+                "https://router.example.com/captive-portal/api",
+                &[zone("lobby"), zone("conference")],
+            ),
+            "# generated by sentry, changes are overwritten\n\
+             dhcp-option=tag:lobby,114,\"https://router.example.com/captive-portal/api\"\n\
+             dhcp-option=tag:lobby,option6:103,\"https://router.example.com/captive-portal/api\"\n\
+             dhcp-option=tag:conference,114,\"https://router.example.com/captive-portal/api\"\n\
+             dhcp-option=tag:conference,option6:103,\"https://router.example.com/captive-portal/api\"\n"
+        );
+    }
+
+    #[test]
+    fn test_write_conf() {
+        let dir = TempDir::new("dnsmasq").unwrap();
+        let path = dir.path().join("dnsmasq.d/sentry.conf");
+
+        assert!(!write_conf(&path, None).unwrap());
+        assert!(write_conf(&path, Some("a\n")).unwrap());
+        assert!(!write_conf(&path, Some("a\n")).unwrap());
+        assert!(write_conf(&path, Some("b\n")).unwrap());
+        assert!(write_conf(&path, None).unwrap());
+        assert!(!path.exists());
+    }
+}
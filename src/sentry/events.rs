@@ -0,0 +1,430 @@
+//! The lifecycle events of the sessions, for billing and analytics.
+//!
+//! Every event is passed to the sinks enabled by the `events` key of the captif
+//! config: ubus events (on by default), a JSON lines file and a buffer the backends
+//! fetch through the carrier api.
+
+use errors::*;
+use sentry::neighbors::Neighbors;
+use sentry::store::Session;
+use sentry::ubus;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+/// The json lines file is moved to `<path>.1` once it grows larger.
+const JSONL_MAX_BYTES: u64 = 1024 * 1024;
+/// The number of events kept for the backends.
+const BUFFER_LEN: usize = 1024;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Authorized,
+    Extended,
+    Expired,
+    Revoked,
+    /// The client had no traffic for the idle timeout.
+    Idle,
+    /// The client used up the data quota of its session.
+    QuotaExceeded,
+    /// An authorized client showed up on the network.
+    ClientSeen,
+    /// An authorized client left the network, its neighbor entries are gone.
+    ClientGone,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Authorized => "authorized",
+            Kind::Extended => "extended",
+            Kind::Expired => "expired",
+            Kind::Revoked => "revoked",
+            Kind::Idle => "idle",
+            Kind::QuotaExceeded => "quota_exceeded",
+            Kind::ClientSeen => "client_seen",
+            Kind::ClientGone => "client_gone",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Event {
+    pub event: Kind,
+    pub mac: String,
+    pub ip: Option<String>,
+    pub hostname: Option<String>,
+    /// The identity of this device.
+    pub identity: String,
+    /// The zone the client was authorized in.
+    pub zone: Option<String>,
+    /// Unix timestamp of the event.
+    pub timestamp: i64,
+    /// Unix timestamp of the authorization.
+    pub authorized: i64,
+    /// Unix timestamp the session expires at.
+    pub expires: Option<i64>,
+    /// The traffic of the session so far.
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Which sinks get the events.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct Config {
+    #[serde(default = "default_ubus")]
+    pub ubus: bool,
+    /// The path of the json lines file.
+    pub jsonl: Option<String>,
+    /// Keep the events for the backends, see `carrier_api`.
+    #[serde(default)]
+    pub carrier: bool,
+}
+
+fn default_ubus() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ubus: default_ubus(),
+            jsonl: None,
+            carrier: false,
+        }
+    }
+}
+
+pub trait Sink: Debug + Send + Sync {
+    fn send(&self, event: &Event) -> Result<()>;
+}
+
+/// Sends the events on ubus, as `/sentry/<event>`.
+///
+/// `authorized` goes to `/sentry/accept`, where it was announced before.
+#[derive(Debug)]
+pub struct Ubus;
+
+impl Sink for Ubus {
+    fn send(&self, event: &Event) -> Result<()> {
+        let channel = match event.event {
+            Kind::Authorized => "/sentry/accept".to_owned(),
+            kind => format!("/sentry/{}", kind.name()),
+        };
+        let timestamp = event.timestamp.to_string();
+        let authorized = event.authorized.to_string();
+        let expires = event.expires.map(|e| e.to_string());
+        let packets = event.packets.to_string();
+        let bytes = event.bytes.to_string();
+
+        let mut map: HashMap<&str, &str> = HashMap::new();
+        map.insert("event", event.event.name());
+        map.insert("mac", &event.mac);
+        if let Some(ref ip) = event.ip {
+            map.insert("ip", ip);
+        }
+        if let Some(ref hostname) = event.hostname {
+            map.insert("hostname", hostname);
+        }
+        map.insert("identity", &event.identity);
+        if let Some(ref zone) = event.zone {
+            map.insert("zone", zone);
+        }
+        map.insert("timestamp", &timestamp);
+        map.insert("authorized", &authorized);
+        if let Some(ref expires) = expires {
+            map.insert("expires", expires);
+        }
+        map.insert("packets", &packets);
+        map.insert("bytes", &bytes);
+        ubus::send_message(&channel, &map);
+
+        Ok(())
+    }
+}
+
+/// Appends the events to a file, one json object per line.
+#[derive(Debug)]
+pub struct JsonLines {
+    path: PathBuf,
+}
+
+impl JsonLines {
+    pub fn new<P: Into<PathBuf>>(path: P) -> JsonLines {
+        JsonLines { path: path.into() }
+    }
+}
+
+impl Sink for JsonLines {
+    fn send(&self, event: &Event) -> Result<()> {
+        let path = &self.path;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).chain_err(|| format!("unable to create {:?}", dir))?;
+        }
+        if fs::metadata(path).map(|m| m.len() > JSONL_MAX_BYTES).unwrap_or(false) {
+            let rotated = PathBuf::from(format!("{}.1", path.display()));
+            fs::rename(path, &rotated).chain_err(|| format!("unable to rotate {:?}", path))?;
+        }
+
+        let mut line = serde_json::to_vec(event).chain_err(|| "unable to serialize the event")?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(&line))
+            .chain_err(|| format!("unable to write {:?}", path))
+    }
+}
+
+/// Keeps the latest events numbered, for the backends to fetch.
+#[derive(Default, Debug)]
+pub struct Buffer {
+    events: Mutex<(u64, VecDeque<(u64, Event)>)>,
+}
+
+impl Buffer {
+    /// The events after the sequence number `since`, with their sequence numbers.
+    pub fn since(&self, since: u64) -> Vec<(u64, Event)> {
+        self.events
+            .lock()
+            .unwrap()
+            .1
+            .iter()
+            .filter(|&&(seq, _)| seq > since)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Sink for Buffer {
+    fn send(&self, event: &Event) -> Result<()> {
+        let mut events = self.events.lock().unwrap();
+        events.0 += 1;
+        let seq = events.0;
+
+        events.1.push_back((seq, event.clone()));
+        if events.1.len() > BUFFER_LEN {
+            events.1.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// Turns session changes into events and passes them to the sinks.
+#[derive(Debug)]
+pub struct Events {
+    identity: String,
+    sinks: Vec<Arc<Sink>>,
+    /// The buffer of the carrier api, if enabled.
+    pub buffer: Option<Arc<Buffer>>,
+    /// The macs of the authorized clients currently on the network.
+    present: Mutex<HashSet<String>>,
+}
+
+impl Events {
+    pub fn new(identity: &str, config: &Config) -> Events {
+        let mut sinks: Vec<Arc<Sink>> = Vec::new();
+        if config.ubus {
+            sinks.push(Arc::new(Ubus));
+        }
+        if let Some(ref path) = config.jsonl {
+            sinks.push(Arc::new(JsonLines::new(path.as_str())));
+        }
+
+        let buffer = if config.carrier {
+            let buffer = Arc::new(Buffer::default());
+            sinks.push(buffer.clone());
+            Some(buffer)
+        } else {
+            None
+        };
+
+        Events {
+            identity: identity.to_owned(),
+            sinks,
+            buffer,
+            present: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Sends the event for `session` to all sinks, a failing sink does not stop the others.
+    pub fn emit(&self, kind: Kind, session: &Session, now: i64) {
+        let event = Event {
+            event: kind,
+            mac: session.mac.clone(),
+            ip: session.ip.clone(),
+            hostname: session.hostname.clone(),
+            identity: self.identity.clone(),
+            zone: session.zone.clone(),
+            timestamp: now,
+            authorized: session.authorized,
+            expires: session.expires,
+            packets: session.packets,
+            bytes: session.bytes,
+        };
+
+        for sink in &self.sinks {
+            if let Err(e) = sink.send(&event) {
+                warn!("unable to send the {} event of {}: {}", kind.name(), session.mac, e);
+            }
+        }
+    }
+
+    /// Emits `client_seen` and `client_gone` for the sessions whose clients joined or
+    /// left the network since the last call.
+    pub fn update_presence(&self, sessions: &[Session], neighbors: &Neighbors, now: i64) {
+        let mut present = self.present.lock().unwrap();
+        let mut current = HashSet::new();
+
+        for session in sessions {
+            let mac = session.mac.to_lowercase();
+            if neighbors.ips(&mac).is_empty() {
+                if present.contains(&mac) {
+                    self.emit(Kind::ClientGone, session, now);
+                }
+            } else {
+                if !present.contains(&mac) {
+                    self.emit(Kind::ClientSeen, session, now);
+                }
+                current.insert(mac);
+            }
+        }
+
+        *present = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::neighbors::testing::FakeNeighbors;
+
+    use tempdir::TempDir;
+
+    const TEST_MAC: &str = "de:ad:be:ef:00:11";
+
+    fn session() -> Session {
+        Session {
+            mac: TEST_MAC.to_owned(),
+            ip: Some("192.168.8.1".to_owned()),
+            hostname: Some("laptop".to_owned()),
+            authorized: 1000,
+            expires: Some(4600),
+            quota: None,
+            packets: 12,
+            bytes: 3400,
+            rate: Default::default(),
+            zone: Some("lobby".to_owned()),
+        }
+    }
+
+    fn events(config: Config) -> Events {
+        Events::new("identity", &Config { ubus: false, ..config })
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let dir = TempDir::new("events").unwrap();
+        let path = dir.path().join("log/events.jsonl");
+        let events = events(Config {
+            jsonl: Some(path.to_str().unwrap().to_owned()),
+            ..Config::default()
+        });
+
+        events.emit(Kind::Authorized, &session(), 1000);
+        events.emit(Kind::Revoked, &session(), 2000);
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines[0],
+            json!({
+                "event": "authorized",
+                "mac": TEST_MAC,
+                "ip": "192.168.8.1",
+                "hostname": "laptop",
+                "identity": "identity",
+                "zone": "lobby",
+                "timestamp": 1000,
+                "authorized": 1000,
+                "expires": 4600,
+                "packets": 12,
+                "bytes": 3400,
+            })
+        );
+        assert_eq!(lines[1]["event"], json!("revoked"));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_jsonl_rotation() {
+        let dir = TempDir::new("events").unwrap();
+        let path = dir.path().join("events.log");
+        let events = events(Config {
+            jsonl: Some(path.to_str().unwrap().to_owned()),
+            ..Config::default()
+        });
+
+        fs::write(&path, vec![b'\n'; JSONL_MAX_BYTES as usize + 1]).unwrap();
+        events.emit(Kind::Authorized, &session(), 1000);
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(dir.path().join("events.log.1").exists());
+    }
+
+    #[test]
+    fn test_buffer() {
+        let events = events(Config {
+            carrier: true,
+            ..Config::default()
+        });
+        let buffer = events.buffer.clone().unwrap();
+
+        for _ in 0..BUFFER_LEN + 2 {
+            events.emit(Kind::Extended, &session(), 1000);
+        }
+
+        assert_eq!(buffer.since(0).len(), BUFFER_LEN);
+        assert_eq!(buffer.since(0)[0].0, 3);
+        let latest: Vec<u64> = buffer.since(BUFFER_LEN as u64).iter().map(|e| e.0).collect();
+        assert_eq!(latest, vec![BUFFER_LEN as u64 + 1, BUFFER_LEN as u64 + 2]);
+    }
+
+    #[test]
+    fn test_presence() {
+        let events = events(Config {
+            carrier: true,
+            ..Config::default()
+        });
+        let buffer = events.buffer.clone().unwrap();
+        let neighbors = FakeNeighbors::default();
+        let sessions = vec![session()];
+        let kinds = |since| -> Vec<Kind> {
+            buffer.since(since).iter().map(|&(_, ref e)| e.event).collect()
+        };
+
+        events.update_presence(&sessions, &neighbors, 1000);
+        assert!(kinds(0).is_empty());
+
+        neighbors.insert("2001:db8::2c1", "DE:AD:BE:EF:00:11");
+        events.update_presence(&sessions, &neighbors, 1010);
+        events.update_presence(&sessions, &neighbors, 1020);
+        assert_eq!(kinds(0), vec![Kind::ClientSeen]);
+
+        neighbors.entries.lock().unwrap().clear();
+        events.update_presence(&sessions, &neighbors, 1030);
+        assert_eq!(kinds(1), vec![Kind::ClientGone]);
+        assert_eq!(buffer.since(1)[0].1.timestamp, 1030);
+    }
+}
@@ -0,0 +1,757 @@
+use errors::*;
+use sentry::firewall::{self, Client, Counter, DnsSet, Entry, Firewall};
+use sentry::walled_garden::Network;
+
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+use std::sync::Mutex;
+
+use iptables::{self, IPTables};
+
+use regex::Regex;
+
+const IPT_BYPASS_RULE: &str = "-jACCEPT -mcomment --comment timestamp=0";
+const IPT_WALLED_GARDEN_COMMENT: &str = "sentry-walled-garden";
+const IPT_DNS_SET: &str = "sentry_walled_garden_dns";
+const IPT_DNS_SET6: &str = "sentry_walled_garden_dns6";
+const IPT_DNS_SET_COMMENT: &str = "sentry-dns-set";
+const IPT_ACCOUNTING_TABLE: &str = "mangle";
+const IPT_ACCOUNTING_CHAIN: &str = "sentry_accounting";
+const IPT_ACCOUNTING_COMMENT: &str = "sentry-acct";
+const IPT_LIMIT_COMMENT: &str = "sentry-limit";
+const IPT_TLS_REDIRECT_COMMENT: &str = "sentry-tls-redirect";
+
+lazy_static! {
+    static ref MAC_SOURCE_REGEX: Regex = Regex::new(
+        r"--mac-source\s([a-fA-F0-9:]{17})").unwrap();
+    static ref TIMESTAMP_REGEX: Regex = Regex::new(
+        r#""timestamp=(\d+)(?:,expires=(\d+))?""#).unwrap();
+    static ref ACCOUNTING_REGEX: Regex = Regex::new(
+        r"sentry-acct=([a-fA-F0-9:]{17})").unwrap();
+    static ref COUNTER_REGEX: Regex = Regex::new(
+        r"-c (\d+) (\d+)").unwrap();
+    /// The chains already warned about being managed for ipv4 only.
+    static ref IPV4_ONLY: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+#[derive(PartialEq, Debug)]
+struct Rule<'a> {
+    mac_source: &'a str,
+    timestamp: i64,
+    expires: Option<i64>,
+}
+
+impl<'rule> Rule<'rule> {
+    fn parse<'a>(rule: &'a str) -> Option<Rule<'a>> {
+        let mac_source_capt = MAC_SOURCE_REGEX.captures(rule);
+        let timestamp_capt = TIMESTAMP_REGEX.captures(rule);
+
+        if mac_source_capt.is_none() || timestamp_capt.is_none() {
+            return None;
+        }
+
+        let timestamp_capt = timestamp_capt.unwrap();
+        let expires = timestamp_capt
+            .get(2)
+            .and_then(|e| e.as_str().parse::<i64>().ok());
+        if let Some(Ok(timestamp)) = timestamp_capt.get(1).map(|t| t.as_str().parse::<i64>()) {
+            Some(Rule {
+                mac_source: mac_source_capt.unwrap().get(1).unwrap().as_str(),
+                timestamp: timestamp,
+                expires: expires,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn to_string(&self) -> String {
+        format!(
+            r#"-m mac --mac-source {} -m comment --comment {} -j ACCEPT"#,
+            self.mac_source,
+            comment(self.timestamp, self.expires)
+        )
+    }
+}
+
+/// The comment keeping the authorization time and the expiry of a client.
+fn comment(timestamp: i64, expires: Option<i64>) -> String {
+    match expires {
+        Some(expires) => format!("timestamp={},expires={}", timestamp, expires),
+        None => format!("timestamp={}", timestamp),
+    }
+}
+
+/// Extracts the rules of `chain` containing `needle` from the output of `iptables -S`,
+/// in the form `iptables -D` takes them.
+fn chain_rules(rules: &[String], chain: &str, needle: &str) -> Vec<String> {
+    let prefix = format!("-A {} ", chain);
+
+    rules
+        .iter()
+        .filter(|rule| rule.contains(needle))
+        .filter_map(|rule| {
+            if rule.starts_with(&prefix) {
+                Some(rule[prefix.len()..].to_owned())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The rule redirecting https to the tls listener of sentry on `port`, as `iptables -S`
+/// prints it.
+fn tls_redirect(port: u16) -> String {
+    format!(
+        "-p tcp -m tcp --dport 443 -m comment --comment {} -j REDIRECT --to-ports {}",
+        IPT_TLS_REDIRECT_COMMENT, port
+    )
+}
+
+/// Whether `redirect` is the last rule of `chain` in the output of `iptables -S`.
+fn is_last(rules: &[String], chain: &str, redirect: &str) -> bool {
+    chain_rules(rules, chain, "").last().map(|rule| rule.as_str()) == Some(redirect)
+}
+
+/// Adds up the counters of the accounting rules per client, from the output of
+/// `iptables -S -v`.
+fn parse_counters(output: &str) -> HashMap<String, Counter> {
+    let mut result: HashMap<String, Counter> = HashMap::new();
+
+    for rule in output.lines() {
+        let mac = ACCOUNTING_REGEX.captures(rule).map(|c| c[1].to_lowercase());
+        let counter = COUNTER_REGEX.captures(rule).and_then(|c| {
+            Some(Counter {
+                packets: c[1].parse().ok()?,
+                bytes: c[2].parse().ok()?,
+            })
+        });
+
+        if let (Some(mac), Some(counter)) = (mac, counter) {
+            result.entry(mac).or_insert_with(Counter::default).add(counter);
+        }
+    }
+
+    result
+}
+
+/// Matches the traffic over `kbit` per second, in a bucket shared by all rules of
+/// the same name.
+fn hashlimit(name: &str, kbit: u32) -> String {
+    format!(
+        "-m hashlimit --hashlimit-name {} --hashlimit-above {}kb/s",
+        name,
+        (kbit / 8).max(1)
+    )
+}
+
+/// The accounting rules of a client, the rate limits first to only count the traffic
+/// passing.
+///
+/// iptables and ip6tables keep their hashlimit buckets apart. With `dual_stack`, both
+/// manage the client, and each family gets half of the rates its traffic can take in
+/// both, the upload rate always and the download rate if the client has addresses of
+/// both families. Together the families stay within the rates of the client.
+fn client_rules(client: &Client, ipv6: bool, dual_stack: bool) -> Vec<String> {
+    let comment = format!("-m comment --comment {}={}", IPT_ACCOUNTING_COMMENT, client.mac);
+    let limit = format!("-m comment --comment {}={} -j DROP", IPT_LIMIT_COMMENT, client.mac);
+    let upload = format!("-m mac --mac-source {}", client.mac);
+    let downloads: Vec<String> = client
+        .ips
+        .iter()
+        .filter(|ip| ip.is_ipv6() == ipv6)
+        .map(|ip| format!("-d {}", ip))
+        .collect();
+    let v4 = client.ips.iter().any(|ip| ip.is_ipv4());
+    let v6 = client.ips.iter().any(|ip| ip.is_ipv6());
+    let share = |rate: u32, split: bool| if split { rate / 2 } else { rate };
+    // hashlimit names are limited to 15 characters
+    let name = client.mac.replace(":", "");
+    let mut rules = Vec::new();
+
+    if let Some(up) = client.rate.up {
+        let up = share(up, dual_stack);
+        rules.push(format!("{} {} {}", upload, hashlimit(&format!("su{}", name), up), limit));
+    }
+    if let Some(down) = client.rate.down {
+        let down = share(down, dual_stack && v4 && v6);
+        for download in &downloads {
+            rules.push(format!(
+                "{} {} {}",
+                download,
+                hashlimit(&format!("sd{}", name), down),
+                limit
+            ));
+        }
+    }
+
+    rules.push(format!("{} {}", upload, comment));
+    for download in &downloads {
+        rules.push(format!("{} {}", download, comment));
+    }
+
+    rules
+}
+
+/// The iptables of the address families the clients of `chain` in `table` are
+/// managed in.
+///
+/// fw3 only creates the ipv6 nat chains if the kernel supports ipv6 nat, otherwise
+/// ipv6 is not redirected and the client rules are kept for ipv4 only.
+fn tables(table: &str, chain: &str) -> Vec<IPTables> {
+    let mut tables = vec![iptables::new(false).unwrap()];
+
+    let ipt6 = iptables::new(true)
+        .ok()
+        .filter(|ipt6| ipt6.chain_exists(table, chain).unwrap_or(false));
+    match ipt6 {
+        Some(ipt6) => tables.push(ipt6),
+        None => if IPV4_ONLY.lock().unwrap().insert(format!("{} {}", table, chain)) {
+            warn!("no ip6tables chain {} in table {}, managing it for ipv4 only", chain, table);
+        },
+    }
+
+    tables
+}
+
+fn is_ipv6(ipt: &IPTables) -> bool {
+    ipt.cmd == "ip6tables"
+}
+
+fn create_ipset(name: &str, family: &str) -> Result<()> {
+    let output = Command::new("ipset")
+        .args(&["create", name, "hash:ip", "family", family, "timeout"])
+        .arg(firewall::DNS_SET_TIMEOUT_SECS.to_string())
+        .arg("-exist")
+        .output()
+        .chain_err(|| "error running ipset")?;
+    if !output.status.success() {
+        bail!(
+            "ipset create failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// The fw3 backend, authorized clients are ACCEPT rules in the chain of their zone, by
+/// default `prerouting_<zone>_rule` of the `nat` table, of iptables and ip6tables. The
+/// authorization time and expiry are kept in a comment on the rule.
+///
+/// The last rule of a zone chain redirects the https of everyone not accepted before
+/// to the tls listener, rules are appended in front of it.
+///
+/// The nat table only sees the first packet of a connection, the traffic is counted
+/// by rules without target in the `sentry_accounting` chain of the `mangle` table.
+/// Rate limits are hashlimit rules dropping the excess in front of them, the rates are
+/// split across iptables and ip6tables, see `client_rules`.
+#[derive(Debug)]
+pub struct Iptables {
+    table: String,
+    /// The zones and their chains.
+    chains: Vec<(String, String)>,
+    /// The port of the tls listener.
+    tls_port: u16,
+}
+
+impl Iptables {
+    pub fn new(table: &str, chains: Vec<(String, String)>, tls_port: u16) -> Iptables {
+        Iptables {
+            table: table.to_owned(),
+            chains,
+            tls_port,
+        }
+    }
+
+    /// Moves the https redirect of `chain` behind the rules appended to it, or adds it.
+    fn ensure_tls_redirect(&self, ipt: &IPTables, chain: &str) -> Result<()> {
+        let redirect = tls_redirect(self.tls_port);
+        let rules = ipt.list(&self.table, chain)
+            .chain_err(|| "Could not list the chain rules!")?;
+        if is_last(&rules, chain, &redirect) {
+            return Ok(());
+        }
+
+        for rule in chain_rules(&rules, chain, IPT_TLS_REDIRECT_COMMENT) {
+            ipt.delete(&self.table, chain, &rule)
+                .chain_err(|| format!("Error deleting rule: {}", rule))?;
+        }
+        ipt.append(&self.table, chain, &redirect)
+            .chain_err(|| format!("Error redirecting https with {}", ipt.cmd))?;
+
+        Ok(())
+    }
+
+    /// The zone chains, each with the iptables of the address families it is managed in.
+    fn chains(&self) -> Vec<(IPTables, &str)> {
+        self.chains
+            .iter()
+            .flat_map(|&(_, ref chain)| {
+                tables(&self.table, chain)
+                    .into_iter()
+                    .map(move |ipt| (ipt, chain.as_str()))
+            })
+            .collect()
+    }
+
+    /// The chain of `zone` with the iptables of the address families it is managed in.
+    fn zone_chain(&self, zone: &str) -> Result<(Vec<IPTables>, &str)> {
+        let chain = firewall::chain(&self.chains, zone)?;
+        Ok((tables(&self.table, chain), chain))
+    }
+
+    /// The iptables of the address families any zone is managed in.
+    fn tables(&self) -> Vec<IPTables> {
+        let mut result: Vec<IPTables> = Vec::new();
+        for (ipt, _) in self.chains() {
+            if !result.iter().any(|t| t.cmd == ipt.cmd) {
+                result.push(ipt);
+            }
+        }
+        result
+    }
+
+    /// Creates the accounting chain of `ipt` and jumps to it, unless it exists.
+    fn ensure_accounting(ipt: &IPTables) -> Result<()> {
+        if !ipt.chain_exists(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN).unwrap_or(false) {
+            ipt.new_chain(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN)
+                .chain_err(|| format!("Error creating the {} accounting chain", ipt.cmd))?;
+        }
+        ipt.append_unique(
+            IPT_ACCOUNTING_TABLE,
+            "FORWARD",
+            &format!("-j {}", IPT_ACCOUNTING_CHAIN),
+        ).chain_err(|| format!("Error jumping to the {} accounting chain", ipt.cmd))?;
+
+        Ok(())
+    }
+
+    /// Deletes the accounting rules of the client with the given lowercase mac address.
+    fn remove_client_rules(ipt: &IPTables, mac: &str) -> Result<()> {
+        let rules = ipt.list(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN)
+            .chain_err(|| format!("Error listing the {} accounting chain", ipt.cmd))?;
+
+        for comment in &[IPT_ACCOUNTING_COMMENT, IPT_LIMIT_COMMENT] {
+            let needle = format!("--comment {}={}", comment, mac);
+            for rule in chain_rules(&rules, IPT_ACCOUNTING_CHAIN, &needle) {
+                ipt.delete(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN, &rule)
+                    .chain_err(|| format!("Error deleting accounting rule: {}", rule))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_client_rules(ipt: &IPTables, client: &Client, dual_stack: bool) -> Result<()> {
+        for rule in client_rules(client, is_ipv6(ipt), dual_stack) {
+            ipt.append(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN, &rule)
+                .chain_err(|| format!("Error adding accounting rule: {}", rule))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Firewall for Iptables {
+    fn zones(&self) -> Vec<String> {
+        self.chains.iter().map(|&(ref zone, _)| zone.clone()).collect()
+    }
+
+    fn authorize(&self, zone: &str, mac: &str, timestamp: i64, expires: Option<i64>) -> Result<()> {
+        // keep a single rule per client, so the newest authorization counts
+        self.revoke(zone, mac)?;
+
+        let (tables, chain) = self.zone_chain(zone)?;
+        for ipt in tables {
+            ipt.append(
+                &self.table,
+                chain,
+                &format!(
+                    "-jACCEPT -mmac --mac-source {} -mcomment --comment {}",
+                    mac,
+                    comment(timestamp, expires)
+                ),
+            ).chain_err(|| format!("Error authorizing client with {}", ipt.cmd))?;
+            self.ensure_tls_redirect(&ipt, chain)?;
+        }
+
+        Ok(())
+    }
+
+    fn revoke(&self, zone: &str, mac: &str) -> Result<()> {
+        let (tables, chain) = self.zone_chain(zone)?;
+        for ipt in tables {
+            let rules = ipt.list(&self.table, chain)
+                .chain_err(|| "Could not list the chain rules!")?;
+
+            for rule in rules {
+                if let Some(rule) = Rule::parse(&rule) {
+                    if rule.mac_source.eq_ignore_ascii_case(mac) {
+                        ipt.delete(&self.table, chain, &rule.to_string())
+                            .chain_err(|| format!("Error deleting rule: {}", rule.to_string()))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the clients authorized in all address families, clients missing in one
+    /// are authorized again by the next reconcile.
+    fn list(&self, zone: &str) -> Result<Vec<Entry>> {
+        let mut result: Option<Vec<Entry>> = None;
+
+        let (tables, chain) = self.zone_chain(zone)?;
+        for ipt in tables {
+            // put the redirect in place, reconcile lists every zone regularly
+            self.ensure_tls_redirect(&ipt, chain)?;
+            let rules = ipt.list(&self.table, chain)
+                .chain_err(|| "Could not list the chain rules!")?;
+            let entries: Vec<Entry> = rules
+                .iter()
+                .filter_map(|rule| Rule::parse(rule))
+                .map(|rule| Entry {
+                    mac: rule.mac_source.to_owned(),
+                    timestamp: rule.timestamp,
+                    expires: rule.expires,
+                })
+                .collect();
+
+            result = Some(match result {
+                Some(result) => result.into_iter().filter(|e| entries.contains(e)).collect(),
+                None => entries,
+            });
+        }
+
+        Ok(result.unwrap_or_default())
+    }
+
+    fn bypass(&self) -> Result<()> {
+        for (ipt, chain) in self.chains() {
+            ipt.append(&self.table, chain, IPT_BYPASS_RULE)
+                .chain_err(|| format!("Error bypassing sentry with {}", ipt.cmd))?;
+            self.ensure_tls_redirect(&ipt, chain)?;
+        }
+
+        Ok(())
+    }
+
+    fn unbypass(&self) -> Result<()> {
+        for (ipt, chain) in self.chains() {
+            ipt.delete_all(&self.table, chain, IPT_BYPASS_RULE)
+                .chain_err(|| format!("Error removing the {} bypass", ipt.cmd))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_walled_garden(&self, networks: &[Network]) -> Result<()> {
+        for (ipt, chain) in self.chains() {
+            let rules = ipt.list(&self.table, chain)
+                .chain_err(|| "Could not list the chain rules!")?;
+            for rule in chain_rules(&rules, chain, IPT_WALLED_GARDEN_COMMENT) {
+                ipt.delete(&self.table, chain, &rule)
+                    .chain_err(|| format!("Error deleting rule: {}", rule))?;
+            }
+
+            for network in networks.iter().filter(|n| n.addr.is_ipv6() == is_ipv6(&ipt)) {
+                ipt.append(
+                    &self.table,
+                    chain,
+                    &format!(
+                        "-jACCEPT -d {} -mcomment --comment {}",
+                        network, IPT_WALLED_GARDEN_COMMENT
+                    ),
+                ).chain_err(|| format!("Error adding {} to the walled garden", network))?;
+            }
+            self.ensure_tls_redirect(&ipt, chain)?;
+        }
+
+        Ok(())
+    }
+
+    fn ensure_dns_set(&self) -> Result<DnsSet> {
+        create_ipset(IPT_DNS_SET, "inet")?;
+        create_ipset(IPT_DNS_SET6, "inet6")?;
+
+        for (ipt, chain) in self.chains() {
+            let set = if is_ipv6(&ipt) { IPT_DNS_SET6 } else { IPT_DNS_SET };
+            ipt.append_unique(
+                &self.table,
+                chain,
+                &format!(
+                    "-jACCEPT -mset --match-set {} dst -mcomment --comment {}",
+                    set, IPT_DNS_SET_COMMENT
+                ),
+            ).chain_err(|| format!("Error accepting the dns set with {}", ipt.cmd))?;
+            self.ensure_tls_redirect(&ipt, chain)?;
+        }
+
+        Ok(DnsSet::Ipset {
+            v4: IPT_DNS_SET.to_owned(),
+            v6: IPT_DNS_SET6.to_owned(),
+        })
+    }
+
+    fn set_accounting(&self, clients: &[Client]) -> Result<()> {
+        let tables = self.tables();
+        let dual_stack = tables.len() > 1;
+        for ipt in tables {
+            Iptables::ensure_accounting(&ipt)?;
+            ipt.flush_chain(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN)
+                .chain_err(|| format!("Error flushing the {} accounting chain", ipt.cmd))?;
+
+            for client in clients {
+                Iptables::add_client_rules(&ipt, client, dual_stack)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_client_accounting(&self, client: &Client) -> Result<()> {
+        let tables = self.tables();
+        let dual_stack = tables.len() > 1;
+        for ipt in tables {
+            Iptables::ensure_accounting(&ipt)?;
+            Iptables::remove_client_rules(&ipt, &client.mac)?;
+            Iptables::add_client_rules(&ipt, client, dual_stack)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_accounting(&self, mac: &str) -> Result<()> {
+        for ipt in self.tables() {
+            if ipt.chain_exists(IPT_ACCOUNTING_TABLE, IPT_ACCOUNTING_CHAIN).unwrap_or(false) {
+                Iptables::remove_client_rules(&ipt, &mac.to_lowercase())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn counters(&self) -> Result<HashMap<String, Counter>> {
+        let mut result: HashMap<String, Counter> = HashMap::new();
+
+        for ipt in self.tables() {
+            let command = format!("-S {} -v", IPT_ACCOUNTING_CHAIN);
+            let output = ipt.execute(IPT_ACCOUNTING_TABLE, &command)
+                .chain_err(|| format!("Error reading the {} counters", ipt.cmd))?;
+            if !output.status.success() {
+                bail!(
+                    "{} -S {} failed: {}",
+                    ipt.cmd,
+                    IPT_ACCOUNTING_CHAIN,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+
+            for (mac, counter) in parse_counters(&String::from_utf8_lossy(&output.stdout)) {
+                result.entry(mac).or_insert_with(Counter::default).add(counter);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::firewall::Rate;
+
+    #[test]
+    fn test_rule_parse() {
+        let expected_rule = Rule {
+            mac_source: "DE:AD:BE:EF:DE:AD",
+            timestamp: 233445,
+            expires: None,
+        };
+
+        let rule = Rule::parse(
+            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD
+                     -m comment --comment \"timestamp=233445\" -j ACCEPT",
+        ).expect("Error parsing the rule");
+
+        assert_eq!(expected_rule, rule);
+    }
+
+    #[test]
+    fn test_chain_rules() {
+        let rules = vec![
+            "-N prerouting_public_rule".to_owned(),
+            "-A prerouting_public_rule -d 192.0.2.0/24 -m comment --comment sentry-walled-garden -j ACCEPT".to_owned(),
+            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD -m comment --comment \"timestamp=233445\" -j ACCEPT".to_owned(),
+        ];
+
+        assert_eq!(
+            chain_rules(&rules, "prerouting_public_rule", IPT_WALLED_GARDEN_COMMENT),
+            vec!["-d 192.0.2.0/24 -m comment --comment sentry-walled-garden -j ACCEPT"]
+        );
+    }
+
+    #[test]
+    fn test_tls_redirect() {
+        let chain = "prerouting_public_rule";
+        let mut rules = vec![
+            "-N prerouting_public_rule".to_owned(),
+            "-A prerouting_public_rule -p tcp -m tcp --dport 443 -m comment --comment sentry-tls-redirect -j REDIRECT --to-ports 8443".to_owned(),
+        ];
+        assert!(is_last(&rules, chain, &tls_redirect(8443)));
+        assert!(!is_last(&rules, chain, &tls_redirect(9443)));
+
+        // a client authorized behind the redirect would have its https redirected
+        rules.push("-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD -m comment --comment \"timestamp=233445\" -j ACCEPT".to_owned());
+        assert!(!is_last(&rules, chain, &tls_redirect(8443)));
+        assert_eq!(
+            chain_rules(&rules, chain, IPT_TLS_REDIRECT_COMMENT),
+            vec![tls_redirect(8443)]
+        );
+
+        assert!(!is_last(&rules[..1], chain, &tls_redirect(8443)));
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let output = "-N sentry_accounting\n\
+            -A sentry_accounting -m mac --mac-source DE:AD:BE:EF:00:11 -m comment --comment sentry-acct=de:ad:be:ef:00:11 -c 10 1200\n\
+            -A sentry_accounting -d 192.168.8.1/32 -c 20 30000 -m comment --comment sentry-acct=de:ad:be:ef:00:11\n\
+            -A sentry_accounting -m mac --mac-source DE:AD:BE:EF:00:22 -m comment --comment sentry-acct=de:ad:be:ef:00:22 -c 0 0\n";
+
+        let counters = parse_counters(output);
+        assert_eq!(counters.len(), 2);
+        assert_eq!(
+            counters["de:ad:be:ef:00:11"],
+            Counter {
+                packets: 30,
+                bytes: 31200,
+            }
+        );
+        assert_eq!(counters["de:ad:be:ef:00:22"], Counter::default());
+    }
+
+    #[test]
+    fn test_client_rules() {
+        let mut client = Client {
+            mac: "de:ad:be:ef:00:11".to_owned(),
+            ips: vec!["192.168.8.1".parse().unwrap(), "2001:db8::2c1".parse().unwrap()],
+            rate: Rate::default(),
+        };
+        assert_eq!(
+            client_rules(&client, false, true),
+            vec![
+                "-m mac --mac-source de:ad:be:ef:00:11 -m comment --comment sentry-acct=de:ad:be:ef:00:11",
+                "-d 192.168.8.1 -m comment --comment sentry-acct=de:ad:be:ef:00:11",
+            ]
+        );
+
+        client.rate = Rate {
+            up: Some(1000),
+            down: Some(4000),
+        };
+        let rules = client_rules(&client, true, false);
+        assert_eq!(
+            &rules[..2],
+            &[
+                "-m mac --mac-source de:ad:be:ef:00:11 -m hashlimit --hashlimit-name \
+                 sudeadbeef0011 --hashlimit-above 125kb/s -m comment --comment \
+                 sentry-limit=de:ad:be:ef:00:11 -j DROP",
+                "-d 2001:db8::2c1 -m hashlimit --hashlimit-name sddeadbeef0011 \
+                 --hashlimit-above 500kb/s -m comment --comment sentry-limit=de:ad:be:ef:00:11 -j DROP",
+            ]
+        );
+        assert_eq!(rules.len(), 4);
+
+        // both families together stay within the rates of a dual-stack client
+        for &ipv6 in &[false, true] {
+            let rules = client_rules(&client, ipv6, true);
+            assert!(rules[0].contains("--hashlimit-above 62kb/s"), "{}", rules[0]);
+            assert!(rules[1].contains("--hashlimit-above 250kb/s"), "{}", rules[1]);
+        }
+
+        // the download rate is not split if the client only has addresses of one family
+        client.ips.truncate(1);
+        let rules = client_rules(&client, false, true);
+        assert!(rules[0].contains("--hashlimit-above 62kb/s"), "{}", rules[0]);
+        assert!(rules[1].contains("--hashlimit-above 500kb/s"), "{}", rules[1]);
+    }
+
+    #[test]
+    fn test_rule_parse_expires() {
+        let expected_rule = Rule {
+            mac_source: "DE:AD:BE:EF:DE:AD",
+            timestamp: 233445,
+            expires: Some(237045),
+        };
+
+        let rule = Rule::parse(
+            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD
+                     -m comment --comment \"timestamp=233445,expires=237045\" -j ACCEPT",
+        ).expect("Error parsing the rule");
+
+        assert_eq!(expected_rule, rule);
+    }
+
+    #[test]
+    fn test_rule_parse_fail() {
+        assert!(
+            Rule::parse(
+                "-A prerouting_public_rule -m mac
+                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
+            ).is_none()
+        );
+    }
+
+    #[test]
+    fn test_rule_parse_fail_mac_wrong() {
+        assert!(
+            Rule::parse(
+                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:EG:DE:AD
+                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
+            ).is_none()
+        );
+
+        assert!(
+            Rule::parse(
+                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:DE:AD
+                     -m comment --comment \"timestamp=233445\" -j ACCEPT"
+            ).is_none()
+        );
+    }
+
+    #[test]
+    fn test_rule_parse_fail_timestamp_wrong() {
+        assert!(
+            Rule::parse(
+                "-A prerouting_public_rule -m mac  --mac-source DE:AD:BE:DE:AD:DE
+                     -m comment --comment \"timestamp=hi\" -j ACCEPT"
+            ).is_none()
+        );
+    }
+
+    #[test]
+    fn test_rule_to_string() {
+        let mut rule = Rule {
+            mac_source: "DE:AD:BE:DE:AD:DE",
+            timestamp: 3456,
+            expires: None,
+        };
+
+        let expected_result = "-m mac --mac-source DE:AD:BE:DE:AD:DE -m comment --comment \
+                               timestamp=3456 -j ACCEPT";
+
+        assert_eq!(expected_result, rule.to_string());
+
+        rule.expires = Some(7056);
+
+        let expected_result = "-m mac --mac-source DE:AD:BE:DE:AD:DE -m comment --comment \
+                               timestamp=3456,expires=7056 -j ACCEPT";
+
+        assert_eq!(expected_result, rule.to_string());
+    }
+
+}
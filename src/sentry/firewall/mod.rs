@@ -0,0 +1,281 @@
+//! Firewall backends used to let authorized clients pass the captive redirect.
+//!
+//! fw3 based releases only ship iptables, fw4 (OpenWrt 22.03+) is nftables only.
+//! Both are hidden behind the `Firewall` trait, the backend is selected by the
+//! `firewall` key of the genesis `captif` config or the sentry settings. A backend
+//! manages the chains of all captive zones at once, a client is only let through in
+//! the zone it was authorized in.
+//!
+//! The captive redirect of http is up to the firewall config, the https of clients not
+//! let through is redirected to the tls listener of sentry by the backends.
+//!
+//! Clients are authorized by their mac address, for ipv4 and ipv6 alike. This also
+//! covers the SLAAC privacy addresses a client changes regularly.
+//!
+//! The traffic of the authorized clients is counted and rate limited apart from their
+//! authorization, uploads by the mac address and downloads by the addresses of the
+//! client. The rates are policed, not shaped: traffic over the rate is dropped rather
+//! than queued. With nftables the downloads of a client share one limit, iptables keeps
+//! the limits of ipv4 and ipv6 apart and gives each family half the rate of a dual-stack
+//! client.
+
+mod ipt;
+mod nft;
+#[cfg(test)]
+pub mod testing;
+
+pub use self::ipt::Iptables;
+pub use self::nft::Nftables;
+
+use errors::*;
+use sentry::config;
+use sentry::walled_garden::Network;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// The firewall implementation sentry talks to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Iptables,
+    Nftables,
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::Iptables
+    }
+}
+
+/// The backend and the names of the firewall objects sentry uses, `{zone}` in the
+/// chain names is replaced by the zone name.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub backend: Backend,
+    /// The iptables table of the zone chains.
+    pub iptables_table: String,
+    pub iptables_chain: String,
+    pub nftables_chain: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            backend: Backend::default(),
+            iptables_table: "nat".to_owned(),
+            iptables_chain: "prerouting_{zone}_rule".to_owned(),
+            nftables_chain: "dstnat_{zone}".to_owned(),
+        }
+    }
+}
+
+/// The zones with the names of their chains, after `pattern`.
+fn zone_chains(pattern: &str, zones: &[String]) -> Vec<(String, String)> {
+    zones
+        .iter()
+        .map(|zone| (zone.clone(), pattern.replace("{zone}", zone)))
+        .collect()
+}
+
+/// The chain of `zone`, an error for zones the firewall does not manage.
+fn chain<'a>(chains: &'a [(String, String)], zone: &str) -> Result<&'a str> {
+    chains
+        .iter()
+        .find(|&&(ref name, _)| name == zone)
+        .map(|&(_, ref chain)| chain.as_str())
+        .ok_or_else(|| format!("unknown firewall zone: {}", zone).into())
+}
+
+/// How long dnsmasq's addresses of walled garden domains stay in the `DnsSet`, counted
+/// from when they were added. dnsmasq does not refresh the timeout of addresses already
+/// in the set and the sets know nothing of the dns TTLs, so the fixed timeout is meant to
+/// outlast the TTLs clients cache answers for. Expired addresses are added again by the
+/// next lookup, connections already accepted are not affected.
+pub const DNS_SET_TIMEOUT_SECS: u32 = 15 * 60;
+
+/// The firewall sets dnsmasq adds the addresses of resolved walled garden domains to,
+/// one per address family.
+#[derive(Clone, PartialEq, Debug)]
+pub enum DnsSet {
+    /// ipsets, used with iptables.
+    Ipset { v4: String, v6: String },
+    /// nftables sets, as `family#table#set`.
+    Nftset { v4: String, v6: String },
+}
+
+impl DnsSet {
+    /// The dnsmasq option adding the addresses of `domain` and its subdomains to the sets.
+    pub fn dnsmasq_option(&self, domain: &str) -> String {
+        match *self {
+            DnsSet::Ipset { ref v4, ref v6 } => format!("ipset=/{}/{},{}", domain, v4, v6),
+            DnsSet::Nftset { ref v4, ref v6 } => {
+                format!("nftset=/{}/4#{},6#{}", domain, v4, v6)
+            }
+        }
+    }
+}
+
+/// An authorized client as found in the firewall.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub mac: String,
+    /// Unix timestamp of the authorization.
+    pub timestamp: i64,
+    /// Unix timestamp the client expires at, if the firewall knows about it.
+    pub expires: Option<i64>,
+}
+
+/// Rate limits in kbit/s, `None` is unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct Rate {
+    pub up: Option<u32>,
+    pub down: Option<u32>,
+}
+
+impl Rate {
+    /// Takes the limits not set from `other`.
+    pub fn or(self, other: Rate) -> Rate {
+        Rate {
+            up: self.up.or(other.up),
+            down: self.down.or(other.down),
+        }
+    }
+}
+
+/// A client whose traffic is counted.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Client {
+    pub mac: String,
+    /// The addresses the downloads of the client are counted by.
+    pub ips: Vec<IpAddr>,
+    pub rate: Rate,
+}
+
+/// The traffic of a client in both directions.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Counter {
+    pub fn add(&mut self, other: Counter) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+pub trait Firewall: Debug + Send + Sync {
+    /// The zones the firewall manages, never empty.
+    fn zones(&self) -> Vec<String>;
+
+    /// Lets the client with the given mac address pass in `zone`.
+    ///
+    /// `expires` is a hint for backends that can expire entries on their own,
+    /// the expiry is still enforced by `access_control::reconcile`.
+    fn authorize(&self, zone: &str, mac: &str, timestamp: i64, expires: Option<i64>) -> Result<()>;
+
+    /// Removes the authorization of the client with the given mac address in `zone`.
+    fn revoke(&self, zone: &str, mac: &str) -> Result<()>;
+
+    /// Lists the clients authorized in `zone`.
+    fn list(&self, zone: &str) -> Result<Vec<Entry>>;
+
+    /// Disables sentry by letting everyone pass, until `unbypass` is called.
+    fn bypass(&self) -> Result<()>;
+
+    /// Removes the bypass, if there is one.
+    fn unbypass(&self) -> Result<()>;
+
+    /// Lets unauthorized clients reach the given networks, replacing the previous ones.
+    fn set_walled_garden(&self, networks: &[Network]) -> Result<()>;
+
+    /// Creates the set of addresses unauthorized clients may reach, filled by dnsmasq.
+    fn ensure_dns_set(&self) -> Result<DnsSet>;
+
+    /// Counts the traffic of the given clients and limits their rates, replacing the
+    /// previous ones. All counters start over at zero.
+    fn set_accounting(&self, clients: &[Client]) -> Result<()>;
+
+    /// Counts the traffic of a single client and limits its rates, replacing its previous
+    /// rules. Its counters start over at zero, those of the other clients are kept.
+    fn set_client_accounting(&self, client: &Client) -> Result<()>;
+
+    /// Stops counting the traffic of the client with the given mac address.
+    fn remove_accounting(&self, mac: &str) -> Result<()>;
+
+    /// The traffic counted since `set_accounting`, by the lowercase mac address.
+    fn counters(&self) -> Result<HashMap<String, Counter>>;
+}
+
+/// Lets every client pass without authorization, or stops doing so.
+pub fn set_bypass(firewall: &Firewall, enabled: bool) -> Result<()> {
+    firewall.unbypass()?;
+    if enabled {
+        firewall.bypass()?;
+    }
+    Ok(())
+}
+
+/// The firewall managing the given zones, the default zone if there are none.
+///
+/// The https of unauthorized clients is redirected to `tls_port`.
+pub fn new(config: &Config, zones: &[String], tls_port: u16) -> Arc<Firewall> {
+    let default = [config::DEFAULT_ZONE.to_owned()];
+    let zones = if zones.is_empty() { &default[..] } else { zones };
+
+    match config.backend {
+        Backend::Iptables => Arc::new(Iptables::new(
+            &config.iptables_table,
+            zone_chains(&config.iptables_chain, zones),
+            tls_port,
+        )),
+        Backend::Nftables => Arc::new(Nftables::new(
+            zone_chains(&config.nftables_chain, zones),
+            tls_port,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_dnsmasq_option() {
+        let ipset = DnsSet::Ipset {
+            v4: "set".to_owned(),
+            v6: "set6".to_owned(),
+        };
+        assert_eq!(ipset.dnsmasq_option("example.com"), "ipset=/example.com/set,set6");
+
+        let nftset = DnsSet::Nftset {
+            v4: "inet#fw4#set".to_owned(),
+            v6: "inet#fw4#set6".to_owned(),
+        };
+        assert_eq!(
+            nftset.dnsmasq_option("example.com"),
+            "nftset=/example.com/4#inet#fw4#set,6#inet#fw4#set6"
+        );
+    }
+
+    #[test]
+    fn test_zone_chains() {
+        let chains = zone_chains("dstnat_{zone}", &["lobby".to_owned(), "staff".to_owned()]);
+        assert_eq!(chain(&chains, "lobby").unwrap(), "dstnat_lobby");
+        assert_eq!(chain(&chains, "staff").unwrap(), "dstnat_staff");
+        assert!(chain(&chains, "conference").is_err());
+    }
+
+    #[test]
+    fn test_backend_deserialize() {
+        let backend: Backend = serde_json::from_str("\"nftables\"").unwrap();
+        assert_eq!(Backend::Nftables, backend);
+        assert_eq!(Backend::Iptables, Backend::default());
+    }
+}
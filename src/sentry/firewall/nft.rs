@@ -0,0 +1,708 @@
+use errors::*;
+use sentry::firewall::{self, Client, Counter, DnsSet, Entry, Firewall};
+use sentry::walled_garden::Network;
+
+use std::collections::HashMap;
+use std::process::Command;
+
+use serde_json;
+
+use chrono::offset::Utc;
+
+const NFT_FAMILY: &str = "inet";
+const NFT_TABLE: &str = "fw4";
+/// The authorized clients of a zone are in the set of this name, followed by the zone.
+const NFT_SET_PREFIX: &str = "sentry_authorized_";
+/// The set shared by all zones of older releases, its accept rules are removed.
+const NFT_LEGACY_SET: &str = "sentry_authorized";
+const NFT_BYPASS_COMMENT: &str = "sentry-bypass";
+const NFT_TLS_REDIRECT_COMMENT: &str = "sentry-tls-redirect";
+const NFT_WALLED_GARDEN_SET: &str = "sentry_walled_garden";
+const NFT_WALLED_GARDEN_SET6: &str = "sentry_walled_garden6";
+const NFT_DNS_SET: &str = "sentry_walled_garden_dns";
+const NFT_DNS_SET6: &str = "sentry_walled_garden_dns6";
+const NFT_ACCOUNTING_CHAIN: &str = "sentry_accounting";
+const NFT_ACCOUNTING_COMMENT: &str = "sentry-acct";
+const NFT_LIMIT_COMMENT: &str = "sentry-limit";
+/// The download limit of a client is a named limit of this name, followed by its mac
+/// address without colons.
+const NFT_DOWNLOAD_LIMIT_PREFIX: &str = "sentry_down_";
+
+fn nft(args: &[&str]) -> Result<String> {
+    let output = Command::new("nft")
+        .args(args)
+        .output()
+        .chain_err(|| "error running nft")?;
+
+    if !output.status.success() {
+        bail!(
+            "nft {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    String::from_utf8(output.stdout).chain_err(|| "error parsing nft output as utf8 string")
+}
+
+/// Whether `nft` failed because the element to delete does not exist.
+fn is_missing(error: &Error) -> bool {
+    error.to_string().ends_with("No such file or directory")
+}
+
+/// Extracts the authorized clients from the output of `nft -j list set`.
+///
+/// Elements only carry their remaining lifetime, the authorization time is
+/// calculated back from `timeout` and `expires`.
+fn parse_set(output: &str, now: i64) -> Vec<Entry> {
+    let mut result = Vec::new();
+
+    let json: serde_json::Value = match serde_json::from_str(output) {
+        Ok(json) => json,
+        Err(_) => return result,
+    };
+
+    let objects = match json["nftables"].as_array() {
+        Some(objects) => objects,
+        None => return result,
+    };
+
+    for object in objects {
+        if let Some(elems) = object["set"]["elem"].as_array() {
+            for elem in elems {
+                if let Some(mac) = elem.as_str() {
+                    result.push(Entry {
+                        mac: mac.to_owned(),
+                        timestamp: now,
+                        expires: None,
+                    });
+                } else if let Some(mac) = elem["elem"]["val"].as_str() {
+                    let timeout = elem["elem"]["timeout"].as_i64();
+                    let expires = elem["elem"]["expires"].as_i64().or(timeout);
+
+                    result.push(Entry {
+                        mac: mac.to_owned(),
+                        timestamp: now - (timeout.unwrap_or(0) - expires.unwrap_or(0)),
+                        expires: expires.map(|e| now + e),
+                    });
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Adds up the counters of the accounting rules per client, from the output of
+/// `nft -j list chain`.
+fn parse_counters(output: &str) -> HashMap<String, Counter> {
+    let mut result: HashMap<String, Counter> = HashMap::new();
+    let prefix = format!("{}=", NFT_ACCOUNTING_COMMENT);
+
+    let json: serde_json::Value = match serde_json::from_str(output) {
+        Ok(json) => json,
+        Err(_) => return result,
+    };
+
+    for object in json["nftables"].as_array().into_iter().flatten() {
+        let rule = &object["rule"];
+        let mac = match rule["comment"].as_str() {
+            Some(comment) if comment.starts_with(&prefix) => {
+                comment[prefix.len()..].to_lowercase()
+            }
+            _ => continue,
+        };
+
+        for expr in rule["expr"].as_array().into_iter().flatten() {
+            let packets = expr["counter"]["packets"].as_u64();
+            let bytes = expr["counter"]["bytes"].as_u64();
+            if let (Some(packets), Some(bytes)) = (packets, bytes) {
+                result
+                    .entry(mac.clone())
+                    .or_insert_with(Counter::default)
+                    .add(Counter { packets, bytes });
+            }
+        }
+    }
+
+    result
+}
+
+/// The named limit shared by the download rules of the client with the given mac address.
+fn download_limit(mac: &str) -> String {
+    format!("{}{}", NFT_DOWNLOAD_LIMIT_PREFIX, mac.to_lowercase().replace(":", ""))
+}
+
+/// Extracts the names of the download limits from the output of `nft list limits`.
+fn parse_limits(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("limit"), Some(name)) if name.starts_with(NFT_DOWNLOAD_LIMIT_PREFIX) => {
+                    Some(name.to_owned())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn kbytes(kbit: u32) -> String {
+    (kbit / 8).max(1).to_string()
+}
+
+/// The accounting rules of a client, the rate limits first to only count the traffic
+/// passing. Downloads are matched per address family and share the named limit of
+/// the client.
+fn client_rules(client: &Client) -> Vec<String> {
+    let comment = format!("comment \"{}={}\"", NFT_ACCOUNTING_COMMENT, client.mac);
+    let limit_comment = format!("comment \"{}={}\"", NFT_LIMIT_COMMENT, client.mac);
+    let upload = format!("ether saddr {}", client.mac);
+    let mut downloads = Vec::new();
+    for &(protocol, ipv6) in &[("ip", false), ("ip6", true)] {
+        let ips: Vec<String> = client
+            .ips
+            .iter()
+            .filter(|ip| ip.is_ipv6() == ipv6)
+            .map(|ip| ip.to_string())
+            .collect();
+        if !ips.is_empty() {
+            downloads.push(format!("{} daddr {{ {} }}", protocol, ips.join(", ")));
+        }
+    }
+    let mut rules = Vec::new();
+
+    if let Some(up) = client.rate.up {
+        rules.push(format!(
+            "{} limit rate over {} kbytes/second drop {}",
+            upload,
+            kbytes(up),
+            limit_comment
+        ));
+    }
+    if client.rate.down.is_some() {
+        for download in &downloads {
+            rules.push(format!(
+                "{} limit name {} drop {}",
+                download,
+                download_limit(&client.mac),
+                limit_comment
+            ));
+        }
+    }
+
+    rules.push(format!("{} counter {}", upload, comment));
+    for download in &downloads {
+        rules.push(format!("{} counter {}", download, comment));
+    }
+
+    rules
+}
+
+/// The set of the clients authorized in `zone`.
+fn authorized_set(zone: &str) -> String {
+    format!("{}{}", NFT_SET_PREFIX, zone)
+}
+
+/// Whether the output of `nft list chain` has a rule matching the named `set`.
+fn matches_set(rules: &str, set: &str) -> bool {
+    rules.contains(&format!("@{} ", set))
+}
+
+/// The rule redirecting https to the tls listener of sentry on `port`, as `nft list`
+/// prints it.
+fn tls_redirect(port: u16) -> String {
+    format!(
+        "tcp dport 443 redirect to :{} comment \"{}\"",
+        port, NFT_TLS_REDIRECT_COMMENT
+    )
+}
+
+/// Extracts the handles of the rules containing `needle` from the output of
+/// `nft -a list chain`.
+fn parse_handles(output: &str, needle: &str) -> Vec<u64> {
+    output
+        .lines()
+        .filter(|line| line.contains(needle))
+        .filter_map(|line| line.rsplit("# handle ").next())
+        .filter_map(|handle| handle.trim().parse().ok())
+        .collect()
+}
+
+/// The fw4 backend, authorized clients are elements of a named set per zone with per
+/// element timeouts, `sentry_authorized_<zone>`. A single rule in the chain of the zone,
+/// by default `dstnat_<zone>`, accepts everyone in its set.
+///
+/// Accept rules are inserted at the top of the zone chains, the https of everyone else
+/// is redirected to the tls listener by the last rule.
+///
+/// The fw4 table is of the `inet` family, its rules apply to ipv4 and ipv6 alike.
+/// Only the destination sets of the walled garden exist once per address family.
+///
+/// The traffic is counted by rules with counters in the `sentry_accounting` chain,
+/// hooked into forward. Rate limits are rules dropping the excess in front of them, the
+/// download rules of a client share a named limit, `sentry_down_<mac>`.
+#[derive(Debug)]
+pub struct Nftables {
+    /// The zones and their chains.
+    chains: Vec<(String, String)>,
+    /// The port of the tls listener.
+    tls_port: u16,
+}
+
+impl Nftables {
+    pub fn new(chains: Vec<(String, String)>, tls_port: u16) -> Nftables {
+        Nftables { chains, tls_port }
+    }
+
+    /// Creates the set of `zone`, its accept rule and the https redirect, unless they are
+    /// already there, and returns the set.
+    fn ensure(&self, zone: &str) -> Result<String> {
+        let chain = firewall::chain(&self.chains, zone)?;
+        let set = authorized_set(zone);
+        nft(&[
+            "add", "set", NFT_FAMILY, NFT_TABLE, &set,
+            "{", "type", "ether_addr;", "flags", "timeout;", "}",
+        ])?;
+
+        let rules = nft(&["-a", "list", "chain", NFT_FAMILY, NFT_TABLE, chain])?;
+        for handle in parse_handles(&rules, &format!("@{} ", NFT_LEGACY_SET)) {
+            nft(&[
+                "delete", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                "handle", &handle.to_string(),
+            ])?;
+        }
+        if !matches_set(&rules, &set) {
+            nft(&[
+                "insert", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                "ether", "saddr", &format!("@{}", set), "accept",
+            ])?;
+        }
+
+        let redirect = tls_redirect(self.tls_port);
+        if !rules.contains(&redirect) {
+            // the redirect to a previous port
+            let comment = format!("comment \"{}\"", NFT_TLS_REDIRECT_COMMENT);
+            for handle in parse_handles(&rules, &comment) {
+                nft(&[
+                    "delete", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                    "handle", &handle.to_string(),
+                ])?;
+            }
+
+            let mut args = vec!["add", "rule", NFT_FAMILY, NFT_TABLE, chain];
+            args.extend(redirect.split_whitespace());
+            nft(&args).chain_err(|| "Error redirecting https with nftables")?;
+        }
+
+        Ok(set)
+    }
+
+    /// Creates an ipv4 or ipv6 address set with the given flags and rules accepting
+    /// it as destination, unless they are already there.
+    fn ensure_destination_set(&self, set: &str, ipv6: bool, flags: &[&str]) -> Result<()> {
+        let (addr_type, protocol) = if ipv6 {
+            ("ipv6_addr;", "ip6")
+        } else {
+            ("ipv4_addr;", "ip")
+        };
+
+        let mut args = vec![
+            "add", "set", NFT_FAMILY, NFT_TABLE, set, "{", "type", addr_type,
+        ];
+        args.extend_from_slice(flags);
+        args.push("}");
+        nft(&args)?;
+
+        for &(_, ref chain) in &self.chains {
+            let rules = nft(&["list", "chain", NFT_FAMILY, NFT_TABLE, chain])?;
+            if !matches_set(&rules, set) {
+                nft(&[
+                    "insert", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                    protocol, "daddr", &format!("@{}", set), "accept",
+                ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ensure_accounting(&self) -> Result<()> {
+        nft(&[
+            "add", "chain", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN,
+            "{", "type", "filter", "hook", "forward", "priority", "mangle;", "}",
+        ]).map(|_| ())
+    }
+
+    /// Adds the download limit and the accounting rules of `client`.
+    fn add_client(&self, client: &Client) -> Result<()> {
+        if let Some(down) = client.rate.down {
+            nft(&[
+                "add", "limit", NFT_FAMILY, NFT_TABLE, &download_limit(&client.mac),
+                "{", "rate", "over", &kbytes(down), "kbytes/second;", "}",
+            ]).chain_err(|| format!("Error limiting the downloads of {}", client.mac))?;
+        }
+
+        for rule in client_rules(client) {
+            let mut args = vec!["add", "rule", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN];
+            args.extend(rule.split_whitespace());
+            nft(&args).chain_err(|| format!("Error counting the traffic of {}", client.mac))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Firewall for Nftables {
+    fn zones(&self) -> Vec<String> {
+        self.chains.iter().map(|&(ref zone, _)| zone.clone()).collect()
+    }
+
+    fn authorize(&self, zone: &str, mac: &str, _timestamp: i64, expires: Option<i64>) -> Result<()> {
+        let set = self.ensure(zone)?;
+
+        // re-adding an element does not refresh its timeout
+        self.revoke(zone, mac).ok();
+
+        match expires {
+            Some(expires) => {
+                let remaining = expires - Utc::now().timestamp();
+                if remaining <= 0 {
+                    return Ok(());
+                }
+
+                nft(&[
+                    "add", "element", NFT_FAMILY, NFT_TABLE, &set,
+                    "{", mac, "timeout", &format!("{}s", remaining), "}",
+                ])
+            }
+            None => nft(&["add", "element", NFT_FAMILY, NFT_TABLE, &set, "{", mac, "}"]),
+        }.chain_err(|| "Error authorizing client with nftables")
+            .map(|_| ())
+    }
+
+    fn revoke(&self, zone: &str, mac: &str) -> Result<()> {
+        firewall::chain(&self.chains, zone)?;
+        let set = authorized_set(zone);
+        match nft(&["delete", "element", NFT_FAMILY, NFT_TABLE, &set, "{", mac, "}"]) {
+            // the element is gone already, e.g. its timeout ran out
+            Err(ref e) if is_missing(e) => Ok(()),
+            result => result
+                .chain_err(|| format!("Error deleting element: {}", mac))
+                .map(|_| ()),
+        }
+    }
+
+    fn list(&self, zone: &str) -> Result<Vec<Entry>> {
+        // the set only exists after the first authorization otherwise
+        let set = self.ensure(zone)?;
+        let output = nft(&["-j", "list", "set", NFT_FAMILY, NFT_TABLE, &set])
+            .chain_err(|| "Could not list the set elements!")?;
+
+        Ok(parse_set(&output, Utc::now().timestamp()))
+    }
+
+    fn bypass(&self) -> Result<()> {
+        for &(_, ref chain) in &self.chains {
+            nft(&[
+                "insert", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                "accept", "comment", &format!("\"{}\"", NFT_BYPASS_COMMENT),
+            ]).chain_err(|| "Error bypassing sentry with nftables")?;
+        }
+
+        Ok(())
+    }
+
+    fn unbypass(&self) -> Result<()> {
+        let comment = format!("comment \"{}\"", NFT_BYPASS_COMMENT);
+        for &(_, ref chain) in &self.chains {
+            let rules = nft(&["-a", "list", "chain", NFT_FAMILY, NFT_TABLE, chain])?;
+
+            for handle in parse_handles(&rules, &comment) {
+                nft(&[
+                    "delete", "rule", NFT_FAMILY, NFT_TABLE, chain,
+                    "handle", &handle.to_string(),
+                ]).chain_err(|| "Error removing the nftables bypass")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_walled_garden(&self, networks: &[Network]) -> Result<()> {
+        for &(set, ipv6) in &[(NFT_WALLED_GARDEN_SET, false), (NFT_WALLED_GARDEN_SET6, true)] {
+            self.ensure_destination_set(set, ipv6, &["flags", "interval;"])?;
+            nft(&["flush", "set", NFT_FAMILY, NFT_TABLE, set])?;
+
+            let elements: Vec<String> = networks
+                .iter()
+                .filter(|n| n.addr.is_ipv6() == ipv6)
+                .map(|n| n.to_string())
+                .collect();
+            if elements.is_empty() {
+                continue;
+            }
+
+            nft(&[
+                "add", "element", NFT_FAMILY, NFT_TABLE, set,
+                "{", &elements.join(", "), "}",
+            ]).chain_err(|| "Error updating the nftables walled garden")?;
+        }
+
+        Ok(())
+    }
+
+    fn ensure_dns_set(&self) -> Result<DnsSet> {
+        let timeout = format!("{}s;", firewall::DNS_SET_TIMEOUT_SECS);
+        let flags = ["flags", "timeout;", "timeout", &timeout];
+        self.ensure_destination_set(NFT_DNS_SET, false, &flags)?;
+        self.ensure_destination_set(NFT_DNS_SET6, true, &flags)?;
+
+        Ok(DnsSet::Nftset {
+            v4: format!("{}#{}#{}", NFT_FAMILY, NFT_TABLE, NFT_DNS_SET),
+            v6: format!("{}#{}#{}", NFT_FAMILY, NFT_TABLE, NFT_DNS_SET6),
+        })
+    }
+
+    fn set_accounting(&self, clients: &[Client]) -> Result<()> {
+        self.ensure_accounting()?;
+        nft(&["flush", "chain", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN])?;
+        // limits in use by rules can not be deleted, only now they are unused
+        let limits = nft(&["list", "limits", "table", NFT_FAMILY, NFT_TABLE])?;
+        for limit in parse_limits(&limits) {
+            nft(&["delete", "limit", NFT_FAMILY, NFT_TABLE, &limit])?;
+        }
+
+        for client in clients {
+            self.add_client(client)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_client_accounting(&self, client: &Client) -> Result<()> {
+        self.ensure_accounting()?;
+        self.remove_accounting(&client.mac)?;
+        self.add_client(client)
+    }
+
+    fn remove_accounting(&self, mac: &str) -> Result<()> {
+        let mac = mac.to_lowercase();
+        let rules = nft(&["-a", "list", "chain", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN])
+            .chain_err(|| "Could not list the accounting rules!")?;
+
+        for comment in &[NFT_ACCOUNTING_COMMENT, NFT_LIMIT_COMMENT] {
+            for handle in parse_handles(&rules, &format!("\"{}={}\"", comment, mac)) {
+                nft(&[
+                    "delete", "rule", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN,
+                    "handle", &handle.to_string(),
+                ])?;
+            }
+        }
+        // there is none without a download rate
+        nft(&["delete", "limit", NFT_FAMILY, NFT_TABLE, &download_limit(&mac)]).ok();
+
+        Ok(())
+    }
+
+    fn counters(&self) -> Result<HashMap<String, Counter>> {
+        let output = nft(&["-j", "list", "chain", NFT_FAMILY, NFT_TABLE, NFT_ACCOUNTING_CHAIN])
+            .chain_err(|| "Could not list the accounting rules!")?;
+
+        Ok(parse_counters(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::firewall::Rate;
+
+    const NFT_LIST_SET_OUTPUT: &'static str = r#"
+    {
+        "nftables": [
+            { "metainfo": { "version": "1.0.2", "json_schema_version": 1 } },
+            { "set": {
+                "family": "inet",
+                "name": "sentry_authorized_public",
+                "table": "fw4",
+                "type": "ether_addr",
+                "handle": 42,
+                "flags": [ "timeout" ],
+                "elem": [
+                    { "elem": { "val": "de:ad:be:ef:00:11", "timeout": 3600, "expires": 3000 } },
+                    "de:ad:be:ef:00:22"
+                ]
+            } }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_set() {
+        let entries = parse_set(NFT_LIST_SET_OUTPUT, 10000);
+
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    mac: String::from("de:ad:be:ef:00:11"),
+                    timestamp: 9400,
+                    expires: Some(13000),
+                },
+                Entry {
+                    mac: String::from("de:ad:be:ef:00:22"),
+                    timestamp: 10000,
+                    expires: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_handles() {
+        let output = r#"table inet fw4 {
+	chain dstnat_public {
+		accept comment "sentry-bypass" # handle 57
+		ether saddr @sentry_authorized accept # handle 42
+		ether saddr @sentry_authorized_public accept # handle 43
+		accept comment "sentry-bypass" # handle 58
+	}
+}"#;
+
+        assert_eq!(parse_handles(output, "comment \"sentry-bypass\""), vec![57, 58]);
+        assert_eq!(parse_handles(output, "@sentry_authorized "), vec![42]);
+        assert!(parse_handles(output, "other").is_empty());
+    }
+
+    #[test]
+    fn test_tls_redirect() {
+        let output = r#"table inet fw4 {
+	chain dstnat_public {
+		ether saddr @sentry_authorized_public accept # handle 43
+		tcp dport 443 redirect to :8443 comment "sentry-tls-redirect" # handle 44
+	}
+}"#;
+
+        assert!(output.contains(&tls_redirect(8443)));
+        assert!(!output.contains(&tls_redirect(9443)));
+        assert_eq!(parse_handles(output, "comment \"sentry-tls-redirect\""), vec![44]);
+    }
+
+    #[test]
+    fn test_zone_sets() {
+        let rules = "ether saddr @sentry_authorized_lobby accept";
+        assert!(matches_set(rules, &authorized_set("lobby")));
+        assert!(!matches_set(rules, &authorized_set("staff")));
+        // the accept rule of another zone does not count for a prefix of its name
+        assert!(!matches_set(rules, &authorized_set("lob")));
+        assert!(!matches_set(rules, NFT_LEGACY_SET));
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let output = r#"
+        {
+            "nftables": [
+                { "metainfo": { "version": "1.0.2", "json_schema_version": 1 } },
+                { "chain": { "family": "inet", "table": "fw4", "name": "sentry_accounting" } },
+                { "rule": {
+                    "chain": "sentry_accounting",
+                    "comment": "sentry-acct=de:ad:be:ef:00:11",
+                    "expr": [
+                        { "match": { "op": "==", "left": { "payload": { "protocol": "ether", "field": "saddr" } }, "right": "de:ad:be:ef:00:11" } },
+                        { "counter": { "packets": 10, "bytes": 1200 } }
+                    ]
+                } },
+                { "rule": {
+                    "chain": "sentry_accounting",
+                    "comment": "sentry-acct=de:ad:be:ef:00:11",
+                    "expr": [
+                        { "match": { "op": "==", "left": { "payload": { "protocol": "ip6", "field": "daddr" } }, "right": "2001:db8::2c1" } },
+                        { "counter": { "packets": 20, "bytes": 30000 } }
+                    ]
+                } },
+                { "rule": {
+                    "chain": "sentry_accounting",
+                    "expr": [ { "counter": { "packets": 1, "bytes": 1 } } ]
+                } }
+            ]
+        }"#;
+
+        let counters = parse_counters(output);
+        assert_eq!(counters.len(), 1);
+        assert_eq!(
+            counters["de:ad:be:ef:00:11"],
+            Counter {
+                packets: 30,
+                bytes: 31200,
+            }
+        );
+        assert!(parse_counters("Error: No such file or directory").is_empty());
+    }
+
+    #[test]
+    fn test_client_rules() {
+        let client = Client {
+            mac: "de:ad:be:ef:00:11".to_owned(),
+            ips: vec![
+                "192.168.8.1".parse().unwrap(),
+                "2001:db8::2c1".parse().unwrap(),
+                "2001:db8::2c2".parse().unwrap(),
+            ],
+            rate: Rate {
+                up: Some(1000),
+                down: Some(4000),
+            },
+        };
+
+        assert_eq!(
+            client_rules(&client),
+            vec![
+                "ether saddr de:ad:be:ef:00:11 limit rate over 125 kbytes/second drop \
+                 comment \"sentry-limit=de:ad:be:ef:00:11\"",
+                "ip daddr { 192.168.8.1 } limit name sentry_down_deadbeef0011 drop \
+                 comment \"sentry-limit=de:ad:be:ef:00:11\"",
+                "ip6 daddr { 2001:db8::2c1, 2001:db8::2c2 } limit name sentry_down_deadbeef0011 \
+                 drop comment \"sentry-limit=de:ad:be:ef:00:11\"",
+                "ether saddr de:ad:be:ef:00:11 counter comment \"sentry-acct=de:ad:be:ef:00:11\"",
+                "ip daddr { 192.168.8.1 } counter comment \"sentry-acct=de:ad:be:ef:00:11\"",
+                "ip6 daddr { 2001:db8::2c1, 2001:db8::2c2 } counter comment \
+                 \"sentry-acct=de:ad:be:ef:00:11\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let output = r#"table inet fw4 {
+	limit sentry_down_deadbeef0011 {
+		rate over 500 kbytes/second
+	}
+	limit other {
+		rate 10/second
+	}
+}"#;
+
+        assert_eq!(parse_limits(output), vec!["sentry_down_deadbeef0011"]);
+        assert_eq!(download_limit("DE:AD:BE:EF:00:11"), "sentry_down_deadbeef0011");
+    }
+
+    #[test]
+    fn test_parse_set_invalid_output() {
+        assert!(parse_set("Error: No such file or directory", 10000).is_empty());
+    }
+
+    #[test]
+    fn test_is_missing() {
+        let missing = Error::from(
+            "nft delete element inet fw4 sentry_authorized_public { de:ad:be:ef:00:11 } failed: \
+             Error: Could not process rule: No such file or directory",
+        );
+        assert!(is_missing(&missing));
+
+        let denied = Error::from("nft delete element failed: Error: Operation not permitted");
+        assert!(!is_missing(&denied));
+    }
+}
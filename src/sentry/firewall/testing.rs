@@ -0,0 +1,139 @@
+//! An in-memory firewall for tests.
+
+use errors::*;
+use sentry::config;
+use sentry::firewall::{Client, Counter, DnsSet, Entry, Firewall};
+use sentry::walled_garden::Network;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Default, Debug)]
+pub struct FakeFirewall {
+    /// The zones managed, the default zone if empty.
+    pub zones: Vec<String>,
+    /// The authorized clients with their zone.
+    pub entries: Mutex<Vec<(String, Entry)>>,
+    pub bypassed: Mutex<bool>,
+    pub walled_garden: Mutex<Vec<Network>>,
+    pub accounting: Mutex<Vec<Client>>,
+    /// The counters handed out, `set_accounting` resets them.
+    pub counters: Mutex<HashMap<String, Counter>>,
+    /// Reading the counters fails while set.
+    pub counters_fail: Mutex<bool>,
+}
+
+impl FakeFirewall {
+    pub fn with_zones(zones: &[&str]) -> FakeFirewall {
+        FakeFirewall {
+            zones: zones.iter().map(|zone| zone.to_string()).collect(),
+            ..FakeFirewall::default()
+        }
+    }
+
+    fn check_zone(&self, zone: &str) -> Result<()> {
+        if !self.zones().iter().any(|z| z == zone) {
+            bail!("unknown firewall zone: {}", zone);
+        }
+        Ok(())
+    }
+}
+
+impl Firewall for FakeFirewall {
+    fn zones(&self) -> Vec<String> {
+        if self.zones.is_empty() {
+            vec![config::DEFAULT_ZONE.to_owned()]
+        } else {
+            self.zones.clone()
+        }
+    }
+
+    fn authorize(&self, zone: &str, mac: &str, timestamp: i64, expires: Option<i64>) -> Result<()> {
+        self.revoke(zone, mac)?;
+        self.entries.lock().unwrap().push((
+            zone.to_owned(),
+            Entry {
+                mac: mac.to_owned(),
+                timestamp: timestamp,
+                expires: expires,
+            },
+        ));
+        Ok(())
+    }
+
+    fn revoke(&self, zone: &str, mac: &str) -> Result<()> {
+        self.check_zone(zone)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|&(ref z, ref entry)| z != zone || !entry.mac.eq_ignore_ascii_case(mac));
+        Ok(())
+    }
+
+    fn list(&self, zone: &str) -> Result<Vec<Entry>> {
+        self.check_zone(zone)?;
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&&(ref z, _)| z == zone)
+            .map(|&(_, ref entry)| entry.clone())
+            .collect())
+    }
+
+    fn bypass(&self) -> Result<()> {
+        *self.bypassed.lock().unwrap() = true;
+        Ok(())
+    }
+
+    fn unbypass(&self) -> Result<()> {
+        *self.bypassed.lock().unwrap() = false;
+        Ok(())
+    }
+
+    fn set_walled_garden(&self, networks: &[Network]) -> Result<()> {
+        *self.walled_garden.lock().unwrap() = networks.to_vec();
+        Ok(())
+    }
+
+    fn ensure_dns_set(&self) -> Result<DnsSet> {
+        Ok(DnsSet::Ipset {
+            v4: "sentry_walled_garden_dns".to_owned(),
+            v6: "sentry_walled_garden_dns6".to_owned(),
+        })
+    }
+
+    fn set_accounting(&self, clients: &[Client]) -> Result<()> {
+        *self.accounting.lock().unwrap() = clients.to_vec();
+        *self.counters.lock().unwrap() = clients
+            .iter()
+            .map(|client| (client.mac.to_lowercase(), Counter::default()))
+            .collect();
+        Ok(())
+    }
+
+    fn set_client_accounting(&self, client: &Client) -> Result<()> {
+        self.remove_accounting(&client.mac)?;
+        self.accounting.lock().unwrap().push(client.clone());
+        self.counters
+            .lock()
+            .unwrap()
+            .insert(client.mac.to_lowercase(), Counter::default());
+        Ok(())
+    }
+
+    fn remove_accounting(&self, mac: &str) -> Result<()> {
+        let mac = mac.to_lowercase();
+        self.accounting.lock().unwrap().retain(|client| client.mac != mac);
+        self.counters.lock().unwrap().remove(&mac);
+        Ok(())
+    }
+
+    fn counters(&self) -> Result<HashMap<String, Counter>> {
+        if *self.counters_fail.lock().unwrap() {
+            bail!("unable to read the counters");
+        }
+        Ok(self.counters.lock().unwrap().clone())
+    }
+}
@@ -1,3 +1,6 @@
+use sentry::neighbors::Neighbors;
+
+use std::net::{IpAddr, Ipv4Addr};
 use std::process::Command;
 
 fn execute(args: &[&str]) -> Option<String> {
@@ -10,35 +13,134 @@ fn execute(args: &[&str]) -> Option<String> {
     None
 }
 
+/// The neighbors with a link layer address in the output of `ip n`, as ip, mac,
+/// state and device.
+fn neighbors(output: &str) -> Vec<(IpAddr, &str, &str, &str)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+
+            if cols.len() < 6 || cols[3] != "lladdr" {
+                return None;
+            }
+
+            cols[0]
+                .parse()
+                .ok()
+                .map(|ip| (ip, cols[4], cols[cols.len() - 1], cols[2]))
+        })
+        .collect()
+}
+
 fn get_mac_impl(ip: &str, output: &str) -> Option<String> {
-    for line in output.lines() {
-        let cols: Vec<&str> = line.split_whitespace().collect();
+    // compare parsed addresses, ipv6 addresses have several notations
+    let ip = normalize(ip.parse().ok()?);
 
-        if cols.len() < 6 {
-            continue;
-        }
+    neighbors(output)
+        .into_iter()
+        .find(|&(neighbor, _, _, _)| neighbor == ip)
+        .map(|(_, mac, _, _)| mac.to_owned())
+}
 
-        if cols[0] == ip {
-            return Some(cols[4].to_owned());
-        }
+fn get_device_impl(ip: &IpAddr, output: &str) -> Option<String> {
+    neighbors(output)
+        .into_iter()
+        .find(|&(neighbor, _, _, _)| neighbor == *ip)
+        .map(|(_, _, _, device)| device.to_owned())
+}
+
+fn get_ips_impl(mac: &str, output: &str) -> Vec<IpAddr> {
+    neighbors(output)
+        .into_iter()
+        .filter(|&(_, neighbor, _, _)| neighbor.eq_ignore_ascii_case(mac))
+        .map(|(ip, _, _, _)| ip)
+        .collect()
+}
+
+fn get_active_impl(mac: &str, output: &str) -> bool {
+    neighbors(output).into_iter().any(|(_, neighbor, state, _)| {
+        neighbor.eq_ignore_ascii_case(mac) && ["REACHABLE", "DELAY", "PROBE"].contains(&state)
+    })
+}
+
+/// Turns ipv4-mapped ipv6 addresses, as seen by a listener on `[::]`, back into ipv4.
+pub fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => IpAddr::V4(Ipv4Addr::new(
+                (hi >> 8) as u8,
+                hi as u8,
+                (lo >> 8) as u8,
+                lo as u8,
+            )),
+            _ => ip,
+        },
+        ip => ip,
     }
+}
 
-    None
+/// Checks for a mac address in the `DE:AD:BE:EF:00:11` notation.
+pub fn is_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
 }
 
-pub fn ip_to_mac(ip: &str) -> Option<String> {
-    if let Some(output) = execute(&["n"]) {
+/// Looks up the mac address of a neighbor, ipv4 or ipv6.
+fn ip_to_mac(ip: &str) -> Option<String> {
+    let family = match normalize(ip.parse().ok()?) {
+        IpAddr::V4(_) => "-4",
+        IpAddr::V6(_) => "-6",
+    };
+
+    if let Some(output) = execute(&[family, "n"]) {
         get_mac_impl(ip, &output)
     } else {
         None
     }
 }
 
+/// All addresses of the neighbor with the given mac address.
+///
+/// Clients using SLAAC privacy extensions change their ipv6 address regularly and
+/// use several at once, only one of them is usually known to dhcp.
+fn mac_to_ips(mac: &str) -> Vec<IpAddr> {
+    if let Some(output) = execute(&["n"]) {
+        get_ips_impl(mac, &output)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Looks up the neighbors by running `ip n`, for systems without netlink access.
+#[derive(Default, Debug)]
+pub struct IpCommand;
+
+impl Neighbors for IpCommand {
+    fn mac(&self, ip: &IpAddr) -> Option<String> {
+        ip_to_mac(&ip.to_string())
+    }
+
+    fn ips(&self, mac: &str) -> Vec<IpAddr> {
+        mac_to_ips(mac)
+    }
+
+    fn device(&self, ip: &IpAddr) -> Option<String> {
+        execute(&["n"]).and_then(|output| get_device_impl(ip, &output))
+    }
 
+    fn is_active(&self, mac: &str) -> bool {
+        execute(&["n"]).map_or(false, |output| get_active_impl(mac, &output))
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use super::get_mac_impl;
+    use super::{get_active_impl, get_device_impl, get_ips_impl, get_mac_impl, is_mac, normalize};
 
     const TEST_IP_OUTPUT: &'static str = "192.168.8.1 dev enp0s20u1 lladdr \
                                           DE:AD:BE:EF:00:11 REACHABLE\n\
@@ -61,9 +163,89 @@ mod tests {
         }
     }
 
+    const TEST_IP6_OUTPUT: &'static str = "fe80::dcad:beff:feef:11 dev br-lan lladdr \
+                                           de:ad:be:ef:00:11 STALE\n\
+                                           2001:db8:0:1:8d2c:1a4:6b3f:9e01 dev br-lan lladdr \
+                                           de:ad:be:ef:00:11 REACHABLE\n\
+                                           2001:db8:0:1::1 dev br-lan lladdr \
+                                           de:ad:be:ef:00:01 router REACHABLE\n\
+                                           2001:db8:0:1::99 dev br-lan  FAILED";
+
+    #[test]
+    fn test_get_mac_ipv6() {
+        let test_ip_addresses = [
+            ("2001:db8:0:1:8d2c:1a4:6b3f:9e01", Some(String::from("de:ad:be:ef:00:11"))),
+            ("2001:0db8:0000:0001:8d2c:01a4:6b3f:9e01", Some(String::from("de:ad:be:ef:00:11"))),
+            ("2001:db8:0:1::1", Some(String::from("de:ad:be:ef:00:01"))),
+            ("2001:db8:0:1::99", None),
+            ("no address", None),
+        ];
+
+        for &(ref test_ip, ref test_result) in &test_ip_addresses {
+            assert_eq!(*test_result, get_mac_impl(&test_ip, &TEST_IP6_OUTPUT));
+        }
+
+        assert_eq!(
+            Some(String::from("DE:AD:BE:EF:00:11")),
+            get_mac_impl("::ffff:192.168.8.1", &TEST_IP_OUTPUT)
+        );
+    }
+
+    #[test]
+    fn test_get_ips() {
+        let ips: Vec<String> = get_ips_impl("DE:AD:BE:EF:00:11", &TEST_IP6_OUTPUT)
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect();
+
+        assert_eq!(
+            ips,
+            vec!["fe80::dcad:beff:feef:11", "2001:db8:0:1:8d2c:1a4:6b3f:9e01"]
+        );
+    }
+
+    #[test]
+    fn test_get_active() {
+        assert!(get_active_impl("DE:AD:BE:EF:00:11", &TEST_IP6_OUTPUT));
+        assert!(get_active_impl("de:ad:be:ef:00:01", &TEST_IP6_OUTPUT));
+        assert!(!get_active_impl("de:ad:be:ef:00:22", &TEST_IP6_OUTPUT));
+        assert!(!get_active_impl("de:ad:be:ef:00:11", "192.168.8.1 dev br-lan lladdr \
+                                                       de:ad:be:ef:00:11 STALE"));
+    }
+
+    #[test]
+    fn test_get_device() {
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert_eq!(
+            get_device_impl(&ip("192.168.8.2"), TEST_IP_OUTPUT),
+            Some("enp0s20u2".to_owned())
+        );
+        assert_eq!(get_device_impl(&ip("192.168.8.3"), TEST_IP_OUTPUT), None);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert_eq!(normalize(ip("::ffff:192.168.8.1")), ip("192.168.8.1"));
+        assert_eq!(normalize(ip("192.168.8.1")), ip("192.168.8.1"));
+        assert_eq!(normalize(ip("::1")), ip("::1"));
+        assert_eq!(normalize(ip("2001:db8::1")), ip("2001:db8::1"));
+    }
+
     #[test]
     fn test_get_mac_invalid_output() {
         assert_eq!(None, get_mac_impl("192.168.8.1", &TEST_INVALID_IP_OUTPUT));
     }
 
+    #[test]
+    fn test_is_mac() {
+        assert!(is_mac("DE:AD:BE:EF:00:11"));
+        assert!(is_mac("de:ad:be:ef:00:11"));
+        assert!(!is_mac("DE:AD:BE:EF:00"));
+        assert!(!is_mac("DE:AD:BE:EF:00:1G"));
+        assert!(!is_mac("DEAD:BE:EF:00:11:"));
+    }
+
 }
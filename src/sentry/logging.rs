@@ -0,0 +1,268 @@
+//! Leveled logging to logd, or any syslog, and stderr.
+//!
+//! The records are filtered by the `level` of the `log` settings, a level optionally
+//! followed by levels of targets, e.g. `info,sentry::proxy=debug`. The targets are the
+//! module paths without the crate name.
+//!
+//! Secrets added with `add_secret` and the values of parameters ending in `token`,
+//! `secret` or `key`, e.g. `access_token` or `api_key`, never reach the log. With
+//! `redact_macs`, mac addresses are shortened to their vendor part.
+
+use errors::*;
+
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+
+use regex::Regex;
+
+/// The socket of logd and syslog daemons.
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+/// The syslog facility of system daemons.
+const SYSLOG_FACILITY_DAEMON: u8 = 3;
+
+lazy_static! {
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+    static ref SECRET_PARAM: Regex = Regex::new(
+        r"(?i)(^|[?&\s])([a-z_]*(?:token|secret|key))=[^&\s]+"
+    ).unwrap();
+    static ref MAC: Regex = Regex::new(
+        r"(?i)\b([0-9a-f]{2}[:-][0-9a-f]{2}[:-][0-9a-f]{2})[:-][0-9a-f]{2}[:-][0-9a-f]{2}[:-][0-9a-f]{2}\b"
+    ).unwrap();
+}
+
+/// Where the log goes.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// logd on OpenWrt, stderr if it is not reachable.
+    Syslog,
+    Stderr,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub output: Output,
+    pub level: String,
+    /// Log only the vendor part of mac addresses.
+    pub redact_macs: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            output: Output::Syslog,
+            level: "info".to_owned(),
+            redact_macs: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        Filter::parse(&self.level).map(|_| ())
+    }
+}
+
+/// The level of each target, the most specific target counts.
+#[derive(Clone, PartialEq, Debug)]
+struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Filter> {
+        let level = |s: &str| {
+            LevelFilter::from_str(s.trim()).map_err(|_| Error::from(format!("unknown log level: {}", s)))
+        };
+
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').filter(|d| !d.trim().is_empty()) {
+            match directive.find('=') {
+                Some(i) => {
+                    let target = directive[..i].trim().to_owned();
+                    filter.targets.push((target, level(&directive[i + 1..])?));
+                }
+                None => filter.default = level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| target == t || target.starts_with(&format!("{}::", t)))
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, ::std::cmp::max)
+    }
+}
+
+/// The module path without the crate name.
+fn short_target(target: &str) -> &str {
+    target.strip_prefix("sentry::").unwrap_or(target)
+}
+
+/// Never logs `secret`, e.g. a key read from a config.
+pub fn add_secret(secret: &str) {
+    if !secret.is_empty() {
+        SECRETS.write().unwrap().push(secret.to_owned());
+    }
+}
+
+fn redact(message: &str, macs: bool) -> String {
+    let mut message = SECRET_PARAM
+        .replace_all(message, "$1$2=<redacted>")
+        .into_owned();
+    for secret in SECRETS.read().unwrap().iter() {
+        message = message.replace(secret.as_str(), "<redacted>");
+    }
+    if macs {
+        message = MAC.replace_all(&message, "$1:xx:xx:xx").into_owned();
+    }
+    message
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn connect_syslog() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SYSLOG_SOCKET)?;
+    Ok(socket)
+}
+
+struct Logger {
+    filter: Filter,
+    redact_macs: bool,
+    /// The syslog socket, stderr is used without one.
+    syslog: Option<Mutex<UnixDatagram>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(short_target(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let target = short_target(record.target());
+        let message = redact(&record.args().to_string(), self.redact_macs);
+
+        if let Some(ref syslog) = self.syslog {
+            let line = format!(
+                "<{}>sentry[{}]: {}: {}",
+                SYSLOG_FACILITY_DAEMON * 8 + severity(record.level()),
+                process::id(),
+                target,
+                message
+            );
+            let mut socket = syslog.lock().unwrap();
+            if socket.send(line.as_bytes()).is_ok() {
+                return;
+            }
+            // a restarted logd listens on a new socket
+            if let Ok(reconnected) = connect_syslog() {
+                *socket = reconnected;
+                if socket.send(line.as_bytes()).is_ok() {
+                    return;
+                }
+            }
+        }
+
+        let _ = writeln!(io::stderr(), "{:<5} {}: {}", record.level(), target, message);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, it stays for the lifetime of the process.
+pub fn init(config: &Config) -> Result<()> {
+    let filter = Filter::parse(&config.level).chain_err(|| "invalid log level")?;
+    let syslog = match config.output {
+        Output::Syslog => connect_syslog()
+            .map_err(|e| eprintln!("unable to reach {}, logging to stderr: {}", SYSLOG_SOCKET, e))
+            .ok()
+            .map(Mutex::new),
+        Output::Stderr => None,
+    };
+
+    log::set_max_level(filter.max());
+    log::set_boxed_logger(Box::new(Logger {
+        filter,
+        redact_macs: config.redact_macs,
+        syslog,
+    })).chain_err(|| "unable to install the logger")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse("warn, sentry::proxy=debug,sentry=error").unwrap();
+        assert_eq!(filter.level("sentry::proxy"), LevelFilter::Debug);
+        assert_eq!(filter.level("sentry::proxy::client"), LevelFilter::Debug);
+        assert_eq!(filter.level("sentry::proxy_cache"), LevelFilter::Error);
+        assert_eq!(filter.level("sentry::api"), LevelFilter::Error);
+        assert_eq!(filter.level("time_control"), LevelFilter::Warn);
+        assert_eq!(filter.max(), LevelFilter::Debug);
+
+        assert_eq!(Filter::parse("").unwrap().level("sentry"), LevelFilter::Info);
+        assert!(Filter::parse("loud").is_err());
+        assert!(Filter::parse("info,sentry::api=loud").is_err());
+
+        assert_eq!(short_target("sentry::sentry::proxy"), "sentry::proxy");
+        assert_eq!(short_target("hyper::client"), "hyper::client");
+    }
+
+    #[test]
+    fn test_redact() {
+        add_secret("hunter22");
+        assert_eq!(
+            redact("GET /?token=abc.def&tos=1 with hunter22", false),
+            "GET /?token=<redacted>&tos=1 with <redacted>"
+        );
+        assert_eq!(
+            redact("POST /oauth?access_token=a1&api_key=b2&client_secret=c3&x=1", false),
+            "POST /oauth?access_token=<redacted>&api_key=<redacted>&client_secret=<redacted>&x=1"
+        );
+        assert_eq!(redact("sentry_token=abc", false), "sentry_token=<redacted>");
+        assert_eq!(redact("keyboard=qwerty&tokens=2", false), "keyboard=qwerty&tokens=2");
+        assert_eq!(
+            redact("session idle: DE:AD:BE:EF:00:11", true),
+            "session idle: DE:AD:BE:xx:xx:xx"
+        );
+        assert_eq!(
+            redact("session idle: DE:AD:BE:EF:00:11", false),
+            "session idle: DE:AD:BE:EF:00:11"
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Counters exposed by the management api.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub redirects: AtomicUsize,
+    pub portal_requests: AtomicUsize,
+    pub proxied_requests: AtomicUsize,
+    pub authorizations: AtomicUsize,
+    pub rejected_tokens: AtomicUsize,
+    pub revocations: AtomicUsize,
+}
+
+pub fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// Renders the counters in the prometheus text format.
+    pub fn render(&self, sessions: usize) -> String {
+        let mut out = String::new();
+
+        for &(name, help, counter) in &[
+            ("redirects", "Requests redirected to the portal.", &self.redirects),
+            ("portal_requests", "Requests forwarded to the portal.", &self.portal_requests),
+            ("proxied_requests", "Requests proxied for unauthorized clients.", &self.proxied_requests),
+            ("authorizations", "Clients authorized.", &self.authorizations),
+            ("rejected_tokens", "Authorization tokens rejected.", &self.rejected_tokens),
+            ("revocations", "Sessions revoked.", &self.revocations),
+        ] {
+            writeln!(out, "# HELP sentry_{}_total {}", name, help).unwrap();
+            writeln!(out, "# TYPE sentry_{}_total counter", name).unwrap();
+            writeln!(out, "sentry_{}_total {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP sentry_sessions Currently authorized clients.").unwrap();
+        writeln!(out, "# TYPE sentry_sessions gauge").unwrap();
+        writeln!(out, "sentry_sessions {}", sessions).unwrap();
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        inc(&metrics.redirects);
+        inc(&metrics.redirects);
+
+        let out = metrics.render(3);
+        assert!(out.contains("\nsentry_redirects_total 2\n"));
+        assert!(out.contains("\nsentry_authorizations_total 0\n"));
+        assert!(out.ends_with("\nsentry_sessions 3\n"));
+    }
+}
@@ -6,119 +6,223 @@ mod service;
 mod ubus;
 mod config;
 mod access_control;
+mod store;
+mod token;
+mod firewall;
+mod ubus_object;
+mod carrier_api;
+mod metrics;
+mod api;
+mod reload;
+mod degraded;
+mod walled_garden;
+mod dnsmasq;
+mod tls;
+mod neighbors;
+mod events;
+mod settings;
+mod cli;
+mod logging;
 
 use errors::*;
+use sentry::api::Api;
+use sentry::config::Portal;
+use sentry::metrics::Metrics;
 use sentry::sentry::Sentry;
 use sentry::service::Service;
+use sentry::settings::Settings;
+use sentry::store::Store;
 
-use std::fs::File;
-use std::io::Read;
-use std::str::FromStr;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::TcpListener;
 
-use hyper;
 use hyper::server::Http;
 
-use futures::Stream;
+use futures::{Future, Stream};
 
 use rand::{self, Rng};
 
 use carrier;
 
-const DEFAULT_LISTEN_PORT: u16 = 8444;
-const SECRET_LENGTH: usize = 16;
-
-fn get_redirect_host(redirect_url: &str) -> Result<String> {
-    let uri = hyper::Uri::from_str(redirect_url)
-        .chain_err(|| "unable to convert redirect url to an uri")?;
-
-    uri.host()
-        .map(|s| s.to_owned())
-        .ok_or_else(|| "unable to extract the host from the redirect url".into())
-}
-
-fn create_secret() -> String {
+fn create_secret(length: usize) -> String {
     rand::thread_rng()
         .gen_ascii_chars()
-        .take(SECRET_LENGTH)
+        .take(length)
         .collect::<String>()
 }
 
+/// Listens on `port` of all ipv6 and ipv4 addresses, ipv4 clients show up with
+/// ipv4-mapped addresses then. Hosts without ipv6 only listen on ipv4.
+fn bind(port: u16, handle: &Handle) -> io::Result<TcpListener> {
+    let any6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
 
-pub fn dead<A, E: std::fmt::Debug>(e:E) -> Result<A>  {
-    Sentry::bypass().ok();
-    eprintln!("sentry dead idle, reason: {:?}", e);
-    loop {
-        std::thread::sleep(std::time::Duration::from_secs(10000));
-    }
+    TcpListener::bind(&any6, handle).or_else(|e| {
+        warn!("unable to listen on {}, falling back to ipv4: {}", any6, e);
+        TcpListener::bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), handle)
+    })
 }
 
-pub fn sentry_main(
-    listen_port: Option<u16>,
-) -> Result<()> {
+pub use self::cli::cli_main;
 
-    eprintln!("sentry coming up");
-    let identity = carrier::config::load().expect("carrier::config::load").secret.identity().to_string();
+/// Runs sentry until it fails, `settings` are layered with the captif config.
+pub fn sentry_main(settings: Settings) -> Result<()> {
 
-    let mut genesis : std::path::PathBuf = "/etc/config/genesis/current.json".into();
-    if !genesis.exists() {
-        genesis = "/etc/config/genesis/stable.json".into();
-    }
-    let mut f = File::open(genesis).or_else(dead)?;
-    let mut s = Vec::new();
-    f.read_to_end(&mut s).or_else(dead).unwrap();
-    let config : config::Genesis = serde_json::de::from_slice(&s).or_else(dead)?;
-
-    println!("{:?}", config);
-    let config = match config.captif {
-        Some(v) => v,
-        None => {
-            dead("no captif config").unwrap()
-        },
-    };
+    info!("sentry coming up");
+    let identity = carrier::config::load().expect("carrier::config::load").secret.identity().to_string();
 
+    let listen_port = settings.listen_port;
 
-    let redirect_url = config.url.clone();
-    let redirect_host =
-        get_redirect_host(&redirect_url).chain_err(|| "Error extracting redirect host!")?;
-    let secret = create_secret();
+    let store = Arc::new(Mutex::new(Store::open(store::VOLATILE_PATH, store::PERSISTENT_PATH)));
 
-    let listen_address_string = format!(
-        "0.0.0.0:{}",
-        listen_port.unwrap_or_else(|| DEFAULT_LISTEN_PORT)
-    );
-    let listen_address = listen_address_string
+    let config = match config::load_captif(&settings) {
+        Ok(config) => config,
+        Err(e) => degraded::run(&settings, listen_port, &store, &e)?,
+    };
+    let config = Arc::new(config);
+    if let Some(ref key) = config.token_key {
+        logging::add_secret(key);
+    }
+    info!("captif config loaded, zones: {}", config.zone_names().join(", "));
+    debug!("{:?}", config);
+
+    let settings = settings.layer(&config);
+    let portal = Portal::new(&config, &settings)?;
+    let secret = create_secret(settings.secret_length);
+    logging::add_secret(&secret);
+    let api_address: SocketAddr = settings
+        .api_address
         .parse()
-        .chain_err(|| "Error parsing listen address!")?;
+        .chain_err(|| "Error parsing api address!")?;
 
     let mut evt_loop = Core::new().chain_err(|| "Could not initialize event loop")?;
     let evt_loop_handle = evt_loop.handle();
 
-    let listener =
-        TcpListener::bind(&listen_address, &evt_loop_handle).chain_err(|| "unable to listen")?;
+    let listener = bind(listen_port, &evt_loop_handle).chain_err(|| "unable to listen")?;
+    let tls_listener = bind(settings.tls_listen_port, &evt_loop_handle)
+        .chain_err(|| "unable to listen for tls")?;
+    let api_listener = TcpListener::bind(&api_address, &evt_loop_handle)
+        .chain_err(|| "unable to listen for the api")?;
     let mut http = Http::new();
 
-    let sentry = Sentry::new(secret.clone(), identity, evt_loop_handle.clone());
+    let firewall = firewall::new(
+        &settings.firewall,
+        &config.zone_names(),
+        settings.tls_listen_port,
+    );
+    if let Err(e) = degraded::leave(&*firewall) {
+        error!("unable to remove the bypass: {}", e);
+    }
+    if let Err(e) = degraded::Fallback::new(&settings, &config).save(degraded::FALLBACK_PATH) {
+        warn!("unable to save the fallback config: {}", e);
+    }
+    degraded::announce("running", None, "");
+    if let Err(e) = walled_garden::apply(&*firewall, &portal.walled_garden) {
+        error!("unable to set up the walled garden: {}", e);
+    }
+    let captive_api_url = portal.captive_api_url.as_ref().map(|u| u.as_str());
+    if let Err(e) = dnsmasq::update_captive_api(captive_api_url, &portal.zones) {
+        warn!("unable to announce the captive portal api: {}", e);
+    }
+
+    let tokens = config
+        .token_key
+        .as_ref()
+        .map(|key| Arc::new(token::Verifier::new(key, &identity)));
+    if tokens.is_none() && !config.legacy_auth {
+        warn!("neither token_key nor legacy_auth configured, the portal can not authorize clients");
+    }
+
+    let events = Arc::new(events::Events::new(&identity, &settings.events));
+    let reconcile_interval = Duration::from_secs(settings.reconcile_interval);
+    let sentry = Sentry::new(
+        secret.clone(),
+        identity,
+        evt_loop.remote(),
+        firewall.clone(),
+        neighbors::new(),
+        store.clone(),
+        Arc::new(RwLock::new(portal)),
+        config.clone(),
+        tokens,
+        config.legacy_auth,
+        Arc::new(Metrics::default()),
+        events,
+        Arc::new(settings),
+    );
+
+    // re-install the sessions lost by a reboot or firewall reload
+    if let Err(e) = sentry.reconcile() {
+        error!("unable to restore sessions: {}", e);
+    }
+
+    let watch_sentry = sentry.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = reload::watch_genesis(watch_sentry) {
+            warn!("not watching the genesis config: {}", e);
+        }
+    });
+
+    let sighup_sentry = sentry.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = reload::on_sighup(sighup_sentry) {
+            warn!("not reloading on SIGHUP: {}", e);
+        }
+    });
+
+    let ubus_sentry = sentry.clone();
+    std::thread::spawn(move || ubus_object::run(ubus_sentry));
+
+    if !config.backends.is_empty() {
+        let carrier_sentry = sentry.clone();
+        let backends = config.backends.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = carrier_api::run(carrier_sentry, backends) {
+                error!("sentry carrier api down: {}", e);
+            }
+        });
+    }
+
+    // serve the management api next to the captive traffic
+    let api = Api::new(sentry.clone());
+    let api_handle = evt_loop_handle.clone();
+    let api_http = Http::new();
+    let api_server = api_listener.incoming().for_each(move |(socket, addr)| {
+        api_http.bind_connection(&api_handle, socket, addr, api.clone());
+        Ok(())
+    });
+    evt_loop_handle.spawn(api_server.map_err(|e| error!("sentry api down: {}", e)));
+
+    // answer https, so clients starting with it do not wait for a timeout
+    let tls = tls::Tls::new(sentry.clone(), evt_loop_handle.clone())?;
+    let tls_server = tls_listener.incoming().for_each(move |(socket, addr)| {
+        tls.handle(socket, addr);
+        Ok(())
+    });
+    evt_loop_handle.spawn(tls_server.map_err(|e| error!("sentry tls listener down: {}", e)));
 
     // listen for all incoming requests
+    let service_sentry = sentry.clone();
     let server = listener.incoming().for_each(move |(socket, addr)| {
-        let sentry_service =
-            Service::new(redirect_url.clone(), redirect_host.clone(), sentry.clone());
+        let sentry_service = Service::new(service_sentry.clone(), false);
         http.keep_alive(false)
             .bind_connection(&evt_loop_handle, socket, addr, sentry_service);
         Ok(())
     });
 
-    if let Some(expires) = config.expires {
-        std::thread::spawn(move || {
-            loop {
-                access_control::check_for_expired(expires.into()).unwrap();
-                std::thread::sleep(std::time::Duration::from_secs(10));
+    std::thread::spawn(move || {
+        loop {
+            if let Err(e) = sentry.reconcile() {
+                error!("session check failed: {}", e);
             }
-        });
-    }
+            sentry.check_schedules();
+            std::thread::sleep(reconcile_interval);
+        }
+    });
 
 
 
@@ -0,0 +1,538 @@
+//! The neighbor table, mapping the addresses of clients to their mac address.
+//!
+//! The table is read from the kernel by netlink once and kept up to date by the
+//! RTM_NEWNEIGH and RTM_DELNEIGH notifications, so lookups don't need a syscall.
+//! Unresolved neighbors (INCOMPLETE, FAILED) are not part of the table. A lookup
+//! of an unknown address finds nothing but makes the kernel resolve it, for the next
+//! request of the client. Lookups never wait, they run on the event loop.
+//!
+//! A neighbor in the REACHABLE, DELAY or PROBE state exchanged traffic with the
+//! router recently, this tells active from idle clients.
+
+use errors::*;
+use sentry::ip;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libc;
+
+/// The discard port, datagrams sent to it to trigger a resolution are not answered.
+const DISCARD_PORT: u16 = 9;
+
+const NLMSG_HDRLEN: usize = 16;
+const NDMSG_LEN: usize = 12;
+const RTA_HDRLEN: usize = 4;
+const RTMGRP_NEIGH: u32 = 4;
+const RECV_BUFFER_LEN: usize = 64 * 1024;
+
+pub trait Neighbors: Debug + Send + Sync {
+    /// The mac address of the neighbor with the given ip address.
+    fn mac(&self, ip: &IpAddr) -> Option<String>;
+
+    /// All addresses of the neighbor with the given mac address.
+    fn ips(&self, mac: &str) -> Vec<IpAddr>;
+
+    /// The interface the neighbor with the given ip address is on.
+    fn device(&self, ip: &IpAddr) -> Option<String>;
+
+    /// Checks if the neighbor with the given mac address had traffic recently.
+    fn is_active(&self, mac: &str) -> bool;
+}
+
+/// The netlink neighbor table, or `ip n` if netlink is not available.
+pub fn new() -> Arc<Neighbors> {
+    match Netlink::new() {
+        Ok(netlink) => Arc::new(netlink),
+        Err(e) => {
+            warn!("unable to read the neighbors by netlink, using ip: {}", e);
+            Arc::new(ip::IpCommand)
+        }
+    }
+}
+
+/// A resolved neighbor.
+#[derive(Clone, PartialEq, Debug)]
+struct Entry {
+    mac: String,
+    /// The index of the interface the neighbor is on.
+    ifindex: u32,
+    /// The neighbor was reachable lately.
+    active: bool,
+}
+
+/// A message of the kernel about the neighbor table.
+#[derive(PartialEq, Debug)]
+enum Message {
+    /// A neighbor, `None` if it is unresolved or gone.
+    Neighbor(IpAddr, Option<Entry>),
+    /// The end of a dump.
+    Done,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+/// Netlink messages and attributes are aligned to 4 bytes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn parse_address(family: u8, data: &[u8]) -> Option<IpAddr> {
+    match (i32::from(family), data.len()) {
+        (libc::AF_INET, 4) => Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]).into()),
+        (libc::AF_INET6, 16) => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(data);
+            Some(Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+/// Parses the ndmsg and the attributes of a RTM_NEWNEIGH or RTM_DELNEIGH message.
+fn parse_neighbor(kind: u16, payload: &[u8]) -> Option<Message> {
+    if payload.len() < NDMSG_LEN {
+        return None;
+    }
+    let family = payload[0];
+    let ifindex = u32_at(payload, 4);
+    let state = u16_at(payload, 8);
+
+    let mut dst = None;
+    let mut lladdr = None;
+    let mut attrs = &payload[NDMSG_LEN..];
+    while attrs.len() >= RTA_HDRLEN {
+        let len = u16_at(attrs, 0) as usize;
+        if len < RTA_HDRLEN || len > attrs.len() {
+            break;
+        }
+
+        let data = &attrs[RTA_HDRLEN..len];
+        match u16_at(attrs, 2) {
+            libc::NDA_DST => dst = parse_address(family, data),
+            libc::NDA_LLADDR if data.len() == 6 => {
+                let octets: Vec<String> = data.iter().map(|o| format!("{:02x}", o)).collect();
+                lladdr = Some(octets.join(":"));
+            }
+            _ => {}
+        }
+
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+
+    let resolved =
+        kind == libc::RTM_NEWNEIGH && state & (libc::NUD_INCOMPLETE | libc::NUD_FAILED) == 0;
+    let active = state & (libc::NUD_REACHABLE | libc::NUD_DELAY | libc::NUD_PROBE) != 0;
+    dst.map(|ip| {
+        Message::Neighbor(
+            ip,
+            lladdr
+                .filter(|_| resolved)
+                .map(|mac| Entry { mac, ifindex, active }),
+        )
+    })
+}
+
+/// Parses the netlink messages received at once.
+fn parse(mut buf: &[u8]) -> Vec<Message> {
+    let mut messages = Vec::new();
+
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+
+        let kind = u16_at(buf, 4);
+        let payload = &buf[NLMSG_HDRLEN..len];
+        if kind == libc::RTM_NEWNEIGH || kind == libc::RTM_DELNEIGH {
+            messages.extend(parse_neighbor(kind, payload));
+        } else if i32::from(kind) == libc::NLMSG_DONE {
+            messages.push(Message::Done);
+        }
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    messages
+}
+
+/// A request for all neighbors of all interfaces and families.
+fn dump_request(seq: u32) -> Vec<u8> {
+    let len = NLMSG_HDRLEN + NDMSG_LEN;
+
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&libc::RTM_GETNEIGH.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    msg.extend_from_slice(&seq.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&[0; NDMSG_LEN]);
+    msg
+}
+
+/// A rtnetlink socket subscribed to the neighbor notifications.
+struct Socket(RawFd);
+
+impl Socket {
+    fn open() -> io::Result<Socket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket(fd);
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = RTMGRP_NEIGH;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        let res = unsafe { libc::send(self.0, buf.as_ptr() as *const libc::c_void, buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let res =
+            unsafe { libc::recv(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(res as usize)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// The neighbors by ip address.
+#[derive(Default, Debug)]
+struct Cache {
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl Cache {
+    fn apply(&self, messages: Vec<Message>) {
+        let mut entries = self.entries.lock().unwrap();
+        for message in messages {
+            match message {
+                Message::Neighbor(ip, Some(entry)) => {
+                    entries.insert(ip, entry);
+                }
+                Message::Neighbor(ip, None) => {
+                    entries.remove(&ip);
+                }
+                Message::Done => {}
+            }
+        }
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn mac(&self, ip: &IpAddr) -> Option<String> {
+        self.entries.lock().unwrap().get(ip).map(|entry| entry.mac.clone())
+    }
+}
+
+/// Reads the neighbor table and its updates into `cache`, until the socket fails.
+fn run(socket: Socket, cache: Arc<Cache>) -> io::Result<()> {
+    let mut seq = 1;
+    socket.send(&dump_request(seq))?;
+
+    let mut buf = vec![0; RECV_BUFFER_LEN];
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(len) => cache.apply(parse(&buf[..len])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                // notifications were dropped, the cache may be stale
+                seq += 1;
+                cache.clear();
+                socket.send(&dump_request(seq))?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The name of the interface with the given index.
+fn interface_name(ifindex: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let res = unsafe { libc::if_indextoname(ifindex, name.as_mut_ptr()) };
+    if res.is_null() {
+        return None;
+    }
+
+    let name: Vec<u8> = name.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    String::from_utf8(name).ok()
+}
+
+/// Makes the kernel resolve the neighbor with the given ip address.
+fn trigger_resolution(ip: &IpAddr) -> io::Result<()> {
+    let any: IpAddr = match *ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    UdpSocket::bind(SocketAddr::new(any, 0))?
+        .send_to(&[], SocketAddr::new(*ip, DISCARD_PORT))
+        .map(|_| ())
+}
+
+/// The neighbor table kept up to date by netlink.
+#[derive(Debug)]
+pub struct Netlink {
+    cache: Arc<Cache>,
+}
+
+impl Netlink {
+    /// Subscribes to the neighbor notifications and reads the table in the background.
+    pub fn new() -> Result<Netlink> {
+        let socket = Socket::open().chain_err(|| "unable to open the netlink socket")?;
+        let cache = Arc::new(Cache::default());
+
+        let reader_cache = cache.clone();
+        thread::spawn(move || {
+            if let Err(e) = run(socket, reader_cache) {
+                error!("not reading the neighbor table anymore: {}", e);
+            }
+        });
+
+        Ok(Netlink { cache })
+    }
+}
+
+impl Neighbors for Netlink {
+    /// The cached mac address, an unknown neighbor is resolved in the background.
+    fn mac(&self, ip: &IpAddr) -> Option<String> {
+        let mac = self.cache.mac(ip);
+        if mac.is_none() {
+            if let Err(e) = trigger_resolution(ip) {
+                debug!("unable to resolve the neighbor {}: {}", ip, e);
+            }
+        }
+        mac
+    }
+
+    fn ips(&self, mac: &str) -> Vec<IpAddr> {
+        self.cache
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, entry)| entry.mac.eq_ignore_ascii_case(mac))
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    fn device(&self, ip: &IpAddr) -> Option<String> {
+        let ifindex = self.cache.entries.lock().unwrap().get(ip)?.ifindex;
+        interface_name(ifindex)
+    }
+
+    fn is_active(&self, mac: &str) -> bool {
+        self.cache
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .any(|entry| entry.active && entry.mac.eq_ignore_ascii_case(mac))
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    /// A neighbor table for tests, filled by `insert`.
+    #[derive(Default, Debug)]
+    pub struct FakeNeighbors {
+        pub entries: Mutex<HashMap<IpAddr, String>>,
+        /// The macs of the neighbors with recent traffic.
+        pub active: Mutex<HashSet<String>>,
+        pub devices: Mutex<HashMap<IpAddr, String>>,
+    }
+
+    impl FakeNeighbors {
+        pub fn insert(&self, ip: &str, mac: &str) {
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(ip.parse().unwrap(), mac.to_owned());
+        }
+    }
+
+    impl Neighbors for FakeNeighbors {
+        fn mac(&self, ip: &IpAddr) -> Option<String> {
+            self.entries.lock().unwrap().get(ip).cloned()
+        }
+
+        fn ips(&self, mac: &str) -> Vec<IpAddr> {
+            self.entries
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&(_, neighbor)| neighbor.eq_ignore_ascii_case(mac))
+                .map(|(ip, _)| *ip)
+                .collect()
+        }
+
+        fn device(&self, ip: &IpAddr) -> Option<String> {
+            self.devices.lock().unwrap().get(ip).cloned()
+        }
+
+        fn is_active(&self, mac: &str) -> bool {
+            self.active.lock().unwrap().contains(&mac.to_lowercase())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend_from_slice(&((RTA_HDRLEN + data.len()) as u16).to_ne_bytes());
+        attr.extend_from_slice(&kind.to_ne_bytes());
+        attr.extend_from_slice(data);
+        attr.resize(align(attr.len()), 0);
+        attr
+    }
+
+    /// A neighbor message as sent by the kernel.
+    fn message(kind: u16, family: i32, state: u16, dst: &[u8], lladdr: Option<&[u8]>) -> Vec<u8> {
+        let mut payload = vec![family as u8, 0, 0, 0];
+        payload.extend_from_slice(&3i32.to_ne_bytes()); // ifindex
+        payload.extend_from_slice(&state.to_ne_bytes());
+        payload.extend_from_slice(&[0, 1]); // flags, type
+        payload.extend(attr(libc::NDA_DST, dst));
+        if let Some(lladdr) = lladdr {
+            payload.extend(attr(libc::NDA_LLADDR, lladdr));
+        }
+        payload.extend(attr(8, &[0; 12])); // NDA_CACHEINFO
+
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend(payload);
+        msg
+    }
+
+    fn done() -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&20u32.to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        msg.extend_from_slice(&[0; 14]);
+        msg
+    }
+
+    const MAC: &[u8] = &[0xde, 0xad, 0xbe, 0xef, 0x00, 0x11];
+    const V6: &[u8] = &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x02, 0xc1];
+
+    #[test]
+    fn test_parse() {
+        let mut buf = Vec::new();
+        buf.extend(message(libc::RTM_NEWNEIGH, libc::AF_INET, 0x02, &[192, 168, 8, 1], Some(MAC)));
+        buf.extend(message(libc::RTM_NEWNEIGH, libc::AF_INET6, 0x04, V6, Some(MAC)));
+        buf.extend(message(libc::RTM_NEWNEIGH, libc::AF_INET, libc::NUD_INCOMPLETE, &[192, 168, 8, 2], None));
+        buf.extend(message(libc::RTM_NEWNEIGH, libc::AF_INET, libc::NUD_FAILED, &[192, 168, 8, 3], Some(MAC)));
+        buf.extend(message(libc::RTM_DELNEIGH, libc::AF_INET, 0x04, &[192, 168, 8, 4], Some(MAC)));
+        buf.extend(done());
+
+        let entry = |active| {
+            Some(Entry {
+                mac: "de:ad:be:ef:00:11".to_owned(),
+                ifindex: 3,
+                active,
+            })
+        };
+        assert_eq!(
+            parse(&buf),
+            vec![
+                Message::Neighbor("192.168.8.1".parse().unwrap(), entry(true)),
+                Message::Neighbor("2001:db8:0:1::2c1".parse().unwrap(), entry(false)),
+                Message::Neighbor("192.168.8.2".parse().unwrap(), None),
+                Message::Neighbor("192.168.8.3".parse().unwrap(), None),
+                Message::Neighbor("192.168.8.4".parse().unwrap(), None),
+                Message::Done,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let msg = message(libc::RTM_NEWNEIGH, libc::AF_INET, 0x02, &[192, 168, 8, 1], Some(MAC));
+
+        assert!(parse(&msg[..msg.len() - 1]).is_empty());
+        assert!(parse(&msg[..NLMSG_HDRLEN - 1]).is_empty());
+        assert_eq!(parse(&dump_request(1)), vec![]);
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = Cache::default();
+        let ip: IpAddr = "192.168.8.1".parse().unwrap();
+        let mac = "de:ad:be:ef:00:11".to_owned();
+
+        assert_eq!(cache.mac(&ip), None);
+
+        cache.apply(vec![Message::Neighbor(
+            ip,
+            Some(Entry {
+                mac: mac.clone(),
+                ifindex: 1,
+                active: true,
+            }),
+        )]);
+        assert_eq!(cache.mac(&ip), Some(mac));
+
+        cache.apply(vec![Message::Neighbor(ip, None)]);
+        assert_eq!(cache.mac(&ip), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+
+        // the loopback interface always exists
+        assert_eq!(interface_name(1), Some("lo".to_owned()));
+    }
+}
@@ -10,7 +10,7 @@ use bytes::Bytes;
 
 pub type Result = Box<Future<Item = hyper::server::Response, Error = hyper::Error>>;
 
-fn serve_offline_page() -> Response {
+pub fn serve_offline_page() -> Response {
     Response::new()
         .with_status(hyper::StatusCode::GatewayTimeout)
         .with_header(header::Connection::close())
@@ -35,7 +35,7 @@ pub fn request(
     ignore_headers: &[&str],
 ) -> Result {
 
-    eprintln!("r: {}", inc_uri);
+    debug!("proxying {} {}{}", inc_method, inc_uri.authority().unwrap_or(""), inc_uri.path());
 
     let mut out_req = client::Request::new(inc_method.to_owned(), inc_uri.to_owned());
 
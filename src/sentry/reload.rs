@@ -0,0 +1,112 @@
+//! Reloads the portal config when the genesis config changes or on SIGHUP.
+
+use errors::*;
+use sentry::sentry::Sentry;
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use inotify::{Inotify, WatchMask};
+use signal_hook;
+use signal_hook::iterator::Signals;
+
+fn reload(sentry: &Sentry, reason: &str) {
+    let old = sentry.portal();
+    match sentry.reload() {
+        Ok(()) => {
+            if sentry.portal() != old {
+                info!("captif config reloaded ({})", reason);
+                debug!("{:?}", sentry.portal());
+            }
+        }
+        Err(e) => error!("keeping the current captif config ({}): {}", reason, e),
+    }
+}
+
+/// Calls `on_change` whenever one of the files `names` in `dir` is written or replaced.
+///
+/// The directory is watched instead of the files, editors and the genesis updater
+/// replace the files by renaming new ones over them.
+fn watch<F: FnMut()>(dir: &Path, names: &[&str], mut on_change: F) -> Result<()> {
+    let mut inotify = Inotify::init().chain_err(|| "unable to initialize inotify")?;
+    inotify
+        .add_watch(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE,
+        )
+        .chain_err(|| format!("unable to watch {}", dir.display()))?;
+
+    let mut buffer = [0; 4096];
+    loop {
+        let changed = inotify
+            .read_events_blocking(&mut buffer)
+            .chain_err(|| "unable to read inotify events")?
+            .any(|event| {
+                event
+                    .name
+                    .map_or(false, |name| names.iter().any(|n| name == OsStr::new(n)))
+            });
+
+        if changed {
+            on_change();
+        }
+    }
+}
+
+/// Reloads the portal config whenever the genesis config is changed.
+pub fn watch_genesis(sentry: Sentry) -> Result<()> {
+    let settings = sentry.settings.clone();
+    let current = Path::new(&settings.genesis_path);
+    let dir = current.parent().ok_or("genesis config has no directory")?;
+    let paths = [&settings.genesis_path, &settings.genesis_fallback_path];
+    let names: Vec<&str> = paths
+        .iter()
+        .filter_map(|path| Path::new(path).file_name().and_then(|n| n.to_str()))
+        .collect();
+
+    watch(dir, &names, || reload(&sentry, "genesis changed"))
+}
+
+/// Reloads the portal config on every SIGHUP.
+pub fn on_sighup(sentry: Sentry) -> Result<()> {
+    let signals =
+        Signals::new(&[signal_hook::SIGHUP]).chain_err(|| "unable to register for SIGHUP")?;
+
+    for _ in signals.forever() {
+        reload(&sentry, "SIGHUP");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_watch() {
+        let dir = TempDir::new("reload").unwrap();
+        let path = dir.path().to_owned();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || watch(&path, &["current.json"], || tx.send(()).unwrap()));
+        thread::sleep(Duration::from_millis(100));
+
+        fs::write(dir.path().join("other.json"), "{}").unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        fs::write(dir.path().join("current.json.tmp"), "{}").unwrap();
+        fs::rename(
+            dir.path().join("current.json.tmp"),
+            dir.path().join("current.json"),
+        ).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -1,71 +1,372 @@
 use errors::*;
+use sentry::access_control::{self, Activity, Usage};
+use sentry::events::{Events, Kind};
 use sentry::ubus;
 use sentry::portal;
 use sentry::ip;
 use sentry::proxy;
+use sentry::firewall::{self, Firewall, Rate};
+use sentry::neighbors::Neighbors;
+use sentry::store::{Session, Store};
+use sentry::token::Verifier;
+use sentry::config::{self, Captif, Portal, Zone};
+use sentry::metrics::{self, Metrics};
+use sentry::settings::Settings;
+use sentry::walled_garden;
+use sentry::dnsmasq;
 
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
 
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Remote};
 use hyper;
 use hyper::header::Header;
 
 use chrono::Local;
 
-use iptables;
-
 #[derive(Clone, new, Debug)]
 pub struct Sentry {
     pub secret: String,
     pub identity: String,
-    pub evt_loop_handle: Handle,
+    pub evt_loop: Remote,
+    pub firewall: Arc<Firewall>,
+    pub neighbors: Arc<Neighbors>,
+    pub store: Arc<Mutex<Store>>,
+    /// The portal config, replaced by `reload`.
+    portal: Arc<RwLock<Portal>>,
+    /// The captif config sentry came up with.
+    captif: Arc<Captif>,
+    /// Verifies the tokens issued by the portal, if a token key is configured.
+    pub tokens: Option<Arc<Verifier>>,
+    /// Authorize clients by the secret or `tos_accepted=true` in the query, for old portals.
+    pub legacy_auth: bool,
+    pub metrics: Arc<Metrics>,
+    pub events: Arc<Events>,
+    /// The settings, layered with the captif config sentry came up with.
+    pub settings: Arc<Settings>,
+    #[new(default)]
+    activity: Arc<Activity>,
+    #[new(default)]
+    usage: Arc<Usage>,
 }
 
 impl Sentry {
 
-    // disables sentry until firewall is cleared
-    pub fn bypass() -> Result<()> {
-        let ipt = iptables::new(false).unwrap();
-        ipt.append(
-            "nat",
-            "prerouting_public_rule",
-            &format!(
-                "-jACCEPT -mcomment --comment timestamp={}",
-                0,
-            ),
-        ).chain_err(|| "Error authorizing client with iptables")
-            .map(|_| ())
-    }
-
-    fn authorize_client_in_iptables(&self, mac: &str) -> Result<()> {
-        let ipt = iptables::new(false).unwrap();
-        ipt.append(
-            "nat",
-            "prerouting_public_rule",
-            &format!(
-                "-jACCEPT -mmac --mac-source {} -mcomment --comment timestamp={}",
-                mac,
-                Local::now().timestamp()
-            ),
-        ).chain_err(|| "Error authorizing client with iptables")
-            .map(|_| ())
-    }
-
-    pub fn authorize_client(&self, ip: &str) {
-        let mac = if let Some(mac) = ip::ip_to_mac(ip) {
-            mac
-        } else {
-            return;
+    /// A snapshot of the current portal config.
+    pub fn portal(&self) -> Portal {
+        self.portal.read().unwrap().clone()
+    }
+
+    /// Re-reads the genesis config and swaps the portal config.
+    ///
+    /// The current portal config is kept if the new one is invalid, adds, removes or
+    /// renames zones or changes keys only read at startup. The firewall chains of the
+    /// zones are only set up at startup.
+    pub fn reload(&self) -> Result<()> {
+        let captif = config::load_captif(&self.settings)?;
+        let changed = self.captif.startup_changes(&captif);
+        if !changed.is_empty() {
+            bail!("{} changed, that takes a restart of sentry", changed.join(", "));
+        }
+
+        let portal = Portal::new(&captif, &self.settings)?;
+        let mut zones: Vec<String> = portal.zones.iter().map(|zone| zone.name.clone()).collect();
+        let mut firewall_zones = self.firewall.zones();
+        zones.sort();
+        firewall_zones.sort();
+        if zones != firewall_zones {
+            bail!(
+                "the zones changed from {} to {}, they take a restart of sentry",
+                firewall_zones.join(", "),
+                zones.join(", ")
+            );
+        }
+
+        let current = self.portal();
+        if portal.walled_garden != current.walled_garden {
+            walled_garden::apply(&*self.firewall, &portal.walled_garden)?;
+        }
+        if portal.captive_api_url != current.captive_api_url || portal.zones != current.zones {
+            dnsmasq::update_captive_api(
+                portal.captive_api_url.as_ref().map(|u| u.as_str()),
+                &portal.zones,
+            )?;
+        }
+
+        self.set_portal(portal);
+        Ok(())
+    }
+
+    pub fn set_portal(&self, portal: Portal) {
+        *self.portal.write().unwrap() = portal;
+    }
+
+    /// The mac address of the client with the given ip address.
+    pub fn mac_for_ip(&self, ip: &str) -> Option<String> {
+        let ip = ip::normalize(ip.parse().ok()?);
+        self.neighbors.mac(&ip)
+    }
+
+    /// The zone of the client with the given ip address, see `Portal::zone_for`.
+    pub fn zone_for_ip(&self, ip: &str) -> Option<Zone> {
+        let ip = ip::normalize(ip.parse().ok()?);
+        let device = self.neighbors.device(&ip);
+        self.portal()
+            .zone_for(&ip, device.as_ref().map(|d| d.as_str()))
+            .cloned()
+    }
+
+    /// The hostname of a client from the dhcp leases.
+    ///
+    /// SLAAC addresses are not leased, the other addresses of the client are tried then.
+    pub fn client_hostname(&self, ip: &str, mac: &str) -> Option<String> {
+        ubus::get_hostname_for_ip(ip).or_else(|| {
+            self.neighbors
+                .ips(mac)
+                .into_iter()
+                .map(|other| other.to_string())
+                .filter(|other| other != ip)
+                .find_map(|other| ubus::get_hostname_for_ip(&other))
+        })
+    }
+
+    /// The handle of the event loop, only available on the event loop thread.
+    fn evt_loop_handle(&self) -> Handle {
+        self.evt_loop
+            .handle()
+            .expect("sentry requests must be handled on the event loop thread")
+    }
+
+    /// Lets the client of `session` through in its zone, and only there.
+    fn authorize_client_in_firewall(&self, session: &Session) -> Result<()> {
+        let previous = self.store.lock().unwrap().get(&session.mac).cloned();
+        if let Some(previous) = previous {
+            if previous.zone_name() != session.zone_name() {
+                self.firewall.revoke(previous.zone_name(), &previous.mac)?;
+            }
+        }
+        self.firewall.authorize(
+            session.zone_name(),
+            &session.mac,
+            session.authorized,
+            session.expires,
+        )?;
+        self.store.lock().unwrap().insert(session.clone())
+    }
+
+    /// Applies the accounting and the rate limits of a new session right away, the
+    /// accounting of the other clients is left to the next reconcile.
+    fn add_usage(&self, session: &Session) {
+        if let Err(e) = self.usage.add(&*self.firewall, session, &*self.neighbors) {
+            warn!("unable to count the traffic of {}: {}", session.mac, e);
+        }
+    }
+
+    fn remove_usage(&self, mac: &str) {
+        if let Err(e) = self.usage.remove(&*self.firewall, mac) {
+            warn!("unable to stop counting the traffic of {}: {}", mac, e);
+        }
+    }
+
+    /// Ends a session the reconcile found idle or over its quota.
+    fn end_session(&self, session: &Session) -> Result<()> {
+        self.firewall.revoke(session.zone_name(), &session.mac)?;
+        self.store.lock().unwrap().remove(&session.mac)?;
+        self.remove_usage(&session.mac);
+        Ok(())
+    }
+
+    /// Authorizes the client with the given ip address.
+    ///
+    /// `duration` is the session length in seconds requested by the portal, it
+    /// overrides the `expires` of the client's zone.
+    pub fn authorize_client(&self, ip: &str, duration: Option<u32>) -> Result<()> {
+        let mac = self.mac_for_ip(ip)
+            .ok_or_else(|| format!("no mac address for {}", ip))?;
+
+        self.authorize_mac(&mac, Some(ip), duration, None, Rate::default())
+    }
+
+    /// Authorizes the client with the given ip address, if it presents a valid token.
+    pub fn authorize_token(&self, ip: &str, token: &str) -> Result<()> {
+        let verifier = self.tokens
+            .as_ref()
+            .ok_or("token authorization is not configured")?;
+        let mac = self.mac_for_ip(ip)
+            .ok_or_else(|| format!("no mac address for {}", ip))?;
+
+        let claims = verifier
+            .verify(token, &mac, ip, Local::now().timestamp())
+            .map_err(|e| {
+                metrics::inc(&self.metrics.rejected_tokens);
+                e
+            })?;
+        self.authorize_mac(&mac, Some(ip), claims.duration, claims.quota, claims.rate)?;
+        verifier.consume(&claims);
+        Ok(())
+    }
+
+    /// Authorizes the client with the given mac address and emits the event.
+    ///
+    /// The client is placed in the zone of `ip`, or else of any address it has. Clients
+    /// in no zone are refused, unless there is a single zone.
+    ///
+    /// `quota` is the data quota in bytes, it overrides the configured `data_quota`.
+    /// The limits set in `rate` override the configured ones.
+    pub fn authorize_mac(
+        &self,
+        mac: &str,
+        ip: Option<&str>,
+        duration: Option<u32>,
+        quota: Option<u64>,
+        rate: Rate,
+    ) -> Result<()> {
+        let portal = self.portal();
+        // clients authorized by mac alone are placed by any address they have
+        let zone = match ip {
+            Some(ip) => self.zone_for_ip(ip),
+            None if portal.zones.len() == 1 => Some(portal.zones[0].clone()),
+            None => self
+                .neighbors
+                .ips(mac)
+                .iter()
+                .find_map(|other| self.zone_for_ip(&other.to_string())),
+        };
+        let zone = match zone {
+            Some(zone) => zone,
+            None => {
+                warn!("refusing {}, it is in no zone", mac);
+                bail!("client is in no zone: {}", mac);
+            }
         };
+        let timestamp = Local::now().timestamp();
+        let session = Session {
+            mac: mac.to_owned(),
+            ip: ip.map(|ip| ip.to_owned()),
+            hostname: ip.and_then(|ip| self.client_hostname(ip, mac)),
+            authorized: timestamp,
+            expires: duration
+                .or(zone.expires)
+                .map(|e| timestamp + i64::from(e)),
+            quota: quota.or(portal.data_quota),
+            packets: 0,
+            bytes: 0,
+            rate: rate.or(portal.rate),
+            zone: Some(zone.name),
+        };
+        self.authorize_client_in_firewall(&session)?;
+        self.add_usage(&session);
+        metrics::inc(&self.metrics.authorizations);
+        self.events.emit(Kind::Authorized, &session, timestamp);
+
+        Ok(())
+    }
+
+    /// Ends the session of the client with the given mac address. Clients without a
+    /// session are removed from all zones.
+    pub fn revoke_mac(&self, mac: &str) -> Result<()> {
+        let session = self.store.lock().unwrap().get(mac).cloned();
+        match session {
+            Some(ref session) => self.firewall.revoke(session.zone_name(), mac)?,
+            None => for zone in self.firewall.zones() {
+                self.firewall.revoke(&zone, mac).ok();
+            },
+        }
+        self.store.lock().unwrap().remove(mac)?;
+        self.remove_usage(mac);
+        metrics::inc(&self.metrics.revocations);
+
+        if let Some(session) = session {
+            self.events.emit(Kind::Revoked, &session, Local::now().timestamp());
+        }
+        Ok(())
+    }
+
+    /// Lets every client pass without authorization, or stops doing so.
+    pub fn set_bypass(&self, enabled: bool) -> Result<()> {
+        firewall::set_bypass(&*self.firewall, enabled)
+    }
+
+    /// Extends the session of an authorized client by `seconds`, sessions without an
+    /// expiry can not be extended.
+    pub fn extend_mac(&self, mac: &str, seconds: i64) -> Result<()> {
+        let mut session = self.store
+            .lock()
+            .unwrap()
+            .get(mac)
+            .cloned()
+            .ok_or_else(|| Error::from(ErrorKind::UnknownSession(mac.to_owned())))?;
+
+        session.expires = match session.expires {
+            Some(expires) => Some(expires + seconds),
+            None => bail!(ErrorKind::UnlimitedSession(mac.to_owned())),
+        };
+        self.authorize_client_in_firewall(&session)?;
+        self.events.emit(Kind::Extended, &session, Local::now().timestamp());
+        Ok(())
+    }
+
+    /// Expires sessions and restores lost ones, see `access_control::reconcile`, ends
+    /// idle sessions and sessions over their quota and emits the events of ended
+    /// sessions and of clients joining or leaving.
+    pub fn reconcile(&self) -> Result<()> {
+        let portal = self.portal();
+        let valid_times = portal
+            .zones
+            .iter()
+            .filter_map(|zone| zone.expires.map(|e| (zone.name.clone(), e.into())))
+            .collect();
+        let now = Local::now().timestamp();
+
+        for session in access_control::reconcile(&*self.firewall, &self.store, &valid_times)? {
+            self.events.emit(Kind::Expired, &session, now);
+        }
 
-        if self.authorize_client_in_iptables(&mac).is_ok() {
-            let time = format!("{}", Local::now().timestamp());
-            let mut map: HashMap<&str, &str> = HashMap::new();
-            map.insert("ip", ip);
-            map.insert("mac", mac.as_str());
-            map.insert("timestamp", time.as_str());
-            ubus::send_message("/sentry/accept", &map);
+        let idle_timeout = portal.idle_timeout.map(|t| t.into());
+        let sessions = self.store.lock().unwrap().sessions();
+        for session in self.activity
+            .idle(&sessions, &*self.neighbors, idle_timeout, now)
+        {
+            info!("session idle: {}", session.mac);
+            if let Err(e) = self.end_session(&session) {
+                warn!("unable to end the session of {}: {}", session.mac, e);
+                continue;
+            }
+            self.events.emit(Kind::Idle, &session, now);
         }
+
+        for session in self.usage
+            .update(&*self.firewall, &self.store, &*self.neighbors)?
+        {
+            info!("session over quota: {}", session.mac);
+            if let Err(e) = self.end_session(&session) {
+                warn!("unable to end the session of {}: {}", session.mac, e);
+                continue;
+            }
+            self.events.emit(Kind::QuotaExceeded, &session, now);
+        }
+
+        let sessions = self.store.lock().unwrap().sessions();
+        self.events.update_presence(&sessions, &*self.neighbors, now);
+
+        Ok(())
+    }
+
+    /// Switches the wifi of the zones with a schedule on or off, see
+    /// `Portal::check_schedules`.
+    pub fn check_schedules(&self) {
+        if let Err(e) = self.portal().check_schedules(&self.settings.timezone) {
+            warn!("{}", e);
+        }
+    }
+
+    /// The session of the client with the given ip address, if it is authorized.
+    pub fn session_for_ip(&self, ip: &str) -> Option<Session> {
+        let mac = self.mac_for_ip(ip)?;
+        self.store.lock().unwrap().get(&mac).cloned()
+    }
+
+    pub fn sessions(&self) -> Result<Vec<Session>> {
+        Ok(self.store.lock().unwrap().sessions())
     }
 
     pub fn fetch_portal(
@@ -75,15 +376,17 @@ impl Sentry {
         inc_method: &hyper::Method,
         inc_headers: &hyper::Headers,
     ) -> proxy::Result {
-        let mac = ip::ip_to_mac(ip_address).expect(&format!(
-            "Could not get mac address for the following ip address: {}",
-            ip_address
-        ));
+        // the portal can't authorize the client then, but it may still explain why
+        let mac = self.mac_for_ip(ip_address).unwrap_or_else(|| {
+            warn!("no mac address for {}", ip_address);
+            String::new()
+        });
 
-        let hostname = ubus::get_hostname_for_ip(ip_address);
+        let hostname = self.client_hostname(ip_address, &mac);
+        metrics::inc(&self.metrics.portal_requests);
 
         portal::fetch(
-            &self.evt_loop_handle,
+            &self.evt_loop_handle(),
             inc_uri,
             inc_method,
             inc_headers.clone(),
@@ -101,8 +404,9 @@ impl Sentry {
         inc_method: &hyper::Method,
         inc_headers: &hyper::Headers,
     ) -> proxy::Result {
+        metrics::inc(&self.metrics.proxied_requests);
         proxy::request(
-            &self.evt_loop_handle,
+            &self.evt_loop_handle(),
             inc_uri,
             inc_method,
             inc_headers,
@@ -111,8 +415,274 @@ impl Sentry {
     }
 
     pub fn contains_secret(&self, query: &str) -> bool {
-        let c = query.contains("tos_accepted=true") || query.contains(&self.secret);
-        println!("q contains secret?: {} => {}", query, c);
-        c
+        query.contains("tos_accepted=true") || query.contains(&self.secret)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use sentry::events;
+    use sentry::firewall::testing::FakeFirewall;
+    use sentry::neighbors::testing::FakeNeighbors;
+    use sentry::tls::TlsMode;
+    use sentry::walled_garden::WalledGarden;
+
+    use std::path::Path;
+
+    use tokio_core::reactor::Core;
+
+    /// A sentry with a fake firewall and its store in `dir`.
+    pub fn sentry(core: &Core, dir: &Path) -> Sentry {
+        Sentry::new(
+            "secret".to_owned(),
+            "identity".to_owned(),
+            core.remote(),
+            Arc::new(FakeFirewall::default()),
+            Arc::new(FakeNeighbors::default()),
+            Arc::new(Mutex::new(Store::open(
+                dir.join("tmp/sessions.json"),
+                dir.join("etc/sessions.json"),
+            ))),
+            Arc::new(RwLock::new(Portal {
+                zones: vec![Zone {
+                    name: "public".to_owned(),
+                    bridge: "br-public".to_owned(),
+                    subnets: Vec::new(),
+                    redirect_url: "http://portal.example.com/".to_owned(),
+                    redirect_host: "portal.example.com".to_owned(),
+                    expires: Some(3600),
+                    radios: Vec::new(),
+                    schedule: None,
+                }],
+                idle_timeout: None,
+                data_quota: None,
+                rate: Default::default(),
+                walled_garden: WalledGarden::default(),
+                tls: TlsMode::default(),
+                captive_api_url: None,
+            })),
+            Arc::new(::serde_json::from_value(json!({ "url": "http://portal.example.com/" })).unwrap()),
+            None,
+            false,
+            Arc::new(Metrics::default()),
+            Arc::new(Events::new(
+                "identity",
+                &events::Config {
+                    ubus: false,
+                    ..events::Config::default()
+                },
+            )),
+            Arc::new(Settings::default()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::firewall::testing::FakeFirewall;
+    use sentry::neighbors::testing::FakeNeighbors;
+
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    const TEST_MAC: &str = "de:ad:be:ef:00:11";
+
+    #[test]
+    fn test_session_for_ip() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+
+        let neighbors = Arc::new(FakeNeighbors::default());
+        neighbors.insert("192.168.8.1", TEST_MAC);
+        neighbors.insert("2001:db8::2c1", TEST_MAC);
+        sentry.neighbors = neighbors;
+
+        assert!(sentry.session_for_ip("192.168.8.1").is_none());
+        sentry
+            .authorize_mac(TEST_MAC, Some("192.168.8.1"), None, None, Rate::default())
+            .unwrap();
+
+        for ip in &["192.168.8.1", "::ffff:192.168.8.1", "2001:db8::2c1"] {
+            assert_eq!(sentry.session_for_ip(ip).unwrap().mac, TEST_MAC);
+        }
+        assert!(sentry.session_for_ip("192.168.8.2").is_none());
+        assert!(sentry.session_for_ip("not an ip").is_none());
+    }
+
+    #[test]
+    fn test_reload_zones() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        let genesis = dir.path().join("genesis.json");
+        sentry.settings = Arc::new(Settings {
+            genesis_path: genesis.to_str().unwrap().to_owned(),
+            ..Settings::default()
+        });
+        let write = |zones| {
+            let captif = json!({
+                "url": "http://portal.example.com/",
+                "expires": 3600,
+                "idle_timeout": 600,
+                "zones": zones,
+            });
+            ::std::fs::write(&genesis, json!({ "captif": captif }).to_string()).unwrap();
+        };
+
+        write(json!([{ "name": "lobby" }]));
+        assert!(sentry.reload().is_err());
+        assert_eq!(sentry.portal().idle_timeout, None);
+
+        write(json!([]));
+        sentry.reload().unwrap();
+        assert_eq!(sentry.portal().idle_timeout, Some(600));
+    }
+
+    #[test]
+    fn test_reload_startup_keys() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        let genesis = dir.path().join("genesis.json");
+        sentry.settings = Arc::new(Settings {
+            genesis_path: genesis.to_str().unwrap().to_owned(),
+            ..Settings::default()
+        });
+        let write = |captif: &::serde_json::Value| {
+            ::std::fs::write(&genesis, json!({ "captif": captif }).to_string()).unwrap();
+        };
+        let captif = json!({ "url": "http://portal.example.com/", "idle_timeout": 600 });
+
+        for &(key, ref value) in &[
+            ("token_key", json!("key")),
+            ("legacy_auth", json!(true)),
+            ("backends", json!(["backend"])),
+            ("events", json!({ "ubus": true })),
+            ("api_address", json!("127.0.0.1:9000")),
+            ("failure_policy", json!("closed")),
+            ("firewall", json!("nftables")),
+        ] {
+            let mut changed = captif.clone();
+            changed[key] = value.clone();
+            write(&changed);
+            let e = sentry.reload().unwrap_err();
+            assert_eq!(e.to_string(), format!("{} changed, that takes a restart of sentry", key));
+            assert_eq!(sentry.portal().idle_timeout, None);
+        }
+
+        write(&captif);
+        sentry.reload().unwrap();
+        assert_eq!(sentry.portal().idle_timeout, Some(600));
+    }
+
+    #[test]
+    fn test_zone_isolation() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        let firewall = Arc::new(FakeFirewall::with_zones(&["lobby", "staff"]));
+        sentry.firewall = firewall.clone();
+        let neighbors = Arc::new(FakeNeighbors::default());
+        neighbors.insert("10.1.0.2", TEST_MAC);
+        sentry.neighbors = neighbors.clone();
+        let public = sentry.portal().zones[0].clone();
+        let zone = |name: &str, subnet: &str| Zone {
+            name: name.to_owned(),
+            subnets: vec![walled_garden::Network::parse(subnet).unwrap()],
+            ..public.clone()
+        };
+        sentry.set_portal(Portal {
+            zones: vec![zone("lobby", "10.1.0.0/16"), zone("staff", "10.2.0.0/16")],
+            ..sentry.portal()
+        });
+        let macs = |zone| -> Vec<String> {
+            firewall.list(zone).unwrap().into_iter().map(|e| e.mac).collect()
+        };
+
+        sentry
+            .authorize_mac(TEST_MAC, Some("10.1.0.2"), None, None, Rate::default())
+            .unwrap();
+        assert_eq!(macs("lobby"), vec![TEST_MAC]);
+        assert!(macs("staff").is_empty());
+
+        // moving to the staff network takes a new authorization there
+        neighbors.insert("10.2.0.2", TEST_MAC);
+        sentry
+            .authorize_mac(TEST_MAC, Some("10.2.0.2"), None, None, Rate::default())
+            .unwrap();
+        assert!(macs("lobby").is_empty());
+        assert_eq!(macs("staff"), vec![TEST_MAC]);
+
+        sentry.revoke_mac(TEST_MAC).unwrap();
+        assert!(macs("staff").is_empty());
+
+        // clients of other networks are not placed in any zone
+        let other = "de:ad:be:ef:00:22";
+        neighbors.insert("192.168.8.2", other);
+        assert!(sentry
+            .authorize_mac(other, Some("192.168.8.2"), None, None, Rate::default())
+            .is_err());
+        assert!(sentry.authorize_mac(other, None, None, None, Rate::default()).is_err());
+        // and neither by the secret of legacy portals
+        assert!(sentry.authorize_client("192.168.8.2", None).is_err());
+        assert!(sentry.authorize_client("192.168.8.3", None).is_err());
+        assert!(macs("lobby").is_empty() && macs("staff").is_empty());
+    }
+
+    #[test]
+    fn test_rate() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        let firewall = Arc::new(FakeFirewall::default());
+        sentry.firewall = firewall.clone();
+        sentry.set_portal(Portal {
+            rate: Rate {
+                up: Some(1000),
+                down: Some(8000),
+            },
+            ..sentry.portal()
+        });
+
+        let rate = Rate {
+            up: None,
+            down: Some(2000),
+        };
+        sentry.authorize_mac(TEST_MAC, None, None, None, rate).unwrap();
+        assert_eq!(
+            firewall.accounting.lock().unwrap()[0].rate,
+            Rate {
+                up: Some(1000),
+                down: Some(2000),
+            }
+        );
+
+        sentry.revoke_mac(TEST_MAC).unwrap();
+        assert!(firewall.accounting.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extend() {
+        let dir = TempDir::new("sentry").unwrap();
+        let core = Core::new().unwrap();
+        let sentry = testing::sentry(&core, dir.path());
+        let mut portal = sentry.portal();
+        portal.zones[0].expires = None;
+        sentry.set_portal(portal);
+
+        match sentry.extend_mac(TEST_MAC, 60) {
+            Err(Error(ErrorKind::UnknownSession(_), _)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+
+        sentry.authorize_mac(TEST_MAC, None, None, None, Rate::default()).unwrap();
+        match sentry.extend_mac(TEST_MAC, 60) {
+            Err(Error(ErrorKind::UnlimitedSession(_), _)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(sentry.sessions().unwrap()[0].expires, None);
     }
 }
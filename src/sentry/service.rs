@@ -1,25 +1,237 @@
 use sentry::Sentry;
+use sentry::metrics;
 use sentry::proxy;
+use sentry::store::Session;
 
 use std::net::SocketAddr;
 use std::str::FromStr;
 
 use hyper;
 use hyper::server::{self, Request, Response};
-use hyper::header::{Connection, Host, Location, Referer};
+use hyper::header::{CacheControl, CacheDirective, Connection, ContentType, Host, Location};
 use handlebars::Handlebars;
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 
 use futures::future::{Either, Future};
 use futures;
-use sentry::ubus;
 use sentry::ip;
 
+use chrono::Local;
+use serde_json::Value;
+
+/// The query parameter carrying the authorization token.
+const TOKEN_PARAM: &str = "sentry_token";
+
+/// The path of the captive portal api (RFC 8908).
+pub const CAPTIVE_API_PATH: &str = "/captive-portal/api";
+const CAPTIVE_API_CONTENT_TYPE: &str = "application/captive+json";
+
+/// Returns the value of the first query parameter named `name`.
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Recognizes the connectivity probes of the operating systems.
+///
+/// Unauthorized clients get a response the os takes as captive, so it shows its login
+/// sheet. Authorized clients may still hit the service for a moment after authorization,
+/// they get exactly the response the os expects when it is online.
+mod probe {
+    use hyper::StatusCode;
+
+    const APPLE_SUCCESS: &str =
+        "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>\n";
+    const FIREFOX_SUCCESS: &str =
+        "<meta http-equiv=\"refresh\" content=\"0;url=https://support.mozilla.org/kb/captive-portal\"/>";
+
+    /// How a probe is answered before authorization.
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Captive {
+        /// The usual redirect to the portal.
+        Redirect,
+        /// A page linking the portal, for probes that show the response in their sheet.
+        Page,
+    }
+
+    #[derive(PartialEq, Debug)]
+    pub struct Probe {
+        pub os: &'static str,
+        hosts: &'static [&'static str],
+        path: &'static str,
+        pub captive: Captive,
+        /// The expected response when online.
+        pub status: StatusCode,
+        pub body: &'static str,
+    }
+
+    const APPLE_HOSTS: &[&str] = &[
+        "captive.apple.com",
+        "www.apple.com",
+        "www.appleiphonecell.com",
+        "www.airport.us",
+        "www.ibook.info",
+        "www.itools.info",
+        "www.thinkdifferent.us",
+    ];
+
+    const ANDROID_HOSTS: &[&str] = &[
+        "connectivitycheck.gstatic.com",
+        "connectivitycheck.android.com",
+        "clients1.google.com",
+        "clients3.google.com",
+        "www.google.com",
+        "play.googleapis.com",
+    ];
+
+    const PROBES: &[Probe] = &[
+        Probe {
+            os: "apple",
+            hosts: APPLE_HOSTS,
+            path: "/hotspot-detect.html",
+            captive: Captive::Page,
+            status: StatusCode::Ok,
+            body: APPLE_SUCCESS,
+        },
+        Probe {
+            os: "apple",
+            hosts: APPLE_HOSTS,
+            path: "/library/test/success.html",
+            captive: Captive::Page,
+            status: StatusCode::Ok,
+            body: APPLE_SUCCESS,
+        },
+        Probe {
+            os: "android",
+            hosts: ANDROID_HOSTS,
+            path: "/generate_204",
+            captive: Captive::Redirect,
+            status: StatusCode::NoContent,
+            body: "",
+        },
+        Probe {
+            os: "android",
+            hosts: &["www.google.com"],
+            path: "/gen_204",
+            captive: Captive::Redirect,
+            status: StatusCode::NoContent,
+            body: "",
+        },
+        Probe {
+            os: "windows",
+            hosts: &["www.msftconnecttest.com"],
+            path: "/connecttest.txt",
+            captive: Captive::Redirect,
+            status: StatusCode::Ok,
+            body: "Microsoft Connect Test",
+        },
+        Probe {
+            os: "windows",
+            hosts: &["www.msftncsi.com"],
+            path: "/ncsi.txt",
+            captive: Captive::Redirect,
+            status: StatusCode::Ok,
+            body: "Microsoft NCSI",
+        },
+        Probe {
+            os: "firefox",
+            hosts: &["detectportal.firefox.com"],
+            path: "/canonical.html",
+            captive: Captive::Redirect,
+            status: StatusCode::Ok,
+            body: FIREFOX_SUCCESS,
+        },
+        Probe {
+            os: "firefox",
+            hosts: &["detectportal.firefox.com"],
+            path: "/success.txt",
+            captive: Captive::Redirect,
+            status: StatusCode::Ok,
+            body: "success\n",
+        },
+        Probe {
+            os: "ubuntu",
+            hosts: &["connectivity-check.ubuntu.com"],
+            path: "/",
+            captive: Captive::Redirect,
+            status: StatusCode::NoContent,
+            body: "",
+        },
+    ];
+
+    /// Finds the probe requesting `path` on `host`.
+    pub fn recognize(host: &str, path: &str) -> Option<&'static Probe> {
+        let host = host.trim_end_matches('.').to_lowercase();
+
+        PROBES
+            .iter()
+            .find(|probe| probe.path == path && probe.hosts.contains(&host.as_str()))
+    }
+}
+
+/// The captive portal api state of a client, `session` is its session if it was
+/// ever authorized.
+fn captive_status(session: Option<&Session>, portal_url: &str, now: i64) -> Value {
+    match session {
+        Some(session) if session.is_active(now) => {
+            let mut status = json!({ "captive": false, "user-portal-url": portal_url });
+            if let Some(expires) = session.expires {
+                status["seconds-remaining"] = json!(expires - now);
+            }
+            if let Some(bytes) = session.bytes_remaining() {
+                status["bytes-remaining"] = json!(bytes);
+            }
+            status
+        }
+        _ => json!({ "captive": true, "user-portal-url": portal_url }),
+    }
+}
+
+/// The response the probe expects when the client is online.
+fn probe_success(probe: &probe::Probe) -> Response {
+    Response::new()
+        .with_status(probe.status)
+        .with_header(CacheControl(vec![CacheDirective::NoCache, CacheDirective::NoStore]))
+        .with_header(Connection::close())
+        .with_body(probe.body)
+}
+
+/// A page sending the client to the portal, for probes showing the response.
+fn portal_page(portal_url: &str) -> Response {
+    let url = html_escape(portal_url);
+
+    Response::new()
+        .with_header(ContentType::html())
+        .with_header(CacheControl(vec![CacheDirective::NoCache, CacheDirective::NoStore]))
+        .with_header(Connection::close())
+        .with_body(format!(
+            "<HTML><HEAD><TITLE>Sign in</TITLE>\
+             <META http-equiv=\"refresh\" content=\"0;url={0}\"></HEAD>\
+             <BODY><A href=\"{0}\">Sign in</A></BODY></HTML>\n",
+            url
+        ))
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 #[derive(Clone, new, Debug)]
 pub struct Service {
-    redirect_url: String,
-    redirect_host: String,
     sentry: Sentry,
+    /// Whether the requests arrived over the https listener.
+    tls: bool,
 }
 
 /// The service that handles the http requests.
@@ -31,38 +243,63 @@ pub struct Service {
 ///    http status code 302.
 /// 2. The client will request the portal and will get the portal served.
 /// 3. The client presses the accept button in the portal. The button redirects to a
-///    new page in the portal. This redirect contains our secret, so the service will
-///    authorize the client.
+///    new page in the portal. This redirect contains a token signed by the portal,
+///    so the service will authorize the client.
 /// 4. After authorization, the service should not see any new requests from the client.
+///
+/// Requests for hosts in the walled garden are proxied, so the portal can use payment
+/// providers or login services before the client is authorized.
 impl Service {
     fn remote_addr_to_ip(&self, remote_addr: &SocketAddr) -> String {
-        format!("{}", remote_addr.ip())
+        format!("{}", ip::normalize(remote_addr.ip()))
     }
 
-    /// Checks the request for the service secret. If the secret is present,
-    /// the client is authorized.
+    /// Checks the request for an authorization token signed by the portal or, in
+    /// legacy mode, for the service secret. If present, the client is authorized.
+    ///
+    /// In legacy mode the portal may pass the session length in seconds as
+    /// `duration` parameter, tokens carry it signed.
     fn handle_authorized(&self, req: &Request) {
-        if let Some(query) = req.uri().query() {
-            if self.sentry.contains_secret(query) {
-                if let Some(address) = req.remote_addr() {
-                    self.sentry
-                        .authorize_client(&self.remote_addr_to_ip(&address));
-                }
+        let query = if let Some(query) = req.uri().query() {
+            query
+        } else {
+            return;
+        };
+
+        let address = if let Some(address) = req.remote_addr() {
+            address
+        } else {
+            return;
+        };
+        let ip = self.remote_addr_to_ip(&address);
+
+        if let Some(token) = query_param(query, TOKEN_PARAM) {
+            if let Err(e) = self.sentry.authorize_token(&ip, token) {
+                info!("rejected token of {}: {}", ip, e);
+            }
+        } else if self.sentry.legacy_auth && self.sentry.contains_secret(query) {
+            let duration = query_param(query, "duration").and_then(|d| d.parse().ok());
+            if let Err(e) = self.sentry.authorize_client(&ip, duration) {
+                warn!("unable to authorize {}: {}", ip, e);
             }
         }
     }
 
-    /// Fetches the portal if the host header is equal to the `redirect_host`
+    /// Fetches the portal if the host header is equal to the `redirect_host` of the
+    /// client's zone
     fn handle_portal(&self, req: &Request) -> Option<proxy::Result> {
         if let Some(host) = req.headers().get::<Host>() {
-            if host.hostname() == self.redirect_host {
-                let address = req.remote_addr()
-                    .expect("Could not extract the remote address");
+            let address = req.remote_addr()
+                .expect("Could not extract the remote address");
+            let ip_address = self.remote_addr_to_ip(&address);
+
+            let zone = self.sentry.zone_for_ip(&ip_address);
+            if zone.map_or(false, |zone| host.hostname() == zone.redirect_host) {
                 let uri = hyper::Uri::from_str(&format!("http://{}{}", host, req.uri().as_ref()))
                     .expect("Error at building the portal url!");
 
                 return Some(self.sentry.fetch_portal(
-                    &self.remote_addr_to_ip(&address),
+                    &ip_address,
                     &uri,
                     req.method(),
                     req.headers(),
@@ -73,30 +310,89 @@ impl Service {
         None
     }
 
-    /// Proxies requests, if the referer header is equal to the `redirect_host`
-    fn handle_referer(&self, req: &Request) -> Option<proxy::Result> {
+    /// Proxies requests for the hosts in the walled garden
+    fn handle_walled_garden(&self, req: &Request) -> Option<proxy::Result> {
         let host = if let Some(host) = req.headers().get::<Host>() {
             host
         } else {
             return None;
         };
 
-        let referer = if let Some(referer) = req.headers().get::<Referer>() {
-            referer
+        if self.sentry.portal().walled_garden.allows_host(host.hostname()) {
+            let uri = hyper::Uri::from_str(&format!("http://{}{}", host, req.uri().as_ref()))
+                .expect("Error at building the walled garden url!");
+
+            return Some(self.sentry.proxy_request(&uri, req.method(), req.headers()));
+        }
+
+        None
+    }
+
+    /// Answers the captive portal api for the requesting client
+    fn handle_captive_api(&self, req: &Request) -> Option<Response> {
+        if req.path() != CAPTIVE_API_PATH {
+            return None;
+        }
+
+        let address = req.remote_addr()
+            .expect("Could not extract the remote address");
+        let ip_address = self.remote_addr_to_ip(&address);
+        let status = captive_status(
+            self.sentry.session_for_ip(&ip_address).as_ref(),
+            &self.portal_url(&ip_address, "")?,
+            Local::now().timestamp(),
+        );
+
+        Some(
+            Response::new()
+                .with_header(ContentType(CAPTIVE_API_CONTENT_TYPE.parse().unwrap()))
+                .with_header(CacheControl(vec![CacheDirective::Private]))
+                .with_header(Connection::close())
+                .with_body(status.to_string()),
+        )
+    }
+
+    /// Answers the connectivity probes of the operating systems
+    fn handle_probe(&self, req: &Request) -> Option<Response> {
+        let probe = if let Some(host) = req.headers().get::<Host>() {
+            probe::recognize(host.hostname(), req.path())?
         } else {
             return None;
         };
 
-        if let Ok(ref_uri) = hyper::Uri::from_str(referer.chars().as_str()) {
-            if ref_uri.host() == Some(self.redirect_host.as_str()) {
-                let uri = hyper::Uri::from_str(&format!("http://{}{}", host, req.uri().as_ref()))
-                    .expect("Error at building the referer url!");
+        let address = req.remote_addr()
+            .expect("Could not extract the remote address");
+        let ip_address = self.remote_addr_to_ip(&address);
+        let authorized = self.sentry
+            .session_for_ip(&ip_address)
+            .map_or(false, |session| session.is_active(Local::now().timestamp()));
 
-                return Some(self.sentry.proxy_request(&uri, req.method(), req.headers()));
-            }
+        if authorized {
+            Some(probe_success(probe))
+        } else if probe.captive == probe::Captive::Page {
+            self.portal_url(&ip_address, "").map(|url| portal_page(&url))
+        } else {
+            None
         }
+    }
 
-        None
+    /// Renders the portal url of the zone of the client with the given ip address,
+    /// `None` for clients in no zone
+    fn portal_url(&self, ip_address: &str, origin: &str) -> Option<String> {
+        let zone = self.sentry.zone_for_ip(ip_address)?;
+        let mac = self.sentry.mac_for_ip(ip_address).unwrap_or(String::new());
+        let hostname = percent_encode(
+                self.sentry.client_hostname(ip_address, &mac).unwrap_or_default().as_bytes(),
+                NON_ALPHANUMERIC).to_string();
+
+        Some(Handlebars::new().render_template(&zone.redirect_url, &json!({
+            "origin":          origin,
+            "identity":        self.sentry.identity,
+            "client_ip_addr":  ip_address,
+            "client_mac_addr": mac,
+            "client_hostname": hostname,
+            "zone":            zone.name,
+        })).unwrap())
     }
 
     /// Redirects each request to the portal
@@ -113,23 +409,24 @@ impl Service {
         let address = req.remote_addr()
             .expect("Could not extract the remote address");
         let ip_address = self.remote_addr_to_ip(&address);
-        let hostname = percent_encode(
-                ubus::get_hostname_for_ip(&ip_address).unwrap_or_default().as_bytes(),
-                NON_ALPHANUMERIC).to_string();
-        let mac = ip::ip_to_mac(&ip_address).unwrap_or(String::new());
-        let origin = percent_encode(format!("http://{}{}", host, req.uri().as_ref()).as_bytes(),
+        let scheme = if self.tls { "https" } else { "http" };
+        let origin = percent_encode(format!("{}://{}{}", scheme, host, req.uri().as_ref()).as_bytes(),
             NON_ALPHANUMERIC).to_string();
 
-        let location = Handlebars::new().render_template(&self.redirect_url, &json!({
-            "origin":          origin,
-            "identity":        self.sentry.identity,
-            "client_ip_addr":  ip_address,
-            "client_mac_addr": mac,
-            "client_hostname": hostname,
-        })).unwrap();
+        let location = match self.portal_url(&ip_address, &origin) {
+            Some(location) => location,
+            None => {
+                warn!("refusing {}, it is in no zone", ip_address);
+                resp.set_status(hyper::StatusCode::Forbidden);
+                resp.headers_mut().set(Connection::close());
+                return resp;
+            }
+        };
 
         resp.headers_mut().set(Location::new(location));
+        resp.headers_mut().set(CacheControl(vec![CacheDirective::NoCache, CacheDirective::NoStore]));
         resp.headers_mut().set(Connection::close());
+        metrics::inc(&self.sentry.metrics.redirects);
 
         resp
     }
@@ -147,12 +444,175 @@ impl server::Service for Service {
     fn call(&self, req: Request) -> Self::Future {
         self.handle_authorized(&req);
 
-        if let Some(resp) = self.handle_portal(&req) {
+        if let Some(resp) = self.handle_captive_api(&req) {
+            Either::A(futures::future::ok(resp))
+        } else if let Some(resp) = self.handle_probe(&req) {
+            Either::A(futures::future::ok(resp))
+        } else if let Some(resp) = self.handle_portal(&req) {
             Either::B(resp)
-        } else if let Some(resp) = self.handle_referer(&req) {
+        } else if let Some(resp) = self.handle_walled_garden(&req) {
             Either::B(resp)
         } else {
             Either::A(futures::future::ok(self.handle_redirect(&req)))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_param() {
+        let query = "tos_accepted=true&duration=3600&empty=";
+
+        assert_eq!(query_param(query, "duration"), Some("3600"));
+        assert_eq!(query_param(query, "empty"), Some(""));
+        assert_eq!(query_param(query, "tos_accepted"), Some("true"));
+        assert_eq!(query_param(query, "missing"), None);
+    }
+
+    #[test]
+    fn test_captive_status() {
+        let url = "http://portal.example.com/";
+        let session = |expires| Session {
+            mac: "de:ad:be:ef:00:11".to_owned(),
+            ip: None,
+            hostname: None,
+            authorized: 1000,
+            expires: expires,
+            quota: None,
+            packets: 0,
+            bytes: 0,
+            rate: Default::default(),
+            zone: None,
+        };
+        let limited = |quota| Session {
+            quota: Some(quota),
+            bytes: 600,
+            ..session(None)
+        };
+
+        let cases = vec![
+            (None, json!({ "captive": true, "user-portal-url": url })),
+            (
+                Some(session(Some(1600))),
+                json!({ "captive": false, "user-portal-url": url, "seconds-remaining": 400 }),
+            ),
+            (Some(session(None)), json!({ "captive": false, "user-portal-url": url })),
+            (Some(session(Some(1100))), json!({ "captive": true, "user-portal-url": url })),
+            (
+                Some(limited(1000)),
+                json!({ "captive": false, "user-portal-url": url, "bytes-remaining": 400 }),
+            ),
+            (Some(limited(600)), json!({ "captive": true, "user-portal-url": url })),
+        ];
+
+        for (session, expected) in cases {
+            assert_eq!(captive_status(session.as_ref(), url, 1200), expected);
+        }
+    }
+
+    /// Real probe requests, the host and path of each are matched against the table.
+    const PROBE_REQUESTS: &[(&str, Option<(&str, u16, &str)>)] = &[
+        (
+            "GET /hotspot-detect.html HTTP/1.0\r\nHost: captive.apple.com\r\n\
+             User-Agent: CaptiveNetworkSupport-407.40.1 wispr\r\n\r\n",
+            Some(("apple", 200, "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>\n")),
+        ),
+        (
+            "GET /library/test/success.html HTTP/1.1\r\nHost: www.apple.com\r\n\r\n",
+            Some(("apple", 200, "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>\n")),
+        ),
+        (
+            "GET /generate_204 HTTP/1.1\r\nHost: connectivitycheck.gstatic.com\r\n\
+             User-Agent: Dalvik/2.1.0 (Linux; U; Android 13; Pixel 6)\r\n\r\n",
+            Some(("android", 204, "")),
+        ),
+        (
+            "GET /generate_204 HTTP/1.1\r\nHost: clients3.google.com\r\n\r\n",
+            Some(("android", 204, "")),
+        ),
+        (
+            "GET /gen_204 HTTP/1.1\r\nHost: www.google.com\r\n\r\n",
+            Some(("android", 204, "")),
+        ),
+        (
+            "GET /connecttest.txt HTTP/1.1\r\nConnection: Close\r\n\
+             User-Agent: Microsoft NCSI\r\nHost: www.msftconnecttest.com\r\n\r\n",
+            Some(("windows", 200, "Microsoft Connect Test")),
+        ),
+        (
+            "GET /ncsi.txt HTTP/1.1\r\nUser-Agent: Microsoft NCSI\r\nHost: www.msftncsi.com\r\n\r\n",
+            Some(("windows", 200, "Microsoft NCSI")),
+        ),
+        (
+            "GET /canonical.html HTTP/1.1\r\nHost: detectportal.firefox.com\r\n\
+             User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0\r\n\r\n",
+            Some((
+                "firefox",
+                200,
+                "<meta http-equiv=\"refresh\" content=\"0;url=https://support.mozilla.org/kb/captive-portal\"/>",
+            )),
+        ),
+        (
+            "GET /success.txt?ipv4 HTTP/1.1\r\nHost: detectportal.firefox.com\r\n\r\n",
+            Some(("firefox", 200, "success\n")),
+        ),
+        (
+            "GET / HTTP/1.1\r\nHost: connectivity-check.ubuntu.com.\r\n\r\n",
+            Some(("ubuntu", 204, "")),
+        ),
+        ("GET / HTTP/1.1\r\nHost: captive.apple.com\r\n\r\n", None),
+        ("GET /generate_204 HTTP/1.1\r\nHost: example.com\r\n\r\n", None),
+        ("GET /index.html HTTP/1.1\r\nHost: www.google.com\r\n\r\n", None),
+    ];
+
+    /// Extracts the host header and the path from a raw request.
+    fn host_and_path(request: &str) -> (&str, &str) {
+        let mut lines = request.lines();
+        let target = lines.next().unwrap().split(' ').nth(1).unwrap();
+        let host = lines
+            .filter_map(|line| line.splitn(2, ": ").nth(1).filter(|_| line.starts_with("Host")))
+            .next()
+            .unwrap();
+
+        (host, target.split('?').next().unwrap())
+    }
+
+    #[test]
+    fn test_probes() {
+        use futures::Stream;
+
+        for &(request, expected) in PROBE_REQUESTS {
+            let (host, path) = host_and_path(request);
+            let probe = probe::recognize(host, path);
+
+            match (probe, expected) {
+                (Some(probe), Some((os, status, body))) => {
+                    assert_eq!(probe.os, os, "{}", request);
+
+                    let resp = probe_success(probe);
+                    assert_eq!(resp.status().as_u16(), status, "{}", request);
+                    let actual = resp.body().concat2().wait().unwrap();
+                    assert_eq!(&*actual, body.as_bytes(), "{}", request);
+                }
+                (None, None) => {}
+                (probe, _) => panic!("{:?} recognized as {:?}", request, probe),
+            }
+        }
+    }
+
+    #[test]
+    fn test_portal_page() {
+        use futures::Stream;
+
+        let resp = portal_page("http://portal.example.com/?a=1&b=\"2\"");
+        assert_eq!(resp.status(), hyper::StatusCode::Ok);
+
+        let body = resp.body().concat2().wait().unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("url=http://portal.example.com/?a=1&amp;b=&quot;2&quot;\""));
+        assert!(!body.contains("Success"));
+    }
+}
@@ -0,0 +1,605 @@
+//! The settings of sentry itself, the genesis `captif` config is about the portal.
+//!
+//! They are read from the toml file `SETTINGS_PATH` or, if there is none, from the
+//! uci config `UCI_PATH`. Every key is optional. The `api_address`, `failure_policy`,
+//! `firewall` backend and `events` keys of the captif config take precedence, see
+//! `Settings::layer`.
+//!
+//! ```toml
+//! listen_port = 8444
+//! reconcile_interval = 10
+//!
+//! [firewall]
+//! backend = "nftables"
+//! nftables_chain = "dstnat_{zone}"
+//!
+//! [log]
+//! level = "info,sentry::proxy=debug"
+//! ```
+//!
+//! In uci, the `sentry` section holds the top level keys and the `firewall`, `events`
+//! and `log` sections the keys of their tables. The values are coerced to the type of
+//! their key, booleans take the uci spellings `1`/`0`, `on`/`off`, `yes`/`no`,
+//! `enabled`/`disabled` and `true`/`false`.
+//!
+//! ```text
+//! config sentry 'main'
+//!     option listen_port '8444'
+//!
+//! config firewall
+//!     option backend 'nftables'
+//! ```
+
+use errors::*;
+use sentry::api;
+use sentry::config::{self, Captif};
+use sentry::degraded::FailurePolicy;
+use sentry::events;
+use sentry::firewall;
+use sentry::logging;
+use time_control;
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::result;
+
+use chrono_tz::Tz;
+use serde::de::{self, Deserialize, IntoDeserializer, Unexpected, Visitor};
+use serde::de::value::{Error as UciError, MapDeserializer, SeqDeserializer};
+use toml;
+
+pub const SETTINGS_PATH: &str = "/etc/sentry/sentry.toml";
+pub const UCI_PATH: &str = "/etc/config/sentry";
+/// The uci section type of the top level keys.
+const UCI_SECTION: &str = "sentry";
+const MIN_SECRET_LENGTH: usize = 8;
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// The port of the captive listener, the firewall redirects the clients to it.
+    pub listen_port: u16,
+    /// The port https connections of clients are redirected to.
+    pub tls_listen_port: u16,
+    /// The pem files of the certificate chain and the key the captive portal api is
+    /// served with on the tls listener, see `Captif::captive_api_url`.
+    pub tls_certificate: Option<String>,
+    pub tls_key: Option<String>,
+    /// The address of the local management api, a loopback address.
+    pub api_address: String,
+    /// The length of the secret legacy portals authorize clients with.
+    pub secret_length: usize,
+    pub genesis_path: String,
+    /// Read if there is no file at `genesis_path`.
+    pub genesis_fallback_path: String,
+    /// Seconds between the checks of the sessions and the wifi schedules.
+    pub reconcile_interval: u64,
+    /// Seconds between the retries of the captif config while degraded.
+    pub degraded_retry_interval: u64,
+    /// The timezone of zone schedules without one.
+    pub timezone: String,
+    /// The bridge of zones without one, `{zone}` is replaced by the zone name.
+    pub bridge: String,
+    pub failure_policy: FailurePolicy,
+    pub firewall: firewall::Config,
+    pub events: events::Config,
+    pub log: logging::Config,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            listen_port: 8444,
+            tls_listen_port: 8443,
+            tls_certificate: None,
+            tls_key: None,
+            api_address: api::DEFAULT_API_ADDRESS.to_owned(),
+            secret_length: 16,
+            genesis_path: config::GENESIS_PATH.to_owned(),
+            genesis_fallback_path: config::GENESIS_FALLBACK_PATH.to_owned(),
+            reconcile_interval: 10,
+            degraded_retry_interval: 30,
+            timezone: time_control::DEFAULT_TIMEZONE.to_owned(),
+            bridge: "br-{zone}".to_owned(),
+            failure_policy: FailurePolicy::default(),
+            firewall: firewall::Config::default(),
+            events: events::Config::default(),
+            log: logging::Config::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Checks the values serde can not, the errors name the offending key.
+    pub fn validate(&self) -> Result<()> {
+        for &(key, port) in &[
+            ("listen_port", self.listen_port),
+            ("tls_listen_port", self.tls_listen_port),
+        ] {
+            if port == 0 {
+                bail!("`{}` must not be 0", key);
+            }
+        }
+        if self.listen_port == self.tls_listen_port {
+            bail!("`listen_port` and `tls_listen_port` must differ");
+        }
+        if self.tls_certificate.is_some() != self.tls_key.is_some() {
+            bail!("`tls_certificate` and `tls_key` must be set together");
+        }
+        let api_address = self.api_address
+            .parse::<SocketAddr>()
+            .chain_err(|| format!("`api_address` is not an address: {}", self.api_address))?;
+        // the management api has no authentication
+        if !api_address.ip().is_loopback() {
+            bail!("`api_address` must be a loopback address: {}", self.api_address);
+        }
+        if self.secret_length < MIN_SECRET_LENGTH {
+            bail!("`secret_length` must be at least {}", MIN_SECRET_LENGTH);
+        }
+        for &(key, interval) in &[
+            ("reconcile_interval", self.reconcile_interval),
+            ("degraded_retry_interval", self.degraded_retry_interval),
+        ] {
+            if interval == 0 {
+                bail!("`{}` must not be 0", key);
+            }
+        }
+        if let Err(e) = self.timezone.parse::<Tz>() {
+            bail!("`timezone`: {}", e);
+        }
+        self.log.validate().chain_err(|| "invalid `log.level`")?;
+        if self.firewall.iptables_table.is_empty() {
+            bail!("`firewall.iptables_table` must not be empty");
+        }
+        for &(key, chain) in &[
+            ("firewall.iptables_chain", &self.firewall.iptables_chain),
+            ("firewall.nftables_chain", &self.firewall.nftables_chain),
+        ] {
+            if !chain.contains("{zone}") {
+                bail!("`{}` must contain {{zone}}: {}", key, chain);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The settings with the keys set in the captif config taking precedence.
+    pub fn layer(&self, captif: &Captif) -> Settings {
+        let mut settings = self.clone();
+        if let Some(ref api_address) = captif.api_address {
+            settings.api_address = api_address.clone();
+        }
+        if let Some(policy) = captif.failure_policy {
+            settings.failure_policy = policy;
+        }
+        if let Some(backend) = captif.firewall {
+            settings.firewall.backend = backend;
+        }
+        if let Some(ref events) = captif.events {
+            settings.events = events.clone();
+        }
+        settings
+    }
+
+    /// The bridge of a zone without one.
+    pub fn bridge(&self, zone: &str) -> String {
+        self.bridge.replace("{zone}", zone)
+    }
+}
+
+/// Reads and validates the settings, the defaults are used if there are none.
+pub fn load() -> Result<Settings> {
+    load_from(Path::new(SETTINGS_PATH), Path::new(UCI_PATH))
+}
+
+fn load_from(toml_path: &Path, uci_path: &Path) -> Result<Settings> {
+    if toml_path.exists() {
+        load_file(toml_path)
+    } else if uci_path.exists() {
+        load_file(uci_path)
+    } else {
+        Ok(Settings::default())
+    }
+}
+
+/// Reads and validates the settings in `path`, a toml file if its extension is `toml`
+/// and a uci config otherwise.
+pub fn load_file(path: &Path) -> Result<Settings> {
+    let text =
+        fs::read_to_string(path).chain_err(|| format!("unable to read {}", path.display()))?;
+
+    let settings: Settings = if path.extension() == Some(OsStr::new("toml")) {
+        toml::from_str(&text).chain_err(|| format!("invalid settings in {}", path.display()))?
+    } else {
+        let value = parse_uci(&text).chain_err(|| format!("unable to parse {}", path.display()))?;
+        Settings::deserialize(value)
+            .chain_err(|| format!("invalid settings in {}", path.display()))?
+    };
+
+    settings
+        .validate()
+        .chain_err(|| format!("invalid settings in {}", path.display()))?;
+    Ok(settings)
+}
+
+/// Splits a line of a uci config into words. Quotes group words, comments end the line.
+fn uci_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' if word.is_none() => break,
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                let mut closed = false;
+                for q in chars.by_ref() {
+                    if q == c {
+                        closed = true;
+                        break;
+                    }
+                    word.push(q);
+                }
+                if !closed {
+                    bail!("unterminated quote");
+                }
+            }
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    Ok(words)
+}
+
+/// A value of a uci config. uci only knows strings, they are coerced to the type of the
+/// settings key they are read into.
+#[derive(Clone, PartialEq, Debug)]
+enum UciValue {
+    String(String),
+    List(Vec<String>),
+    Section(BTreeMap<String, UciValue>),
+}
+
+/// The boolean spellings uci accepts.
+fn uci_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "on" | "yes" | "enabled" | "true" => Some(true),
+        "0" | "off" | "no" | "disabled" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> result::Result<V::Value, UciError> {
+                match self {
+                    UciValue::String(ref value) => match value.parse() {
+                        Ok(parsed) => visitor.$visit(parsed),
+                        Err(_) => Err(de::Error::invalid_value(Unexpected::Str(value), &visitor)),
+                    },
+                    _ => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for UciValue {
+    type Error = UciError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> result::Result<V::Value, UciError> {
+        match self {
+            UciValue::String(value) => visitor.visit_string(value),
+            UciValue::List(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter().map(UciValue::String));
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            UciValue::Section(table) => {
+                let mut map = MapDeserializer::new(table.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> result::Result<V::Value, UciError> {
+        match self {
+            UciValue::String(ref value) => match uci_bool(value) {
+                Some(value) => visitor.visit_bool(value),
+                None => Err(de::Error::invalid_value(Unexpected::Str(value), &visitor)),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> result::Result<V::Value, UciError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> result::Result<V::Value, UciError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> result::Result<V::Value, UciError> {
+        match self {
+            UciValue::String(value) => visitor.visit_enum(value.into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, UciError> for UciValue {
+    type Deserializer = UciValue;
+
+    fn into_deserializer(self) -> UciValue {
+        self
+    }
+}
+
+/// Turns a uci config into the structure of the settings.
+fn parse_uci(text: &str) -> Result<UciValue> {
+    let mut root = BTreeMap::new();
+    // the type of the current section, `None` for the top level keys
+    let mut section: Option<String> = None;
+
+    for (n, line) in text.lines().enumerate() {
+        let words = uci_words(line).chain_err(|| format!("line {}", n + 1))?;
+        let (keyword, args) = match words.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+
+        match keyword.as_str() {
+            "config" if !args.is_empty() => {
+                section = if args[0] == UCI_SECTION {
+                    None
+                } else {
+                    Some(args[0].clone())
+                };
+            }
+            "option" | "list" if args.len() == 2 => {
+                let table = match section {
+                    None => &mut root,
+                    Some(ref kind) => match *root
+                        .entry(kind.clone())
+                        .or_insert_with(|| UciValue::Section(BTreeMap::new()))
+                    {
+                        UciValue::Section(ref mut table) => table,
+                        _ => bail!("line {}: `{}` is an option", n + 1, kind),
+                    },
+                };
+                let value = args[1].clone();
+
+                if keyword == "list" {
+                    let list = table
+                        .entry(args[0].clone())
+                        .or_insert_with(|| UciValue::List(Vec::new()));
+                    match *list {
+                        UciValue::List(ref mut items) => items.push(value),
+                        _ => bail!("line {}: `{}` is an option and a list", n + 1, args[0]),
+                    }
+                } else {
+                    table.insert(args[0].clone(), UciValue::String(value));
+                }
+            }
+            _ => bail!("line {}: unexpected `{}`", n + 1, line.trim()),
+        }
+    }
+
+    Ok(UciValue::Section(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+    use tempdir::TempDir;
+
+    const UCI_CONFIG: &str = "
+# sentry settings
+config sentry 'main'
+\toption listen_port '9444'
+\toption timezone \"UTC\"
+\toption bridge br-{zone}
+
+config firewall
+\toption backend 'nftables'
+\toption nftables_chain 'dstnat_{zone}_sentry'
+
+config events
+\toption ubus '0'
+\toption jsonl '/tmp/sentry/events.jsonl'
+
+config log
+\toption output 'stderr'
+\toption redact_macs 'on'
+";
+
+    fn expected() -> Settings {
+        Settings {
+            listen_port: 9444,
+            timezone: "UTC".to_owned(),
+            firewall: firewall::Config {
+                backend: firewall::Backend::Nftables,
+                nftables_chain: "dstnat_{zone}_sentry".to_owned(),
+                ..firewall::Config::default()
+            },
+            events: events::Config {
+                ubus: false,
+                jsonl: Some("/tmp/sentry/events.jsonl".to_owned()),
+                carrier: false,
+            },
+            log: logging::Config {
+                output: logging::Output::Stderr,
+                redact_macs: true,
+                ..logging::Config::default()
+            },
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = TempDir::new("settings").unwrap();
+        let toml_path = dir.path().join("sentry.toml");
+        let uci_path = dir.path().join("sentry");
+
+        assert_eq!(load_from(&toml_path, &uci_path).unwrap(), Settings::default());
+
+        fs::write(&uci_path, UCI_CONFIG).unwrap();
+        assert_eq!(load_from(&toml_path, &uci_path).unwrap(), expected());
+
+        fs::write(
+            &toml_path,
+            "listen_port = 9444\n\
+             timezone = \"UTC\"\n\
+             [firewall]\n\
+             backend = \"nftables\"\n\
+             nftables_chain = \"dstnat_{zone}_sentry\"\n\
+             [events]\n\
+             ubus = false\n\
+             jsonl = \"/tmp/sentry/events.jsonl\"\n\
+             [log]\n\
+             output = \"stderr\"\n\
+             redact_macs = true\n",
+        ).unwrap();
+        assert_eq!(load_from(&toml_path, &uci_path).unwrap(), expected());
+
+        for invalid in &["listen_port = \"many\"", "unknown = 1", "[firewall]\nchain = \"x\""] {
+            fs::write(&toml_path, invalid).unwrap();
+            assert!(load_from(&toml_path, &uci_path).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_uci() {
+        assert_eq!(
+            uci_words("  option jsonl '/tmp/a b' # comment").unwrap(),
+            vec!["option", "jsonl", "/tmp/a b"]
+        );
+        assert!(uci_words("option jsonl '/tmp").is_err());
+
+        let value = parse_uci("config sentry\n list zones 'a'\n list zones 'b'\n").unwrap();
+        let mut zones = BTreeMap::new();
+        zones.insert("zones".to_owned(), UciValue::List(vec!["a".to_owned(), "b".to_owned()]));
+        assert_eq!(value, UciValue::Section(zones));
+
+        assert!(parse_uci("config sentry\n option\n").is_err());
+        assert!(parse_uci("option firewall 'x'\nconfig firewall\n option backend 'x'\n").is_err());
+    }
+
+    #[test]
+    fn test_uci_coercion() {
+        let uci = |text: &str| Settings::deserialize(parse_uci(text).unwrap());
+
+        // strings stay strings even if they look like numbers or booleans
+        let settings = uci(
+            "config sentry\n option genesis_path '1'\n option listen_port '9444'\n\
+             config firewall\n option nftables_chain 'true'\n\
+             config events\n option ubus '1'\n option carrier 'yes'\n\
+             config log\n option redact_macs 'off'\n",
+        ).unwrap();
+        assert_eq!(settings.genesis_path, "1");
+        assert_eq!(settings.listen_port, 9444);
+        assert_eq!(settings.firewall.nftables_chain, "true");
+        assert!(settings.events.ubus);
+        assert!(settings.events.carrier);
+        assert!(!settings.log.redact_macs);
+
+        for invalid in &[
+            "config sentry\n option listen_port 'many'\n",
+            "config events\n option ubus 'maybe'\n",
+            "config firewall\n option backend 'pf'\n",
+            "config sentry\n list listen_port '1'\n",
+        ] {
+            assert!(uci(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Settings::default().validate().is_ok());
+
+        let invalid = vec![
+            Settings { listen_port: 0, ..Settings::default() },
+            Settings { tls_listen_port: 8444, ..Settings::default() },
+            Settings { tls_certificate: Some("/etc/sentry/api.pem".to_owned()), ..Settings::default() },
+            Settings { api_address: "localhost".to_owned(), ..Settings::default() },
+            Settings { api_address: "0.0.0.0:8445".to_owned(), ..Settings::default() },
+            Settings { api_address: "192.168.8.1:8445".to_owned(), ..Settings::default() },
+            Settings { secret_length: 4, ..Settings::default() },
+            Settings { reconcile_interval: 0, ..Settings::default() },
+            Settings { timezone: "Mars/Olympus".to_owned(), ..Settings::default() },
+            Settings {
+                log: logging::Config { level: "loud".to_owned(), ..logging::Config::default() },
+                ..Settings::default()
+            },
+            Settings {
+                firewall: firewall::Config {
+                    iptables_chain: "prerouting_public_rule".to_owned(),
+                    ..firewall::Config::default()
+                },
+                ..Settings::default()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?}", settings);
+        }
+    }
+
+    #[test]
+    fn test_layer() {
+        let settings = expected();
+        let captif: Captif = serde_json::from_value(json!({
+            "url": "http://portal.example.com/",
+            "firewall": "iptables",
+            "failure_policy": "closed",
+        })).unwrap();
+
+        let layered = settings.layer(&captif);
+        assert_eq!(layered.firewall.backend, firewall::Backend::Iptables);
+        assert_eq!(layered.firewall.nftables_chain, "dstnat_{zone}_sentry");
+        assert_eq!(layered.failure_policy, FailurePolicy::Closed);
+        assert_eq!(layered.events, settings.events);
+        assert_eq!(layered.api_address, api::DEFAULT_API_ADDRESS);
+        assert_eq!(settings.bridge("lobby"), "br-lobby");
+    }
+}
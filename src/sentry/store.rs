@@ -0,0 +1,266 @@
+//! The session store, keeps the authorized clients across reboots and firewall reloads.
+//!
+//! Every change is written to a volatile copy in `/tmp`, which survives restarts of
+//! sentry and firewall reloads. To spare the flash, the copy in `/etc` is only
+//! updated every `FLUSH_INTERVAL_SECS`, so a reboot may lose the latest sessions.
+
+use errors::*;
+use sentry::config;
+use sentry::firewall::Rate;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::offset::Utc;
+
+use serde_json;
+
+pub const VOLATILE_PATH: &str = "/tmp/sentry/sessions.json";
+pub const PERSISTENT_PATH: &str = "/etc/sentry/sessions.json";
+const FLUSH_INTERVAL_SECS: i64 = 15 * 60;
+
+/// An authorized client.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Session {
+    pub mac: String,
+    pub ip: Option<String>,
+    pub hostname: Option<String>,
+    /// Unix timestamp of the authorization.
+    pub authorized: i64,
+    /// Unix timestamp the session expires at, `None` if it does not expire.
+    pub expires: Option<i64>,
+    /// The bytes the session may transfer, `None` if it is not limited.
+    #[serde(default)]
+    pub quota: Option<u64>,
+    /// The traffic of the client during the session, up and down.
+    #[serde(default)]
+    pub packets: u64,
+    #[serde(default)]
+    pub bytes: u64,
+    #[serde(default)]
+    pub rate: Rate,
+    /// The zone the client was authorized in, `None` for sessions of releases
+    /// without zones.
+    #[serde(default)]
+    pub zone: Option<String>,
+}
+
+impl Session {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.map(|e| e < now).unwrap_or(false)
+    }
+
+    /// The zone the client is let through in, the default zone for sessions from
+    /// before zones.
+    pub fn zone_name(&self) -> &str {
+        self.zone.as_ref().map_or(config::DEFAULT_ZONE, |zone| zone.as_str())
+    }
+
+    /// The bytes left of the quota.
+    pub fn bytes_remaining(&self) -> Option<u64> {
+        self.quota.map(|q| q.saturating_sub(self.bytes))
+    }
+
+    pub fn is_over_quota(&self) -> bool {
+        self.bytes_remaining() == Some(0)
+    }
+
+    /// Whether the client may still pass, neither expired nor over its quota.
+    pub fn is_active(&self, now: i64) -> bool {
+        !self.is_expired(now) && !self.is_over_quota()
+    }
+}
+
+#[derive(Debug)]
+pub struct Store {
+    path: PathBuf,
+    persistent_path: PathBuf,
+    last_flush: i64,
+    dirty: bool,
+    sessions: BTreeMap<String, Session>,
+    /// The number of sessions removed since the store was opened.
+    removals: u64,
+}
+
+fn key(mac: &str) -> String {
+    mac.to_lowercase()
+}
+
+fn load(path: &Path) -> Option<Vec<Session>> {
+    File::open(path)
+        .ok()
+        .and_then(|f| serde_json::from_reader(f).ok())
+}
+
+fn write(path: &Path, sessions: &BTreeMap<String, Session>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).chain_err(|| format!("unable to create {:?}", dir))?;
+    }
+
+    let sessions: Vec<&Session> = sessions.values().collect();
+    let data = serde_json::to_vec(&sessions).chain_err(|| "unable to serialize the sessions")?;
+
+    // write and rename, so a crash never leaves a half written store behind
+    let tmp = path.with_extension("json.tmp");
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(&data).and_then(|_| f.sync_all()))
+        .chain_err(|| format!("unable to write {:?}", tmp))?;
+    fs::rename(&tmp, path).chain_err(|| format!("unable to replace {:?}", path))
+}
+
+impl Store {
+    /// Opens the store, the volatile copy wins as it can only be newer.
+    pub fn open<P: Into<PathBuf>, Q: Into<PathBuf>>(path: P, persistent_path: Q) -> Store {
+        let path = path.into();
+        let persistent_path = persistent_path.into();
+
+        let sessions = load(&path)
+            .or_else(|| load(&persistent_path))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|s| (key(&s.mac), s))
+            .collect();
+
+        Store {
+            path: path,
+            persistent_path: persistent_path,
+            last_flush: Utc::now().timestamp(),
+            dirty: false,
+            sessions: sessions,
+            removals: 0,
+        }
+    }
+
+    /// Counts the removed sessions, tells whether sessions were removed while the store
+    /// was not locked.
+    pub fn removals(&self) -> u64 {
+        self.removals
+    }
+
+    pub fn get(&self, mac: &str) -> Option<&Session> {
+        self.sessions.get(&key(mac))
+    }
+
+    pub fn sessions(&self) -> Vec<Session> {
+        self.sessions.values().cloned().collect()
+    }
+
+    /// Adds or replaces the session of a client.
+    pub fn insert(&mut self, session: Session) -> Result<()> {
+        self.sessions.insert(key(&session.mac), session);
+        self.save()
+    }
+
+    pub fn remove(&mut self, mac: &str) -> Result<()> {
+        if self.sessions.remove(&key(mac)).is_some() {
+            self.removals += 1;
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn save(&mut self) -> Result<()> {
+        self.dirty = true;
+        write(&self.path, &self.sessions)
+    }
+
+    /// Writes the persistent copy.
+    pub fn flush(&mut self) -> Result<()> {
+        write(&self.persistent_path, &self.sessions)?;
+        self.dirty = false;
+        self.last_flush = Utc::now().timestamp();
+        Ok(())
+    }
+
+    /// Writes the persistent copy, if it is outdated and was not written recently.
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        if self.dirty && self.last_flush + FLUSH_INTERVAL_SECS <= Utc::now().timestamp() {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    fn session(mac: &str) -> Session {
+        Session {
+            mac: mac.to_owned(),
+            ip: Some("192.168.44.200".to_owned()),
+            hostname: Some("nixos".to_owned()),
+            authorized: 1000,
+            expires: Some(4600),
+            quota: None,
+            packets: 0,
+            bytes: 0,
+            rate: Default::default(),
+            zone: None,
+        }
+    }
+
+    #[test]
+    fn test_store_roundtrip() {
+        let dir = TempDir::new("store").unwrap();
+        let path = dir.path().join("tmp/sessions.json");
+        let persistent_path = dir.path().join("etc/sessions.json");
+
+        let mut store = Store::open(&path, &persistent_path);
+        store.insert(session("DE:AD:BE:EF:00:11")).unwrap();
+        store.insert(session("DE:AD:BE:EF:00:22")).unwrap();
+        store.remove("de:ad:be:ef:00:22").unwrap();
+        store.remove("de:ad:be:ef:00:33").unwrap();
+        assert_eq!(store.removals(), 1);
+
+        assert!(!persistent_path.exists());
+
+        let store = Store::open(&path, &persistent_path);
+        assert_eq!(store.sessions(), vec![session("DE:AD:BE:EF:00:11")]);
+        assert!(store.get("de:ad:be:ef:00:11").is_some());
+    }
+
+    #[test]
+    fn test_store_persistent_after_reboot() {
+        let dir = TempDir::new("store").unwrap();
+        let path = dir.path().join("tmp/sessions.json");
+        let persistent_path = dir.path().join("etc/sessions.json");
+
+        let mut store = Store::open(&path, &persistent_path);
+        store.insert(session("DE:AD:BE:EF:00:11")).unwrap();
+        store.flush().unwrap();
+
+        // a reboot clears /tmp
+        fs::remove_file(&path).unwrap();
+
+        let store = Store::open(&path, &persistent_path);
+        assert_eq!(store.sessions(), vec![session("DE:AD:BE:EF:00:11")]);
+    }
+
+    #[test]
+    fn test_session_expired() {
+        let mut session = session("DE:AD:BE:EF:00:11");
+        assert!(!session.is_expired(4600));
+        assert!(session.is_expired(4601));
+
+        session.expires = None;
+        assert!(!session.is_expired(4601));
+    }
+
+    #[test]
+    fn test_session_active() {
+        let mut session = session("DE:AD:BE:EF:00:11");
+        assert!(session.is_active(4600));
+        assert!(!session.is_active(4601));
+
+        session.quota = Some(1000);
+        session.bytes = 1000;
+        assert!(!session.is_active(4600));
+    }
+}
@@ -0,0 +1,510 @@
+//! Handles the https connections of unauthorized clients, the firewall backends redirect
+//! them to the `tls_listen_port`.
+//!
+//! Clients whose first request is https would otherwise wait for a timeout before
+//! the os probes for the captive portal. Depending on the `tls` mode of the captif
+//! config, connections are either reset right after the client hello, so the os
+//! falls back to its plain http probe quickly, or accepted with a self-signed
+//! certificate and answered like plain http requests.
+//!
+//! Connections for the host of the captive portal api are accepted with the certificate
+//! of the settings in either mode, the api is answered like on the captive listener.
+
+use errors::*;
+use sentry::service::Service;
+use sentry::sentry::Sentry;
+
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::server::Http;
+
+use futures::{Future, Poll};
+use futures::future::{self, Either};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use openssl::x509::{X509, X509NameBuilder};
+use tokio_openssl::SslAcceptorExt;
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::read_exact;
+
+use rand::{self, Rng};
+
+const CERTIFICATE_NAME: &str = "sentry captive portal";
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// The length of a tls record header, the length of the record are its last two bytes.
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const TLS_HANDSHAKE: u8 = 22;
+const TLS_CLIENT_HELLO: u8 = 1;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0;
+const TLS_SERVER_NAME_HOST: u8 = 0;
+
+/// How https connections of unauthorized clients are handled.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Reset the connection after the client hello.
+    Reset,
+    /// Complete the handshake with a self-signed certificate and redirect.
+    Certificate,
+}
+
+impl Default for TlsMode {
+    fn default() -> TlsMode {
+        TlsMode::Reset
+    }
+}
+
+/// A bounds checked reader over a tls message.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    /// Reads a vector prefixed by its 8 or 16 bit length.
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.bytes(len).map(|data| Reader { data })
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.bytes(len).map(|data| Reader { data })
+    }
+}
+
+/// Extracts the server name from the tls record carrying a client hello.
+pub fn parse_sni(record: &[u8]) -> Option<String> {
+    let mut record = Reader { data: record };
+    if record.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    record.bytes(2)?; // record version
+    let mut record = record.vec16()?;
+
+    if record.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let len = record.u24()?;
+    let mut hello = Reader { data: record.bytes(len)? };
+
+    hello.bytes(2 + 32)?; // version and random
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+
+    let mut extensions = hello.vec16()?;
+    while let Some(kind) = extensions.u16() {
+        let mut extension = extensions.vec16()?;
+        if kind != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = extension.vec16()?;
+        while let Some(kind) = names.u8() {
+            let name = names.vec16()?;
+            if kind == TLS_SERVER_NAME_HOST {
+                return String::from_utf8(name.data.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+fn self_signed_certificate() -> ::std::result::Result<(X509, PKey<Private>), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, CERTIFICATE_NAME)?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = BigNum::from_u32(rand::thread_rng().gen())?.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(365)?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    Ok((builder.build(), key))
+}
+
+fn acceptor() -> ::std::result::Result<SslAcceptor, ErrorStack> {
+    let (certificate, key) = self_signed_certificate()?;
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    acceptor.set_private_key(&key)?;
+    acceptor.set_certificate(&certificate)?;
+    Ok(acceptor.build())
+}
+
+/// The acceptor with the certificate chain and key of the given pem files.
+fn file_acceptor(certificate: &str, key: &str) -> ::std::result::Result<SslAcceptor, ErrorStack> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    acceptor.set_certificate_chain_file(certificate)?;
+    acceptor.set_private_key_file(key, SslFiletype::PEM)?;
+    acceptor.check_private_key()?;
+    Ok(acceptor.build())
+}
+
+/// Reads the tls record of the client hello, or the first bytes if they are no handshake.
+fn read_hello(socket: TcpStream) -> Box<Future<Item = (TcpStream, Vec<u8>), Error = io::Error>> {
+    Box::new(
+        read_exact(socket, vec![0; TLS_RECORD_HEADER_LEN]).and_then(|(socket, header)| {
+            if header[0] != TLS_HANDSHAKE {
+                return Either::A(future::ok((socket, header)));
+            }
+
+            let len = (header[3] as usize) << 8 | header[4] as usize;
+            Either::B(read_exact(socket, vec![0; len]).map(|(socket, body)| {
+                let mut record = header;
+                record.extend(body);
+                (socket, record)
+            }))
+        }),
+    )
+}
+
+/// A connection with the bytes already read from it put back in front.
+struct Replay {
+    read: Cursor<Vec<u8>>,
+    socket: TcpStream,
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read.position() < self.read.get_ref().len() as u64 {
+            self.read.read(buf)
+        } else {
+            self.socket.read(buf)
+        }
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl AsyncRead for Replay {}
+
+impl AsyncWrite for Replay {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.socket)
+    }
+}
+
+/// Accepts the https connections of the captive listener.
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: SslAcceptor,
+    /// The acceptor for the captive portal api, if there is a certificate for it.
+    api_acceptor: Option<SslAcceptor>,
+    sentry: Sentry,
+    handle: Handle,
+}
+
+impl Tls {
+    /// Creates the acceptor with a new self-signed certificate, and the one of the
+    /// captive portal api with the certificate of the settings.
+    pub fn new(sentry: Sentry, handle: Handle) -> Result<Tls> {
+        let api_acceptor = match (&sentry.settings.tls_certificate, &sentry.settings.tls_key) {
+            (&Some(ref certificate), &Some(ref key)) => Some(
+                file_acceptor(certificate, key)
+                    .chain_err(|| format!("unable to load the tls certificate {}", certificate))?,
+            ),
+            _ => None,
+        };
+
+        Ok(Tls {
+            acceptor: acceptor().chain_err(|| "unable to create the tls certificate")?,
+            api_acceptor,
+            sentry,
+            handle,
+        })
+    }
+
+    /// Fails with `TimedOut` if `f` does not complete within the handshake timeout.
+    fn with_timeout<F>(&self, f: F) -> Box<Future<Item = (), Error = io::Error>>
+    where
+        F: Future<Item = (), Error = io::Error> + 'static,
+    {
+        let timeout = match Timeout::new(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), &self.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(f.select2(timeout).then(|result| match result {
+            Ok(Either::A(_)) => Ok(()),
+            Ok(Either::B(_)) => Err(io::ErrorKind::TimedOut.into()),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        }))
+    }
+
+    /// Resets the connection, its client hello was read already.
+    fn reset(&self, socket: &TcpStream, server_name: Option<&str>) {
+        if let Some(sni) = server_name {
+            if self.sentry.portal().walled_garden.allows_host(sni) {
+                debug!("https to walled garden host {} reached sentry", sni);
+            }
+        }
+
+        // closing with a zero linger sends a reset instead of a fin
+        socket.set_linger(Some(Duration::from_secs(0))).ok();
+    }
+
+    /// Completes the handshake and serves the connection like plain http.
+    fn accept(
+        &self,
+        acceptor: &SslAcceptor,
+        socket: Replay,
+        addr: SocketAddr,
+    ) -> Box<Future<Item = (), Error = io::Error>> {
+        let sentry = self.sentry.clone();
+        let handle = self.handle.clone();
+
+        Box::new(
+            acceptor
+                .accept_async(socket)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+                .map(move |stream| {
+                    Http::new().keep_alive(false).bind_connection(
+                        &handle,
+                        stream,
+                        addr,
+                        Service::new(sentry, true),
+                    );
+                }),
+        )
+    }
+
+    /// Handles a connection of the https listener, by the server name the client asks
+    /// for and the current tls mode.
+    pub fn handle(&self, socket: TcpStream, addr: SocketAddr) {
+        let tls = self.clone();
+        let connection = read_hello(socket).and_then(move |(socket, hello)| {
+            let server_name = parse_sni(&hello);
+            let portal = tls.sentry.portal();
+            let acceptor = match tls.api_acceptor {
+                Some(ref acceptor)
+                    if server_name.is_some() && server_name == portal.captive_api_host() =>
+                {
+                    acceptor
+                }
+                _ if portal.tls == TlsMode::Certificate => &tls.acceptor,
+                _ => {
+                    tls.reset(&socket, server_name.as_ref().map(|s| s.as_str()));
+                    return Either::A(future::ok(()));
+                }
+            };
+
+            let socket = Replay {
+                read: Cursor::new(hello),
+                socket,
+            };
+            Either::B(tls.accept(acceptor, socket, addr))
+        });
+
+        self.handle
+            .spawn(self.with_timeout(connection).map_err(|_| ()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::sentry::testing;
+    use sentry::settings::Settings;
+
+    use std::io::{Read, Write};
+    use std::fs;
+    use std::net;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    use futures::Stream;
+    use futures::sync::oneshot;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use tempdir::TempDir;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    /// Runs a tls listener in `mode` and calls `client` with its address.
+    fn with_listener<F, T>(mode: TlsMode, client: F) -> T
+    where
+        F: FnOnce(SocketAddr) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        with_sentry(mode, |_, _| (), client)
+    }
+
+    /// Like `with_listener`, `configure` adapts the sentry of the listener, given its
+    /// temporary directory.
+    fn with_sentry<C, F, T>(mode: TlsMode, configure: C, client: F) -> T
+    where
+        C: FnOnce(&mut Sentry, &Path),
+        F: FnOnce(SocketAddr) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let dir = TempDir::new("tls").unwrap();
+        let mut core = Core::new().unwrap();
+        let mut sentry = testing::sentry(&core, dir.path());
+        configure(&mut sentry, dir.path());
+        let mut portal = sentry.portal();
+        portal.tls = mode;
+        sentry.set_portal(portal);
+
+        let tls = Tls::new(sentry, core.handle()).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        core.handle().spawn(
+            listener
+                .incoming()
+                .for_each(move |(socket, addr)| {
+                    tls.handle(socket, addr);
+                    Ok(())
+                })
+                .map_err(|_| ()),
+        );
+
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || tx.send(client(addr)).ok());
+        core.run(rx).unwrap()
+    }
+
+    fn connector() -> SslConnector {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.build()
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        // capture the client hello of a real tls client
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            connector().connect("captive.example.com", stream).ok();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hello = vec![0; 4096];
+        let len = stream.read(&mut hello).unwrap();
+
+        assert_eq!(parse_sni(&hello[..len]), Some("captive.example.com".to_owned()));
+        assert_eq!(parse_sni(&hello[..len / 2]), None);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let result = with_listener(TlsMode::Reset, |addr| {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            connector().connect("captive.example.com", stream).map(|_| ())
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_certificate_redirect() {
+        let response = with_listener(TlsMode::Certificate, |addr| {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            let mut stream = connector().connect("captive.example.com", stream).unwrap();
+
+            stream
+                .write_all(b"GET /generate_204 HTTP/1.1\r\nHost: captive.example.com\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).ok();
+            response
+        });
+
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"), "{}", response);
+        assert!(response.contains("Location: http://portal.example.com/"), "{}", response);
+    }
+
+    #[test]
+    fn test_captive_api() {
+        let configure = |sentry: &mut Sentry, dir: &Path| {
+            let (certificate, key) = self_signed_certificate().unwrap();
+            let certificate_path = dir.join("api.pem");
+            let key_path = dir.join("api.key");
+            fs::write(&certificate_path, certificate.to_pem().unwrap()).unwrap();
+            fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+            sentry.settings = Arc::new(Settings {
+                tls_certificate: Some(certificate_path.to_str().unwrap().to_owned()),
+                tls_key: Some(key_path.to_str().unwrap().to_owned()),
+                ..Settings::default()
+            });
+            let mut portal = sentry.portal();
+            portal.captive_api_url =
+                Some("https://captive.example.com:8443/captive-portal/api".to_owned());
+            sentry.set_portal(portal);
+        };
+
+        // answered even though other connections are reset
+        let response = with_sentry(TlsMode::Reset, configure, |addr| {
+            let stream = net::TcpStream::connect(addr).unwrap();
+            let mut stream = connector().connect("captive.example.com", stream).unwrap();
+
+            stream
+                .write_all(b"GET /captive-portal/api HTTP/1.1\r\nHost: captive.example.com\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).ok();
+            response
+        });
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("application/captive+json"), "{}", response);
+        assert!(response.contains("\"captive\":true"), "{}", response);
+    }
+}
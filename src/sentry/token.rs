@@ -0,0 +1,280 @@
+//! Signed authorization tokens issued by the portal.
+//!
+//! A token is `<claims>.<signature>`, both base64url encoded without padding.
+//! The claims are a JSON object binding the token to a device, a client and a
+//! point in time. The signature is an HMAC-SHA256 over the encoded claims, keyed
+//! with the device key `HMAC-SHA256(shared key, identity)`, so a token issued for
+//! one device is worthless on every other device.
+
+use errors::*;
+use sentry::firewall::Rate;
+use sentry::ip;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use base64;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use serde_json;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a token may be valid for at most, its nonce is kept that long.
+const MAX_TOKEN_LIFETIME: i64 = 3600;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Claims {
+    /// The carrier identity of the device the token was issued for.
+    pub identity: String,
+    pub mac: String,
+    pub ip: String,
+    /// Unix timestamp the token expires at, at most `MAX_TOKEN_LIFETIME` seconds ahead.
+    pub exp: i64,
+    /// A unique value, every token is accepted once.
+    pub nonce: String,
+    /// The session length in seconds, the configured `expires` if missing.
+    #[serde(default)]
+    pub duration: Option<u32>,
+    /// The data quota in bytes, the configured `data_quota` if missing.
+    #[serde(default)]
+    pub quota: Option<u64>,
+    /// The rate limits, the configured `rate` for the limits missing.
+    #[serde(default)]
+    pub rate: Rate,
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(key).expect("hmac accepts keys of any length");
+    mac.input(data);
+    mac
+}
+
+/// Derives the key the tokens of the device with the given identity are signed with.
+pub fn device_key(shared_key: &str, identity: &str) -> Vec<u8> {
+    hmac(shared_key.as_bytes(), identity.as_bytes())
+        .result()
+        .code()
+        .to_vec()
+}
+
+/// Whether the two addresses are the same, ipv4-mapped ipv6 addresses are the same as
+/// their ipv4 address.
+fn same_ip(a: &str, b: &str) -> bool {
+    match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+        (Ok(a), Ok(b)) => ip::normalize(a) == ip::normalize(b),
+        _ => false,
+    }
+}
+
+/// Creates a token, the portal side of `Verifier::verify`.
+#[cfg(test)]
+pub fn sign(device_key: &[u8], claims: &Claims) -> String {
+    let claims = base64::encode_config(
+        &serde_json::to_vec(claims).unwrap(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let signature = hmac(device_key, claims.as_bytes()).result().code();
+
+    format!(
+        "{}.{}",
+        claims,
+        base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+pub struct Verifier {
+    key: Vec<u8>,
+    identity: String,
+    /// The nonces of used tokens, kept until the tokens expire.
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Verifier")
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl Verifier {
+    pub fn new(shared_key: &str, identity: &str) -> Verifier {
+        Verifier {
+            key: device_key(shared_key, identity),
+            identity: identity.to_owned(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks signature, binding and freshness of a token presented by the client
+    /// with the given mac and ip address. The token stays valid until it is used, see
+    /// `Verifier::consume`.
+    pub fn verify(&self, token: &str, mac: &str, ip: &str, now: i64) -> Result<Claims> {
+        let mut parts = token.splitn(2, '.');
+        let (claims, signature) = match (parts.next(), parts.next()) {
+            (Some(claims), Some(signature)) => (claims, signature),
+            _ => bail!("malformed token"),
+        };
+
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .chain_err(|| "malformed token signature")?;
+        if hmac(&self.key, claims.as_bytes()).verify(&signature).is_err() {
+            bail!("invalid token signature");
+        }
+
+        let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD)
+            .chain_err(|| "malformed token claims")?;
+        let claims: Claims =
+            serde_json::from_slice(&claims).chain_err(|| "malformed token claims")?;
+
+        if claims.identity != self.identity {
+            bail!("token was issued for another device");
+        }
+        if claims.exp < now {
+            bail!("token expired");
+        }
+        if claims.exp - now > MAX_TOKEN_LIFETIME {
+            bail!("token is valid for longer than {} seconds", MAX_TOKEN_LIFETIME);
+        }
+        if !claims.mac.eq_ignore_ascii_case(mac) || !same_ip(&claims.ip, ip) {
+            bail!("token was issued for another client");
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, exp| *exp >= now);
+        if seen.contains_key(&claims.nonce) {
+            bail!("token was already used");
+        }
+
+        Ok(claims)
+    }
+
+    /// Marks the token with the given claims as used, once its client is authorized.
+    pub fn consume(&self, claims: &Claims) {
+        self.seen
+            .lock()
+            .unwrap()
+            .insert(claims.nonce.clone(), claims.exp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARED_KEY: &str = "shared key";
+    const IDENTITY: &str = "oWv9dKS8ZW3AVQjfx6sJQQMuVTm8hD8MhfnzY7yLeDWd4eKH";
+    const MAC: &str = "DE:AD:BE:EF:00:11";
+    const IP: &str = "192.168.44.200";
+    const NOW: i64 = 1_500_000_000;
+
+    fn claims() -> Claims {
+        Claims {
+            identity: IDENTITY.to_owned(),
+            mac: MAC.to_owned(),
+            ip: IP.to_owned(),
+            exp: NOW + 60,
+            nonce: "bm9uY2U".to_owned(),
+            duration: Some(3600),
+            quota: None,
+            rate: Rate::default(),
+        }
+    }
+
+    fn token(claims: &Claims) -> String {
+        sign(&device_key(SHARED_KEY, IDENTITY), claims)
+    }
+
+    #[test]
+    fn test_verify() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+
+        assert_eq!(
+            verifier
+                .verify(&token(&claims()), "de:ad:be:ef:00:11", IP, NOW)
+                .unwrap(),
+            claims()
+        );
+    }
+
+    #[test]
+    fn test_verify_replay() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+        let token = token(&claims());
+
+        // a failed authorization leaves the token usable
+        let claims = verifier.verify(&token, MAC, IP, NOW).unwrap();
+        assert!(verifier.verify(&token, MAC, IP, NOW).is_ok());
+
+        verifier.consume(&claims);
+        assert!(verifier.verify(&token, MAC, IP, NOW).is_err());
+    }
+
+    #[test]
+    fn test_verify_lifetime() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+        let expiring = |exp| token(&Claims { exp: exp, ..claims() });
+
+        assert!(verifier.verify(&expiring(NOW + MAX_TOKEN_LIFETIME), MAC, IP, NOW).is_ok());
+        assert!(verifier.verify(&expiring(NOW + MAX_TOKEN_LIFETIME + 1), MAC, IP, NOW).is_err());
+        assert!(verifier.verify(&expiring(i64::max_value()), MAC, IP, NOW).is_err());
+    }
+
+    #[test]
+    fn test_verify_wrong_key() {
+        let verifier = Verifier::new("other key", IDENTITY);
+        assert!(verifier.verify(&token(&claims()), MAC, IP, NOW).is_err());
+    }
+
+    #[test]
+    fn test_verify_wrong_device() {
+        let verifier = Verifier::new(SHARED_KEY, "other identity");
+        let token = sign(&device_key(SHARED_KEY, "other identity"), &claims());
+
+        assert!(verifier.verify(&token, MAC, IP, NOW).is_err());
+    }
+
+    #[test]
+    fn test_verify_wrong_client() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+        let token = token(&claims());
+
+        assert!(verifier.verify(&token, "DE:AD:BE:EF:00:22", IP, NOW).is_err());
+        assert!(verifier.verify(&token, MAC, "192.168.44.201", NOW).is_err());
+        assert!(verifier.verify(&token, MAC, "not an ip", NOW).is_err());
+
+        // as seen by a listener on [::]
+        assert!(verifier.verify(&token, MAC, "::ffff:192.168.44.200", NOW).is_ok());
+        let v6 = |ip: &str| self::token(&Claims { ip: ip.to_owned(), ..claims() });
+        assert!(verifier.verify(&v6("2001:DB8::2C1"), MAC, "2001:db8::2c1", NOW).is_ok());
+    }
+
+    #[test]
+    fn test_verify_expired() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+        assert!(verifier.verify(&token(&claims()), MAC, IP, NOW + 61).is_err());
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let verifier = Verifier::new(SHARED_KEY, IDENTITY);
+        let token = token(&claims());
+
+        let mut claims = claims();
+        claims.duration = Some(86400 * 365);
+        let forged_claims = base64::encode_config(
+            &serde_json::to_vec(&claims).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let signature = token.splitn(2, '.').nth(1).unwrap();
+
+        assert!(verifier
+            .verify(&format!("{}.{}", forged_claims, signature), MAC, IP, NOW)
+            .is_err());
+        assert!(verifier.verify("garbage", MAC, IP, NOW).is_err());
+    }
+}
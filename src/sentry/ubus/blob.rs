@@ -0,0 +1,280 @@
+//! The blob and blobmsg encoding of libubox, as spoken on the ubus socket.
+//!
+//! A blob attribute is a 32 bit big endian header (extended flag, 7 bit id,
+//! 24 bit length including the header) followed by its payload, padded to 4 bytes.
+//! blobmsg attributes are extended attributes whose payload starts with a name.
+
+use errors::*;
+
+use std::str;
+
+use serde_json::{Map, Number, Value};
+
+const BLOB_ATTR_EXTENDED: u32 = 0x8000_0000;
+const BLOB_ATTR_ID_MASK: u32 = 0x7f00_0000;
+const BLOB_ATTR_ID_SHIFT: u32 = 24;
+const BLOB_ATTR_LEN_MASK: u32 = 0x00ff_ffff;
+
+const BLOBMSG_TYPE_UNSPEC: u8 = 0;
+const BLOBMSG_TYPE_ARRAY: u8 = 1;
+const BLOBMSG_TYPE_TABLE: u8 = 2;
+pub const BLOBMSG_TYPE_STRING: u8 = 3;
+pub const BLOBMSG_TYPE_INT64: u8 = 4;
+pub const BLOBMSG_TYPE_INT32: u8 = 5;
+const BLOBMSG_TYPE_INT16: u8 = 6;
+const BLOBMSG_TYPE_INT8: u8 = 7;
+const BLOBMSG_TYPE_DOUBLE: u8 = 8;
+const BLOBMSG_TYPE_BOOL: u8 = BLOBMSG_TYPE_INT8;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let len = align(buf.len());
+    buf.resize(len, 0);
+}
+
+/// A single attribute, borrowed from the buffer it was parsed from.
+#[derive(Debug, PartialEq)]
+pub struct Attr<'a> {
+    pub id: u8,
+    pub extended: bool,
+    pub data: &'a [u8],
+}
+
+impl<'a> Attr<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.data.len() < 4 {
+            return None;
+        }
+
+        Some(u32::from_be_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]))
+    }
+
+    pub fn as_u8(&self) -> Option<u8> {
+        self.data.first().cloned()
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        str::from_utf8(self.data).ok().map(|s| s.trim_end_matches('\0'))
+    }
+}
+
+/// Returns the bytes of a blob attribute header for a payload of `len` bytes.
+pub fn attr_header(id: u8, extended: bool, len: usize) -> [u8; 4] {
+    let mut id_len = ((u32::from(id) << BLOB_ATTR_ID_SHIFT) & BLOB_ATTR_ID_MASK)
+        | ((len as u32 + 4) & BLOB_ATTR_LEN_MASK);
+
+    if extended {
+        id_len |= BLOB_ATTR_EXTENDED;
+    }
+
+    id_len.to_be_bytes()
+}
+
+/// Appends an attribute with the given id and payload to `buf`.
+pub fn put_attr(buf: &mut Vec<u8>, id: u8, extended: bool, data: &[u8]) {
+    buf.extend_from_slice(&attr_header(id, extended, data.len()));
+    buf.extend_from_slice(data);
+    pad(buf);
+}
+
+/// Splits a buffer into its consecutive attributes.
+pub fn parse_attrs(mut buf: &[u8]) -> Result<Vec<Attr>> {
+    let mut attrs = Vec::new();
+
+    while buf.len() >= 4 {
+        let id_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let len = (id_len & BLOB_ATTR_LEN_MASK) as usize;
+
+        if len < 4 || len > buf.len() {
+            bail!("invalid blob attribute length: {}", len);
+        }
+
+        attrs.push(Attr {
+            id: ((id_len & BLOB_ATTR_ID_MASK) >> BLOB_ATTR_ID_SHIFT) as u8,
+            extended: id_len & BLOB_ATTR_EXTENDED != 0,
+            data: &buf[4..len],
+        });
+
+        let next = align(len);
+        buf = if next < buf.len() { &buf[next..] } else { &[] };
+    }
+
+    Ok(attrs)
+}
+
+fn put_blobmsg(buf: &mut Vec<u8>, msg_type: u8, name: &str, data: &[u8]) {
+    let mut payload = Vec::with_capacity(align(name.len() + 3) + data.len());
+    payload.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    payload.extend_from_slice(name.as_bytes());
+    payload.push(0);
+    pad(&mut payload);
+    payload.extend_from_slice(data);
+
+    put_attr(buf, msg_type, true, &payload);
+}
+
+/// Appends `value` as blobmsg attribute named `name`.
+pub fn put_value(buf: &mut Vec<u8>, name: &str, value: &Value) {
+    match *value {
+        Value::Null => put_blobmsg(buf, BLOBMSG_TYPE_UNSPEC, name, &[]),
+        Value::Bool(b) => put_blobmsg(buf, BLOBMSG_TYPE_BOOL, name, &[b as u8]),
+        Value::Number(ref n) => {
+            if let Some(i) = n.as_i64() {
+                if i >= i64::from(i32::min_value()) && i <= i64::from(i32::max_value()) {
+                    put_blobmsg(buf, BLOBMSG_TYPE_INT32, name, &(i as i32).to_be_bytes());
+                } else {
+                    put_blobmsg(buf, BLOBMSG_TYPE_INT64, name, &i.to_be_bytes());
+                }
+            } else {
+                let f = n.as_f64().unwrap_or_default();
+                put_blobmsg(buf, BLOBMSG_TYPE_DOUBLE, name, &f.to_bits().to_be_bytes());
+            }
+        }
+        Value::String(ref s) => {
+            let mut data = Vec::with_capacity(s.len() + 1);
+            data.extend_from_slice(s.as_bytes());
+            data.push(0);
+            put_blobmsg(buf, BLOBMSG_TYPE_STRING, name, &data);
+        }
+        Value::Array(ref values) => {
+            let mut data = Vec::new();
+            for value in values {
+                put_value(&mut data, "", value);
+            }
+            put_blobmsg(buf, BLOBMSG_TYPE_ARRAY, name, &data);
+        }
+        Value::Object(ref map) => put_blobmsg(buf, BLOBMSG_TYPE_TABLE, name, &encode_table(map)),
+    }
+}
+
+/// Encodes the content of a blobmsg table.
+pub fn encode_table(map: &Map<String, Value>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in map {
+        put_value(&mut buf, name, value);
+    }
+    buf
+}
+
+fn int_bytes(data: &[u8], len: usize) -> Result<&[u8]> {
+    if data.len() < len {
+        bail!("blobmsg integer too short");
+    }
+    Ok(&data[..len])
+}
+
+fn parse_blobmsg(attr: &Attr) -> Result<(String, Value)> {
+    if !attr.extended || attr.data.len() < 3 {
+        bail!("not a blobmsg attribute");
+    }
+
+    let name_len = u16::from_be_bytes([attr.data[0], attr.data[1]]) as usize;
+    let hdr_len = align(name_len + 3);
+    if attr.data.len() < hdr_len {
+        bail!("blobmsg name exceeds attribute");
+    }
+
+    let name = str::from_utf8(&attr.data[2..2 + name_len])
+        .chain_err(|| "blobmsg name is not utf8")?
+        .to_owned();
+    let data = &attr.data[hdr_len..];
+
+    let value = match attr.id {
+        BLOBMSG_TYPE_ARRAY => Value::Array(decode_array(data)?),
+        BLOBMSG_TYPE_TABLE => Value::Object(decode_table(data)?),
+        BLOBMSG_TYPE_STRING => Value::String(
+            str::from_utf8(data)
+                .chain_err(|| "blobmsg string is not utf8")?
+                .trim_end_matches('\0')
+                .to_owned(),
+        ),
+        BLOBMSG_TYPE_INT64 => {
+            let b = int_bytes(data, 8)?;
+            json!(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        }
+        BLOBMSG_TYPE_INT32 => {
+            let b = int_bytes(data, 4)?;
+            json!(i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        }
+        BLOBMSG_TYPE_INT16 => {
+            let b = int_bytes(data, 2)?;
+            json!(i16::from_be_bytes([b[0], b[1]]))
+        }
+        BLOBMSG_TYPE_INT8 => Value::Bool(int_bytes(data, 1)?[0] != 0),
+        BLOBMSG_TYPE_DOUBLE => {
+            let b = int_bytes(data, 8)?;
+            let bits = u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
+            Number::from_f64(f64::from_bits(bits))
+                .map(Value::Number)
+                .unwrap_or(Value::Null)
+        }
+        _ => Value::Null,
+    };
+
+    Ok((name, value))
+}
+
+/// Decodes the content of a blobmsg table.
+pub fn decode_table(buf: &[u8]) -> Result<Map<String, Value>> {
+    let mut map = Map::new();
+    for attr in parse_attrs(buf)? {
+        let (name, value) = parse_blobmsg(&attr)?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Decodes the content of a blobmsg array.
+pub fn decode_array(buf: &[u8]) -> Result<Vec<Value>> {
+    parse_attrs(buf)?
+        .iter()
+        .map(|attr| parse_blobmsg(attr).map(|(_, value)| value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_string() {
+        let mut buf = Vec::new();
+        put_value(&mut buf, "id", &json!("abc"));
+
+        assert_eq!(
+            buf,
+            vec![
+                0x83, 0, 0, 0x10, // extended, string, len 16
+                0, 2, b'i', b'd', 0, 0, 0, 0, // name "id", padded
+                b'a', b'b', b'c', 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_table() {
+        let value = json!({
+            "string": "hello",
+            "int": 42,
+            "negative": -1,
+            "big": 8589934592i64,
+            "bool": true,
+            "double": 1.5,
+            "array": ["a", 1, false],
+            "table": { "nested": "yes" },
+        });
+
+        let map = value.as_object().unwrap();
+        let decoded = decode_table(&encode_table(map)).unwrap();
+
+        assert_eq!(Value::Object(decoded), value);
+    }
+
+    #[test]
+    fn test_parse_attrs_invalid_length() {
+        assert!(parse_attrs(&[0, 0, 0, 0x20, 1, 2, 3, 4]).is_err());
+    }
+}
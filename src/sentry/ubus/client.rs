@@ -0,0 +1,361 @@
+//! A blocking client for the ubus socket protocol.
+//!
+//! Every message is an 8 byte header (version, type, sequence number, peer)
+//! followed by a single blob attribute holding the message attributes.
+
+use errors::*;
+use sentry::ubus::blob;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+pub const DEFAULT_SOCKET: &str = "/var/run/ubus.sock";
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+pub const UBUS_MSG_HELLO: u8 = 0;
+pub const UBUS_MSG_STATUS: u8 = 1;
+pub const UBUS_MSG_DATA: u8 = 2;
+pub const UBUS_MSG_LOOKUP: u8 = 4;
+pub const UBUS_MSG_INVOKE: u8 = 5;
+pub const UBUS_MSG_ADD_OBJECT: u8 = 6;
+pub const UBUS_MSG_SUBSCRIBE: u8 = 8;
+
+pub const UBUS_ATTR_STATUS: u8 = 1;
+pub const UBUS_ATTR_OBJPATH: u8 = 2;
+pub const UBUS_ATTR_OBJID: u8 = 3;
+pub const UBUS_ATTR_METHOD: u8 = 4;
+pub const UBUS_ATTR_SIGNATURE: u8 = 6;
+pub const UBUS_ATTR_DATA: u8 = 7;
+pub const UBUS_ATTR_TARGET: u8 = 8;
+pub const UBUS_ATTR_NO_REPLY: u8 = 10;
+
+pub const UBUS_STATUS_OK: u32 = 0;
+pub const UBUS_STATUS_INVALID_ARGUMENT: u32 = 2;
+pub const UBUS_STATUS_METHOD_NOT_FOUND: u32 = 3;
+pub const UBUS_STATUS_NOT_FOUND: u32 = 4;
+pub const UBUS_STATUS_UNKNOWN_ERROR: u32 = 9;
+
+/// The object id of ubusd's own event object.
+pub const UBUS_SYSTEM_OBJECT_EVENT: u32 = 1;
+
+fn status_str(status: u32) -> &'static str {
+    match status {
+        0 => "Success",
+        1 => "Invalid command",
+        2 => "Invalid argument",
+        3 => "Method not found",
+        4 => "Not found",
+        5 => "No response",
+        6 => "Permission denied",
+        7 => "Request timed out",
+        8 => "Operation not supported",
+        10 => "Connection failed",
+        _ => "Unknown error",
+    }
+}
+
+/// A single message on the ubus socket.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Message {
+    pub msg_type: u8,
+    pub seq: u16,
+    pub peer: u32,
+    /// The encoded message attributes.
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(msg_type: u8, seq: u16, peer: u32) -> Message {
+        Message {
+            msg_type: msg_type,
+            seq: seq,
+            peer: peer,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn put_u32(&mut self, id: u8, value: u32) {
+        blob::put_attr(&mut self.payload, id, false, &value.to_be_bytes());
+    }
+
+    pub fn put_u8(&mut self, id: u8, value: u8) {
+        blob::put_attr(&mut self.payload, id, false, &[value]);
+    }
+
+    pub fn put_string(&mut self, id: u8, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        blob::put_attr(&mut self.payload, id, false, &data);
+    }
+
+    pub fn put_table(&mut self, id: u8, value: &Map<String, Value>) {
+        blob::put_attr(&mut self.payload, id, false, &blob::encode_table(value));
+    }
+
+    fn attr<'a>(&'a self, id: u8) -> Option<blob::Attr<'a>> {
+        blob::parse_attrs(&self.payload)
+            .ok()
+            .and_then(|attrs| attrs.into_iter().find(|a| a.id == id))
+    }
+
+    pub fn get_u32(&self, id: u8) -> Option<u32> {
+        self.attr(id).and_then(|a| a.as_u32())
+    }
+
+    pub fn get_u8(&self, id: u8) -> Option<u8> {
+        self.attr(id).and_then(|a| a.as_u8())
+    }
+
+    pub fn get_string(&self, id: u8) -> Option<String> {
+        self.attr(id).and_then(|a| a.as_str().map(|s| s.to_owned()))
+    }
+
+    pub fn get_table(&self, id: u8) -> Option<Map<String, Value>> {
+        self.attr(id).and_then(|a| blob::decode_table(a.data).ok())
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = Vec::with_capacity(12 + self.payload.len());
+        buf.push(0);
+        buf.push(self.msg_type);
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.peer.to_be_bytes());
+        buf.extend_from_slice(&blob::attr_header(0, false, self.payload.len()));
+        buf.extend_from_slice(&self.payload);
+
+        w.write_all(&buf).chain_err(|| "error writing to ubus")
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Message> {
+        let mut hdr = [0u8; 12];
+        r.read_exact(&mut hdr).chain_err(|| "error reading from ubus")?;
+
+        let len = (u32::from_be_bytes([0, hdr[9], hdr[10], hdr[11]]) as usize)
+            .checked_sub(4)
+            .ok_or_else(|| Error::from("invalid ubus message length"))?;
+
+        let mut payload = vec![0u8; len];
+        r.read_exact(&mut payload).chain_err(|| "error reading from ubus")?;
+
+        Ok(Message {
+            msg_type: hdr[1],
+            seq: u16::from_be_bytes([hdr[2], hdr[3]]),
+            peer: u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]),
+            payload: payload,
+        })
+    }
+}
+
+/// A method call or notification addressed to one of our objects.
+#[derive(Clone, Debug)]
+pub struct Invocation {
+    pub seq: u16,
+    pub peer: u32,
+    pub object: u32,
+    pub method: String,
+    pub data: Map<String, Value>,
+    pub no_reply: bool,
+}
+
+#[derive(Debug)]
+pub struct Client {
+    stream: UnixStream,
+    id: u32,
+    seq: u16,
+    pending: VecDeque<Message>,
+}
+
+impl Client {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Client> {
+        let mut stream = UnixStream::connect(path).chain_err(|| "unable to connect to ubus")?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))
+            .chain_err(|| "unable to set the ubus read timeout")?;
+
+        let hello = Message::read_from(&mut stream)?;
+        if hello.msg_type != UBUS_MSG_HELLO {
+            bail!("expected ubus hello, got message type {}", hello.msg_type);
+        }
+
+        Ok(Client {
+            stream: stream,
+            id: hello.peer,
+            seq: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// The client id ubusd assigned to this connection.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Sends a request and collects the data replies up to the final status.
+    fn request(&mut self, mut msg: Message) -> Result<Vec<Message>> {
+        self.seq = self.seq.wrapping_add(1);
+        msg.seq = self.seq;
+        msg.write_to(&mut self.stream)?;
+
+        let mut replies = Vec::new();
+        loop {
+            let reply = Message::read_from(&mut self.stream)?;
+
+            if reply.seq != msg.seq
+                || (reply.msg_type != UBUS_MSG_DATA && reply.msg_type != UBUS_MSG_STATUS)
+            {
+                self.pending.push_back(reply);
+                continue;
+            }
+
+            if reply.msg_type == UBUS_MSG_DATA {
+                replies.push(reply);
+                continue;
+            }
+
+            let status = reply
+                .get_u32(UBUS_ATTR_STATUS)
+                .unwrap_or(UBUS_STATUS_UNKNOWN_ERROR);
+            if status != UBUS_STATUS_OK {
+                bail!("ubus request failed: {}", status_str(status));
+            }
+
+            return Ok(replies);
+        }
+    }
+
+    pub fn lookup_id(&mut self, path: &str) -> Result<u32> {
+        let mut msg = Message::new(UBUS_MSG_LOOKUP, 0, 0);
+        msg.put_string(UBUS_ATTR_OBJPATH, path);
+
+        self.request(msg)?
+            .iter()
+            .filter_map(|reply| reply.get_u32(UBUS_ATTR_OBJID))
+            .next()
+            .ok_or_else(|| format!("ubus object not found: {}", path).into())
+    }
+
+    pub fn invoke(&mut self, id: u32, method: &str, args: &Map<String, Value>) -> Result<Value> {
+        let mut msg = Message::new(UBUS_MSG_INVOKE, 0, id);
+        msg.put_u32(UBUS_ATTR_OBJID, id);
+        msg.put_string(UBUS_ATTR_METHOD, method);
+        msg.put_table(UBUS_ATTR_DATA, args);
+
+        Ok(self.request(msg)?
+            .iter()
+            .filter_map(|reply| reply.get_table(UBUS_ATTR_DATA))
+            .next()
+            .map(Value::Object)
+            .unwrap_or(Value::Null))
+    }
+
+    /// Same as `ubus call <path> <method> <args>`.
+    pub fn call(&mut self, path: &str, method: &str, args: &Map<String, Value>) -> Result<Value> {
+        let id = self.lookup_id(path)?;
+        self.invoke(id, method, args)
+    }
+
+    /// Same as `ubus send <event> <data>`.
+    pub fn send(&mut self, event: &str, data: &Map<String, Value>) -> Result<()> {
+        let mut args = Map::new();
+        args.insert("id".to_owned(), Value::String(event.to_owned()));
+        args.insert("data".to_owned(), Value::Object(data.clone()));
+
+        self.invoke(UBUS_SYSTEM_OBJECT_EVENT, "send", &args)
+            .map(|_| ())
+    }
+
+    /// Registers an object, anonymous objects (`path` is `None`) are used as subscribers.
+    ///
+    /// `signature` maps each method name to a table of argument names and blobmsg types.
+    pub fn add_object(&mut self, path: Option<&str>, signature: &Map<String, Value>) -> Result<u32> {
+        let mut msg = Message::new(UBUS_MSG_ADD_OBJECT, 0, 0);
+
+        if let Some(path) = path {
+            msg.put_string(UBUS_ATTR_OBJPATH, path);
+            msg.put_table(UBUS_ATTR_SIGNATURE, signature);
+        }
+
+        self.request(msg)?
+            .iter()
+            .filter_map(|reply| reply.get_u32(UBUS_ATTR_OBJID))
+            .next()
+            .ok_or_else(|| "ubus did not assign an object id".into())
+    }
+
+    /// Subscribes to the notifications of the object at `path`.
+    ///
+    /// Returns the id of the subscriber object, the notifications are received
+    /// with `next_invocation`.
+    pub fn subscribe(&mut self, path: &str) -> Result<u32> {
+        let target = self.lookup_id(path)?;
+        let subscriber = self.add_object(None, &Map::new())?;
+
+        let mut msg = Message::new(UBUS_MSG_SUBSCRIBE, 0, 0);
+        msg.put_u32(UBUS_ATTR_OBJID, subscriber);
+        msg.put_u32(UBUS_ATTR_TARGET, target);
+        self.request(msg)?;
+
+        Ok(subscriber)
+    }
+
+    /// Blocks until a method call or notification for one of our objects arrives.
+    pub fn next_invocation(&mut self) -> Result<Invocation> {
+        loop {
+            let msg = match self.pending.pop_front() {
+                Some(msg) => msg,
+                None => {
+                    self.stream
+                        .set_read_timeout(None)
+                        .chain_err(|| "unable to set the ubus read timeout")?;
+                    let msg = Message::read_from(&mut self.stream);
+                    self.stream
+                        .set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))
+                        .chain_err(|| "unable to set the ubus read timeout")?;
+                    msg?
+                }
+            };
+
+            if msg.msg_type != UBUS_MSG_INVOKE {
+                continue;
+            }
+
+            return Ok(Invocation {
+                seq: msg.seq,
+                peer: msg.peer,
+                object: msg.get_u32(UBUS_ATTR_OBJID).unwrap_or_default(),
+                method: msg.get_string(UBUS_ATTR_METHOD).unwrap_or_default(),
+                data: msg.get_table(UBUS_ATTR_DATA).unwrap_or_default(),
+                no_reply: msg.get_u8(UBUS_ATTR_NO_REPLY).unwrap_or(0) != 0,
+            });
+        }
+    }
+
+    /// Answers an invocation with optional data and a status code.
+    pub fn reply(
+        &mut self,
+        invocation: &Invocation,
+        data: Option<&Map<String, Value>>,
+        status: u32,
+    ) -> Result<()> {
+        if invocation.no_reply {
+            return Ok(());
+        }
+
+        if let Some(data) = data {
+            let mut msg = Message::new(UBUS_MSG_DATA, invocation.seq, invocation.peer);
+            msg.put_u32(UBUS_ATTR_OBJID, invocation.object);
+            msg.put_table(UBUS_ATTR_DATA, data);
+            msg.write_to(&mut self.stream)?;
+        }
+
+        let mut msg = Message::new(UBUS_MSG_STATUS, invocation.seq, invocation.peer);
+        msg.put_u32(UBUS_ATTR_STATUS, status);
+        msg.put_u32(UBUS_ATTR_OBJID, invocation.object);
+        msg.write_to(&mut self.stream)
+    }
+}
@@ -0,0 +1,226 @@
+mod blob;
+mod client;
+#[cfg(test)]
+pub mod testing;
+
+pub use self::blob::{BLOBMSG_TYPE_INT32, BLOBMSG_TYPE_INT64, BLOBMSG_TYPE_STRING};
+pub use self::client::{Client, DEFAULT_SOCKET};
+pub use self::client::{UBUS_STATUS_INVALID_ARGUMENT, UBUS_STATUS_METHOD_NOT_FOUND,
+                       UBUS_STATUS_OK, UBUS_STATUS_UNKNOWN_ERROR};
+
+use errors::*;
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+fn get_ipleases(client: &mut Client, leases: &str) -> Option<Value> {
+    client.call("dhcp", leases, &Map::new()).ok()
+}
+
+/// The leases of all devices, the clients of every zone.
+fn parse_ipleases(output: &Value) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = Vec::new();
+
+    let devices = output["device"].as_object().into_iter().flat_map(|d| d.values());
+    for leases in devices.filter_map(|device| device["leases"].as_array()) {
+        for lease in leases {
+            if let Some(hostname) = lease["hostname"].as_str() {
+                if let Some(ip) = lease["ip"].as_str() {
+                    result.push((ip.to_owned(), hostname.to_owned()))
+                }
+
+                // dhcpv6 leases may carry several addresses
+                for addr in lease["ipv6-addr"].as_array().into_iter().flatten() {
+                    if let Some(ip) = addr["address"].as_str() {
+                        result.push((ip.to_owned(), hostname.to_owned()))
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn hostname_for_ip(client: &mut Client, ip: &str) -> Option<String> {
+    let ip: IpAddr = ip.parse().ok()?;
+
+    for leases in &["ipv4leases", "ipv6leases"] {
+        if let Some(output) = get_ipleases(client, leases) {
+            for &(ref iph, ref hostname) in &parse_ipleases(&output) {
+                if iph.parse().ok() == Some(ip) {
+                    return Some(hostname.to_owned());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+pub fn get_hostname_for_ip(ip: &str) -> Option<String> {
+    let mut client = Client::connect(DEFAULT_SOCKET).ok()?;
+    hostname_for_ip(&mut client, ip)
+}
+
+fn to_table(data: &HashMap<&str, &str>) -> Map<String, Value> {
+    data.iter()
+        .map(|(k, v)| ((*k).to_owned(), Value::String((*v).to_owned())))
+        .collect()
+}
+
+pub fn send_message(channel: &str, data: &HashMap<&str, &str>) {
+    let sent = Client::connect(DEFAULT_SOCKET)
+        .and_then(|mut client| client.send(channel, &to_table(data)));
+
+    if let Err(e) = sent {
+        warn!("error calling ubus: {}", e);
+    }
+}
+
+fn restart(client: &mut Client, service: &str) -> Result<()> {
+    let mut args = Map::new();
+    args.insert("name".to_owned(), json!(service));
+    args.insert("action".to_owned(), json!("restart"));
+
+    client.call("rc", "init", &args).map(|_| ())
+}
+
+/// Restarts the init script `service`, like `/etc/init.d/<service> restart`.
+pub fn restart_service(service: &str) -> Result<()> {
+    Client::connect(DEFAULT_SOCKET).and_then(|mut client| restart(&mut client, service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::FakeUbusd;
+    use serde_json;
+
+    const UBUS_IPLEASES_OUTPUT: &'static str = r#"
+    {
+        "device": {
+                "br-private": {
+                        "leases": [
+
+                        ]
+                },
+                "br-public": {
+                        "leases": [
+                                {
+                                        "mac": "macmacmac",
+                                        "hostname": "nixos",
+                                        "ip": "192.168.44.200",
+                                        "valid": -43175
+                                },
+                                {
+                                        "mac": "macmacmac",
+                                        "hostname": "android-b4283b7e2ffccd8",
+                                        "ip": "192.168.44.230",
+                                        "valid": -42406
+                                }
+                        ]
+                }
+        }
+   }"#;
+
+    #[test]
+    fn test_parse_ipleaeases() {
+        let hostnames = parse_ipleases(&serde_json::from_str(&UBUS_IPLEASES_OUTPUT).unwrap());
+        assert!(hostnames.contains(&(String::from("192.168.44.200"), String::from("nixos"))));
+        assert!(hostnames.contains(&(
+            String::from("192.168.44.230"),
+            String::from("android-b4283b7e2ffccd8")
+        )));
+    }
+
+    #[test]
+    fn test_hostname_for_ip() {
+        let ubusd = FakeUbusd::start();
+        ubusd.add_object("dhcp", |method, _| match method {
+            "ipv4leases" => Some(serde_json::from_str(UBUS_IPLEASES_OUTPUT).unwrap()),
+            "ipv6leases" => Some(json!({ "device": { "br-public": { "leases": [{
+                "duid": "000100012a8b",
+                "hostname": "laptop",
+                "ipv6-addr": [{ "address": "2001:db8:0:1::2c1", "preferred-lifetime": 3600 }]
+            }] } } })),
+            _ => None,
+        });
+
+        let mut client = Client::connect(&ubusd.path).unwrap();
+
+        assert_eq!(
+            hostname_for_ip(&mut client, "192.168.44.230"),
+            Some(String::from("android-b4283b7e2ffccd8"))
+        );
+        assert_eq!(
+            hostname_for_ip(&mut client, "2001:0db8:0:1::02c1"),
+            Some(String::from("laptop"))
+        );
+        assert_eq!(hostname_for_ip(&mut client, "192.168.44.1"), None);
+    }
+
+    #[test]
+    fn test_restart() {
+        let ubusd = FakeUbusd::start();
+        ubusd.add_object("rc", |method, args| match method {
+            "init" if args["name"] == json!("dnsmasq") && args["action"] == json!("restart") => {
+                Some(json!({}))
+            }
+            _ => None,
+        });
+
+        let mut client = Client::connect(&ubusd.path).unwrap();
+
+        assert!(restart(&mut client, "dnsmasq").is_ok());
+        assert!(restart(&mut client, "odhcpd").is_err());
+    }
+
+    #[test]
+    fn test_call_unknown_object() {
+        let ubusd = FakeUbusd::start();
+        let mut client = Client::connect(&ubusd.path).unwrap();
+
+        assert!(client.call("dhcp", "ipv4leases", &Map::new()).is_err());
+    }
+
+    #[test]
+    fn test_send_event() {
+        let ubusd = FakeUbusd::start();
+        let mut client = Client::connect(&ubusd.path).unwrap();
+
+        let mut data = HashMap::new();
+        data.insert("mac", "DE:AD:BE:EF:DE:AD");
+        client.send("/sentry/accept", &to_table(&data)).unwrap();
+
+        assert_eq!(
+            ubusd.events(),
+            vec![
+                (
+                    String::from("/sentry/accept"),
+                    json!({ "mac": "DE:AD:BE:EF:DE:AD" }),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let ubusd = FakeUbusd::start();
+        ubusd.add_object("hostapd.wlan0", |_, _| None);
+
+        let mut client = Client::connect(&ubusd.path).unwrap();
+        let subscriber = client.subscribe("hostapd.wlan0").unwrap();
+
+        let mut data = Map::new();
+        data.insert("address".to_owned(), json!("de:ad:be:ef:de:ad"));
+        ubusd.notify("hostapd.wlan0", "disassoc", &data);
+
+        let invocation = client.next_invocation().unwrap();
+        assert_eq!(invocation.object, subscriber);
+        assert_eq!(invocation.method, "disassoc");
+        assert_eq!(invocation.data, data);
+        assert!(invocation.no_reply);
+    }
+}
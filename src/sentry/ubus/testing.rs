@@ -0,0 +1,249 @@
+//! A small in-process ubusd for tests, speaking the wire protocol on a unix socket.
+//!
+//! It knows static objects with handlers, forwards calls to objects registered
+//! by clients, records sent events and delivers notifications to subscribers.
+
+use sentry::ubus::client::*;
+
+use std::collections::HashMap;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde_json::{Map, Value};
+
+use tempdir::TempDir;
+
+pub type Handler = Box<Fn(&str, &Map<String, Value>) -> Option<Value> + Send>;
+
+enum Owner {
+    Static(Handler),
+    Client(u32),
+}
+
+struct Object {
+    path: Option<String>,
+    owner: Owner,
+    subscribers: Vec<(u32, u32)>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u32,
+    clients: HashMap<u32, UnixStream>,
+    objects: HashMap<u32, Object>,
+    events: Vec<(String, Value)>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn send(&mut self, client: u32, msg: &Message) {
+        if let Some(stream) = self.clients.get_mut(&client) {
+            msg.write_to(stream).ok();
+        }
+    }
+
+    fn status(&mut self, client: u32, seq: u16, status: u32) {
+        let mut msg = Message::new(UBUS_MSG_STATUS, seq, 0);
+        msg.put_u32(UBUS_ATTR_STATUS, status);
+        self.send(client, &msg);
+    }
+}
+
+pub struct FakeUbusd {
+    pub path: PathBuf,
+    state: Arc<Mutex<State>>,
+    _dir: TempDir,
+}
+
+impl FakeUbusd {
+    pub fn start() -> FakeUbusd {
+        let dir = TempDir::new("ubusd").unwrap();
+        let path = dir.path().join("ubus.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let state = Arc::new(Mutex::new(State {
+            // ids below are reserved for ubusd's own objects
+            next_id: 16,
+            ..State::default()
+        }));
+
+        let accept_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let state = accept_state.clone();
+                    thread::spawn(move || serve(stream, state));
+                }
+            }
+        });
+
+        FakeUbusd {
+            path: path,
+            state: state,
+            _dir: dir,
+        }
+    }
+
+    /// Adds an object whose methods are answered by `handler`.
+    pub fn add_object<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&str, &Map<String, Value>) -> Option<Value> + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.objects.insert(
+            id,
+            Object {
+                path: Some(path.to_owned()),
+                owner: Owner::Static(Box::new(handler)),
+                subscribers: Vec::new(),
+            },
+        );
+    }
+
+    /// All events sent so far, as `(id, data)`.
+    pub fn events(&self) -> Vec<(String, Value)> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    /// Sends a notification from the object at `path` to its subscribers.
+    pub fn notify(&self, path: &str, kind: &str, data: &Map<String, Value>) {
+        let mut state = self.state.lock().unwrap();
+        let subscribers = state
+            .objects
+            .values()
+            .find(|o| o.path.as_ref().map(|p| p.as_str()) == Some(path))
+            .map(|o| o.subscribers.clone())
+            .unwrap_or_default();
+
+        for (client, object) in subscribers {
+            let mut msg = Message::new(UBUS_MSG_INVOKE, 0, client);
+            msg.put_u32(UBUS_ATTR_OBJID, object);
+            msg.put_string(UBUS_ATTR_METHOD, kind);
+            msg.put_table(UBUS_ATTR_DATA, data);
+            msg.put_u8(UBUS_ATTR_NO_REPLY, 1);
+            state.send(client, &msg);
+        }
+    }
+}
+
+fn serve(mut stream: UnixStream, state: Arc<Mutex<State>>) {
+    let client = {
+        let mut state = state.lock().unwrap();
+        let id = state.next_id();
+        state.clients.insert(id, stream.try_clone().unwrap());
+        state.send(id, &Message::new(UBUS_MSG_HELLO, 0, id));
+        id
+    };
+
+    while let Ok(msg) = Message::read_from(&mut stream) {
+        let mut state = state.lock().unwrap();
+        handle(&mut state, client, msg);
+    }
+
+    state.lock().unwrap().clients.remove(&client);
+}
+
+fn handle(state: &mut State, client: u32, msg: Message) {
+    match msg.msg_type {
+        UBUS_MSG_LOOKUP => {
+            let path = msg.get_string(UBUS_ATTR_OBJPATH).unwrap_or_default();
+            let id = state
+                .objects
+                .iter()
+                .find(|&(_, o)| o.path.as_ref() == Some(&path))
+                .map(|(id, _)| *id);
+
+            match id {
+                Some(id) => {
+                    let mut reply = Message::new(UBUS_MSG_DATA, msg.seq, 0);
+                    reply.put_string(UBUS_ATTR_OBJPATH, &path);
+                    reply.put_u32(UBUS_ATTR_OBJID, id);
+                    state.send(client, &reply);
+                    state.status(client, msg.seq, UBUS_STATUS_OK);
+                }
+                None => state.status(client, msg.seq, UBUS_STATUS_NOT_FOUND),
+            }
+        }
+        UBUS_MSG_ADD_OBJECT => {
+            let id = state.next_id();
+            state.objects.insert(
+                id,
+                Object {
+                    path: msg.get_string(UBUS_ATTR_OBJPATH),
+                    owner: Owner::Client(client),
+                    subscribers: Vec::new(),
+                },
+            );
+
+            let mut reply = Message::new(UBUS_MSG_DATA, msg.seq, 0);
+            reply.put_u32(UBUS_ATTR_OBJID, id);
+            state.send(client, &reply);
+            state.status(client, msg.seq, UBUS_STATUS_OK);
+        }
+        UBUS_MSG_SUBSCRIBE => {
+            let subscriber = msg.get_u32(UBUS_ATTR_OBJID).unwrap_or_default();
+            let target = msg.get_u32(UBUS_ATTR_TARGET).unwrap_or_default();
+
+            let status = match state.objects.get_mut(&target) {
+                Some(object) => {
+                    object.subscribers.push((client, subscriber));
+                    UBUS_STATUS_OK
+                }
+                None => UBUS_STATUS_NOT_FOUND,
+            };
+            state.status(client, msg.seq, status);
+        }
+        UBUS_MSG_INVOKE => invoke(state, client, msg),
+        UBUS_MSG_DATA | UBUS_MSG_STATUS => {
+            // replies of client objects, forwarded to the caller
+            let caller = msg.peer;
+            state.send(caller, &msg);
+        }
+        _ => state.status(client, msg.seq, UBUS_STATUS_METHOD_NOT_FOUND),
+    }
+}
+
+fn invoke(state: &mut State, client: u32, msg: Message) {
+    let id = msg.get_u32(UBUS_ATTR_OBJID).unwrap_or_default();
+    let method = msg.get_string(UBUS_ATTR_METHOD).unwrap_or_default();
+    let data = msg.get_table(UBUS_ATTR_DATA).unwrap_or_default();
+
+    if id == UBUS_SYSTEM_OBJECT_EVENT && method == "send" {
+        let event = data.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+        let event_data = data.get("data").cloned().unwrap_or(Value::Null);
+        state.events.push((event.to_owned(), event_data));
+        state.status(client, msg.seq, UBUS_STATUS_OK);
+        return;
+    }
+
+    let result = match state.objects.get(&id).map(|o| &o.owner) {
+        Some(&Owner::Static(ref handler)) => Some(handler(&method, &data)),
+        Some(&Owner::Client(owner)) => {
+            let mut forward = Message::new(UBUS_MSG_INVOKE, msg.seq, client);
+            forward.payload = msg.payload.clone();
+            state.send(owner, &forward);
+            return;
+        }
+        None => None,
+    };
+
+    match result {
+        Some(Some(Value::Object(reply_data))) => {
+            let mut reply = Message::new(UBUS_MSG_DATA, msg.seq, id);
+            reply.put_u32(UBUS_ATTR_OBJID, id);
+            reply.put_table(UBUS_ATTR_DATA, &reply_data);
+            state.send(client, &reply);
+            state.status(client, msg.seq, UBUS_STATUS_OK);
+        }
+        Some(Some(_)) => state.status(client, msg.seq, UBUS_STATUS_OK),
+        Some(None) => state.status(client, msg.seq, UBUS_STATUS_METHOD_NOT_FOUND),
+        None => state.status(client, msg.seq, UBUS_STATUS_NOT_FOUND),
+    }
+}
@@ -0,0 +1,290 @@
+//! The `sentry` ubus object, lets LuCI and scripts manage the authorized clients.
+//!
+//! ```text
+//! ubus call sentry list
+//! ubus call sentry authorize '{"mac": "DE:AD:BE:EF:00:11", "duration": 3600}'
+//! ubus call sentry authorize '{"mac": "DE:AD:BE:EF:00:11", "quota": 1073741824}'
+//! ubus call sentry revoke '{"mac": "DE:AD:BE:EF:00:11"}'
+//! ubus call sentry extend '{"mac": "DE:AD:BE:EF:00:11", "seconds": 3600}'
+//! ubus call sentry status
+//! ```
+
+use errors::*;
+use sentry::firewall::Rate;
+use sentry::ip;
+use sentry::sentry::Sentry;
+use sentry::ubus::{self, Client};
+
+use std::convert::TryFrom;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Map, Value};
+
+const OBJECT_PATH: &str = "sentry";
+const RECONNECT_INTERVAL_SECS: u64 = 5;
+
+fn signature() -> Map<String, Value> {
+    let mac = json!({ "mac": ubus::BLOBMSG_TYPE_STRING });
+
+    let mut signature = Map::new();
+    signature.insert("list".to_owned(), json!({}));
+    signature.insert(
+        "authorize".to_owned(),
+        json!({
+            "mac": ubus::BLOBMSG_TYPE_STRING,
+            "duration": ubus::BLOBMSG_TYPE_INT32,
+            "quota": ubus::BLOBMSG_TYPE_INT64,
+            "rate_up": ubus::BLOBMSG_TYPE_INT32,
+            "rate_down": ubus::BLOBMSG_TYPE_INT32,
+        }),
+    );
+    signature.insert("revoke".to_owned(), mac);
+    signature.insert(
+        "extend".to_owned(),
+        json!({ "mac": ubus::BLOBMSG_TYPE_STRING, "seconds": ubus::BLOBMSG_TYPE_INT32 }),
+    );
+    signature.insert("status".to_owned(), json!({}));
+    signature
+}
+
+fn mac_arg(data: &Map<String, Value>) -> Option<&str> {
+    data.get("mac")
+        .and_then(|mac| mac.as_str())
+        .filter(|mac| ip::is_mac(mac))
+}
+
+/// The optional unsigned integer argument `name`, an error if it is negative or no number.
+fn u64_arg(data: &Map<String, Value>, name: &str) -> Result<Option<u64>> {
+    match data.get(name) {
+        Some(value) => match value.as_u64() {
+            Some(value) => Ok(Some(value)),
+            None => bail!("invalid {}: {}", name, value),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Like `u64_arg`, also an error if the value does not fit an `u32`.
+fn u32_arg(data: &Map<String, Value>, name: &str) -> Result<Option<u32>> {
+    match u64_arg(data, name)? {
+        Some(value) => match u32::try_from(value) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => bail!("{} out of range: {}", name, value),
+        },
+        None => Ok(None),
+    }
+}
+
+fn to_reply(result: Result<Value>) -> (Option<Map<String, Value>>, u32) {
+    match result {
+        Ok(Value::Object(map)) => (Some(map), ubus::UBUS_STATUS_OK),
+        Ok(_) => (None, ubus::UBUS_STATUS_OK),
+        Err(e) => {
+            warn!("ubus request failed: {}", e);
+            (None, ubus::UBUS_STATUS_UNKNOWN_ERROR)
+        }
+    }
+}
+
+/// Executes a method of the sentry object, returns the reply data and the ubus status.
+fn dispatch(
+    sentry: &Sentry,
+    method: &str,
+    data: &Map<String, Value>,
+) -> (Option<Map<String, Value>>, u32) {
+    match method {
+        "list" => to_reply(sentry.sessions().and_then(|sessions| {
+            serde_json::to_value(sessions)
+                .map(|sessions| json!({ "sessions": sessions }))
+                .chain_err(|| "unable to serialize the sessions")
+        })),
+        "authorize" => match (
+            mac_arg(data),
+            u32_arg(data, "duration"),
+            u64_arg(data, "quota"),
+            u32_arg(data, "rate_up"),
+            u32_arg(data, "rate_down"),
+        ) {
+            (Some(mac), Ok(duration), Ok(quota), Ok(up), Ok(down)) => {
+                let rate = Rate { up, down };
+                to_reply(
+                    sentry
+                        .authorize_mac(mac, None, duration, quota, rate)
+                        .map(|_| Value::Null),
+                )
+            }
+            _ => (None, ubus::UBUS_STATUS_INVALID_ARGUMENT),
+        },
+        "revoke" => match mac_arg(data) {
+            Some(mac) => to_reply(sentry.revoke_mac(mac).map(|_| Value::Null)),
+            None => (None, ubus::UBUS_STATUS_INVALID_ARGUMENT),
+        },
+        // like the http api, sessions are only extended
+        "extend" => match (mac_arg(data), u32_arg(data, "seconds")) {
+            (Some(mac), Ok(Some(seconds))) => {
+                to_reply(sentry.extend_mac(mac, i64::from(seconds)).map(|_| Value::Null))
+            }
+            _ => (None, ubus::UBUS_STATUS_INVALID_ARGUMENT),
+        },
+        "status" => to_reply(sentry.sessions().map(|sessions| {
+            let portal = sentry.portal();
+            let zones: Vec<&str> = portal.zones.iter().map(|z| z.name.as_str()).collect();
+            json!({
+                "identity": sentry.identity,
+                "expires": portal.zone(None).expires,
+                "zones": zones,
+                "sessions": sessions.len(),
+            })
+        })),
+        _ => (None, ubus::UBUS_STATUS_METHOD_NOT_FOUND),
+    }
+}
+
+/// Registers the sentry object and answers its method calls until the connection fails.
+pub fn serve(client: &mut Client, sentry: &Sentry) -> Result<()> {
+    client.add_object(Some(OBJECT_PATH), &signature())?;
+
+    loop {
+        let invocation = client.next_invocation()?;
+        let (data, status) = dispatch(sentry, &invocation.method, &invocation.data);
+        client.reply(&invocation, data.as_ref(), status)?;
+    }
+}
+
+/// Keeps the sentry object registered, reconnecting whenever ubus goes away.
+pub fn run(sentry: Sentry) {
+    loop {
+        if let Err(e) = Client::connect(ubus::DEFAULT_SOCKET)
+            .and_then(|mut client| serve(&mut client, &sentry))
+        {
+            error!("sentry ubus object down: {}", e);
+        }
+
+        thread::sleep(Duration::from_secs(RECONNECT_INTERVAL_SECS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::sentry::testing;
+    use sentry::ubus::testing::FakeUbusd;
+
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    const TEST_MAC: &str = "DE:AD:BE:EF:00:11";
+
+    fn sentry(core: &Core, dir: &TempDir) -> Sentry {
+        testing::sentry(core, dir.path())
+    }
+
+    fn args(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let dir = TempDir::new("ubus_object").unwrap();
+        let core = Core::new().unwrap();
+        let sentry = sentry(&core, &dir);
+
+        let (_, status) = dispatch(
+            &sentry,
+            "authorize",
+            &args(json!({ "mac": TEST_MAC, "duration": 60 })),
+        );
+        assert_eq!(status, ubus::UBUS_STATUS_OK);
+
+        let (data, _) = dispatch(&sentry, "list", &Map::new());
+        let sessions = data.unwrap()["sessions"].clone();
+        assert_eq!(sessions[0]["mac"], json!(TEST_MAC));
+        let authorized = sessions[0]["authorized"].as_i64().unwrap();
+        let expires = sessions[0]["expires"].as_i64().unwrap();
+        assert_eq!(expires, authorized + 60);
+
+        let (_, status) = dispatch(
+            &sentry,
+            "extend",
+            &args(json!({ "mac": TEST_MAC, "seconds": 60 })),
+        );
+        assert_eq!(status, ubus::UBUS_STATUS_OK);
+        assert_eq!(sentry.sessions().unwrap()[0].expires, Some(expires + 60));
+
+        let (_, status) = dispatch(&sentry, "revoke", &args(json!({ "mac": TEST_MAC })));
+        assert_eq!(status, ubus::UBUS_STATUS_OK);
+        assert!(sentry.sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_invalid() {
+        let dir = TempDir::new("ubus_object").unwrap();
+        let core = Core::new().unwrap();
+        let sentry = sentry(&core, &dir);
+
+        let (_, status) = dispatch(&sentry, "authorize", &args(json!({ "mac": "nope" })));
+        assert_eq!(status, ubus::UBUS_STATUS_INVALID_ARGUMENT);
+
+        let (_, status) = dispatch(&sentry, "extend", &args(json!({ "mac": TEST_MAC })));
+        assert_eq!(status, ubus::UBUS_STATUS_INVALID_ARGUMENT);
+
+        // out of range values are refused rather than truncated
+        for data in &[
+            json!({ "mac": TEST_MAC, "duration": -60 }),
+            json!({ "mac": TEST_MAC, "duration": 4294967296u64 }),
+            json!({ "mac": TEST_MAC, "quota": -1 }),
+            json!({ "mac": TEST_MAC, "rate_up": 4294967296u64 }),
+            json!({ "mac": TEST_MAC, "rate_down": "fast" }),
+        ] {
+            let (_, status) = dispatch(&sentry, "authorize", &args(data.clone()));
+            assert_eq!(status, ubus::UBUS_STATUS_INVALID_ARGUMENT, "{}", data);
+        }
+        assert!(sentry.sessions().unwrap().is_empty());
+
+        let (_, status) = dispatch(
+            &sentry,
+            "authorize",
+            &args(json!({ "mac": TEST_MAC, "duration": 60 })),
+        );
+        assert_eq!(status, ubus::UBUS_STATUS_OK);
+        let expires = sentry.sessions().unwrap()[0].expires;
+        for &seconds in &[-60, 4294967296i64] {
+            let data = json!({ "mac": TEST_MAC, "seconds": seconds });
+            let (_, status) = dispatch(&sentry, "extend", &args(data));
+            assert_eq!(status, ubus::UBUS_STATUS_INVALID_ARGUMENT);
+        }
+        assert_eq!(sentry.sessions().unwrap()[0].expires, expires);
+
+        let (_, status) = dispatch(&sentry, "reboot", &Map::new());
+        assert_eq!(status, ubus::UBUS_STATUS_METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_serve() {
+        let ubusd = FakeUbusd::start();
+        let dir = TempDir::new("ubus_object").unwrap();
+        let core = Core::new().unwrap();
+        let sentry = sentry(&core, &dir);
+
+        let mut server = Client::connect(&ubusd.path).unwrap();
+        let server_sentry = sentry.clone();
+        thread::spawn(move || serve(&mut server, &server_sentry));
+
+        let mut client = Client::connect(&ubusd.path).unwrap();
+        while client.lookup_id(OBJECT_PATH).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        client
+            .call(OBJECT_PATH, "authorize", &args(json!({ "mac": TEST_MAC })))
+            .unwrap();
+
+        let status = client.call(OBJECT_PATH, "status", &Map::new()).unwrap();
+        assert_eq!(status["identity"], json!("identity"));
+        assert_eq!(status["sessions"], json!(1));
+        assert_eq!(status["zones"], json!(["public"]));
+
+        assert!(client.call(OBJECT_PATH, "revoke", &Map::new()).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! The walled garden, destinations unauthorized clients may reach.
+//!
+//! Entries of the `walled_garden` list in the captif config are either domains
+//! (`pay.example.com`), wildcard domains (`*.example.com`, matching subdomains only)
+//! or networks (`192.0.2.0/24`, `198.51.100.7`). Networks are accepted by the firewall,
+//! the addresses of domains are added to a firewall set by dnsmasq when they are resolved.
+//! Plain http requests for domains are proxied by the captive service as well.
+
+use errors::*;
+use sentry::dnsmasq;
+use sentry::firewall::Firewall;
+
+use std::fmt;
+use std::net::IpAddr;
+
+/// An ip network in CIDR notation.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Network {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    pub fn parse(s: &str) -> Result<Network> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .chain_err(|| format!("invalid network address: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid network prefix: {}", s))?,
+            None => max,
+        };
+
+        Ok(Network { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = (!0u32).checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = (!0u128).checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn is_domain(s: &str) -> bool {
+    !s.is_empty() && s.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && !label.starts_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct WalledGarden {
+    domains: Vec<String>,
+    /// The suffixes of the wildcard domains, including the leading dot.
+    wildcards: Vec<String>,
+    networks: Vec<Network>,
+}
+
+impl WalledGarden {
+    pub fn parse(entries: &[String]) -> Result<WalledGarden> {
+        let mut garden = WalledGarden::default();
+
+        for entry in entries {
+            let entry = normalize(entry.trim());
+
+            if entry.starts_with("*.") && is_domain(&entry[2..]) {
+                garden.wildcards.push(entry[1..].to_owned());
+            } else if entry.contains('/') || entry.parse::<IpAddr>().is_ok() {
+                garden.networks.push(Network::parse(&entry)?);
+            } else if is_domain(&entry) {
+                garden.domains.push(entry);
+            } else {
+                bail!("invalid walled garden entry: {}", entry);
+            }
+        }
+
+        Ok(garden)
+    }
+
+    /// The networks the firewall lets unauthorized clients reach.
+    pub fn networks(&self) -> &[Network] {
+        &self.networks
+    }
+
+    /// The domains dnsmasq has to add to the firewall, wildcards as their parent domain.
+    ///
+    /// dnsmasq always matches subdomains too, so on the firewall level a domain includes
+    /// its subdomains and a wildcard includes its parent.
+    pub fn dns_domains(&self) -> Vec<&str> {
+        self.domains
+            .iter()
+            .map(|d| d.as_str())
+            .chain(self.wildcards.iter().map(|w| &w[1..]))
+            .collect()
+    }
+
+    /// Checks if unauthorized clients may reach `host`, a domain or an ip address.
+    pub fn allows_host(&self, host: &str) -> bool {
+        if let Ok(ip) = host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+            return self.networks.iter().any(|network| network.contains(&ip));
+        }
+
+        let host = normalize(host);
+        self.domains.iter().any(|domain| *domain == host)
+            || self.wildcards.iter().any(|suffix| host.ends_with(suffix.as_str()))
+    }
+}
+
+/// Sets up the firewall and dnsmasq for the walled garden.
+pub fn apply(firewall: &Firewall, garden: &WalledGarden) -> Result<()> {
+    firewall.set_walled_garden(garden.networks())?;
+    dnsmasq::update(firewall, garden)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn garden(entries: &[&str]) -> Result<WalledGarden> {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        WalledGarden::parse(&entries)
+    }
+
+    #[test]
+    fn test_network() {
+        let network = Network::parse("192.0.2.0/24").unwrap();
+        assert!(network.contains(&"192.0.2.77".parse().unwrap()));
+        assert!(!network.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(!network.contains(&"2001:db8::1".parse().unwrap()));
+        assert_eq!(network.to_string(), "192.0.2.0/24");
+
+        assert_eq!(Network::parse("198.51.100.7").unwrap().prefix, 32);
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains(&"8.8.8.8".parse().unwrap()));
+        assert!(Network::parse("2001:db8::/32").unwrap().contains(&"2001:db8:1::1".parse().unwrap()));
+
+        assert!(Network::parse("192.0.2.0/33").is_err());
+        assert!(Network::parse("example.com/24").is_err());
+    }
+
+    #[test]
+    fn test_allows_host() {
+        let garden = garden(&["pay.example.com", "*.cdn.example.net", "192.0.2.0/24"]).unwrap();
+
+        assert!(garden.allows_host("pay.example.com"));
+        assert!(garden.allows_host("PAY.example.com."));
+        assert!(!garden.allows_host("evil.pay.example.com"));
+        assert!(!garden.allows_host("example.com"));
+
+        assert!(garden.allows_host("a.cdn.example.net"));
+        assert!(garden.allows_host("a.b.cdn.example.net"));
+        assert!(!garden.allows_host("cdn.example.net"));
+        assert!(!garden.allows_host("evilcdn.example.net"));
+
+        assert!(garden.allows_host("192.0.2.1"));
+        assert!(!garden.allows_host("192.0.3.1"));
+
+        assert_eq!(garden.networks(), &[Network::parse("192.0.2.0/24").unwrap()]);
+        assert_eq!(garden.dns_domains(), vec!["pay.example.com", "cdn.example.net"]);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(garden(&["*"]).is_err());
+        assert!(garden(&["*.com*"]).is_err());
+        assert!(garden(&["exa mple.com"]).is_err());
+        assert!(garden(&["10.0.0.0/40"]).is_err());
+        assert!(garden(&[]).unwrap() == WalledGarden::default());
+    }
+}
@@ -11,54 +11,106 @@ use serde_json;
 
 pub const PUBLIC_WIFI_RADIOS: &[&str] = &["a", "g"];
 pub const PUBLIC_WIFI_TIME_CONTROL_PATH: &str = "/etc/zealot.pub.tc";
+/// The timezone of time controls without one.
+pub const DEFAULT_TIMEZONE: &str = "Europe/Berlin";
 
 /// Stores information about the wifi up times.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(default)]
 pub struct TimeControl {
     /// The vector of vectors that contain the wifi up times.
     /// The up time is given as hour in 24 hour format.
     up_time: Vec<Vec<u8>>,
-    /// The timezone of the given up times.
-    timezone: String,
+    /// The timezone of the given up times, the default timezone if not set.
+    timezone: Option<String>,
 }
 
-impl Default for TimeControl {
-    fn default() -> TimeControl {
-        TimeControl {
-            up_time: vec![],
-            timezone: "Europe/Berlin".to_string(),
+impl TimeControl {
+    /// Checks that the timezone is known.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref timezone) = self.timezone {
+            timezone.parse::<Tz>()?;
         }
+        Ok(())
     }
 }
 
 /// Checks if the current status of the public wifi corresponds to the configured
 /// up times.
 /// If the status does not match, the public wifi is activated/deactivated.
+/// Without readable up times the public wifi is up all day.
 pub fn check_public_wifi() -> Result<()> {
-    let wifi_status = is_pub_wifi_enabled().unwrap_or(false);
-    let req_wifi_status = get_current_requested_wifi_status().unwrap_or(true);
+    let time_control = load_public_time_control().unwrap_or_else(|e| {
+        debug!("public wifi up all day: {}", e);
+        TimeControl::default()
+    });
+    let radios: Vec<String> = PUBLIC_WIFI_RADIOS
+        .iter()
+        .map(|standard| format!("wpublic{}", standard))
+        .collect();
 
-    if wifi_status != req_wifi_status {
-        for standard in PUBLIC_WIFI_RADIOS {
-            change_wifi_status(req_wifi_status, standard);
+    check_wifi(&radios, &time_control, DEFAULT_TIMEZONE)
+}
+
+fn load_public_time_control() -> Result<TimeControl> {
+    let time_control =
+        File::open(PUBLIC_WIFI_TIME_CONTROL_PATH).chain_err(|| "error reading time control file")?;
+    serde_json::from_reader(time_control).chain_err(|| "error parsing time control file")
+}
+
+/// Checks if the status of the given wifi interfaces, uci `wifi-iface` sections,
+/// corresponds to the up times of `time_control` and switches the ones that do not.
+///
+/// Only the wifi devices of switched interfaces are reloaded. Nothing is switched if
+/// the status of the interfaces can not be read, interfaces that do not exist are
+/// skipped.
+pub fn check_wifi(
+    radios: &[String],
+    time_control: &TimeControl,
+    default_timezone: &str,
+) -> Result<()> {
+    if radios.is_empty() {
+        return Ok(());
+    }
+
+    let req_wifi_status =
+        get_current_requested_wifi_status(time_control, default_timezone).unwrap_or(true);
+    let wireless = uci_show_wireless()?;
+
+    let mut devices: Vec<String> = Vec::new();
+    for (radio, device) in wifi_switches(&wireless, radios, req_wifi_status) {
+        change_wifi_status(req_wifi_status, radio)?;
+        if !devices.contains(&device) {
+            devices.push(device);
         }
+    }
 
-        // activate the changes
-        let _ = Command::new("wifi").output();
+    // activate the changes
+    for device in devices {
+        run(Command::new("wifi").args(&["reload", &device]))?;
     }
 
     Ok(())
 }
 
-fn change_wifi_status(enable: bool, standard: &str) {
+/// Runs `command`, an error if it fails.
+fn run(command: &mut Command) -> Result<()> {
+    let output = command
+        .output()
+        .chain_err(|| format!("error running {:?}", command))?;
+    if !output.status.success() {
+        bail!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn change_wifi_status(enable: bool, radio: &str) -> Result<()> {
     let disabled = if enable { 0 } else { 1 };
-    let _ = Command::new("uci")
-        .args(&[
-            "set",
-            &format!("wireless.wpublic{}.disabled={}", standard, disabled),
-        ])
-        .output();
+    run(Command::new("uci").args(&["set", &format!("wireless.{}.disabled={}", radio, disabled)]))
 }
 
 /// Checks, based on the time control, if the wifi should be on or off at the time
@@ -68,13 +120,15 @@ fn change_wifi_status(enable: bool, standard: &str) {
 ///
 /// True => wifi on
 /// False => wifi off
-fn get_current_requested_wifi_status() -> Result<bool> {
-    let time_control =
-        File::open(PUBLIC_WIFI_TIME_CONTROL_PATH).chain_err(|| "error reading time control file")?;
-    let time_control: TimeControl =
-        serde_json::from_reader(time_control).chain_err(|| "error parsing time control file")?;
-
-    let timezone: Tz = time_control.timezone.parse()?;
+fn get_current_requested_wifi_status(
+    time_control: &TimeControl,
+    default_timezone: &str,
+) -> Result<bool> {
+    let timezone: Tz = time_control
+        .timezone
+        .as_ref()
+        .map_or(default_timezone, |t| t.as_str())
+        .parse()?;
 
     let now = timezone.from_utc_datetime(&Utc::now().naive_utc());
 
@@ -107,33 +161,83 @@ fn weekday_to_index(wday: Weekday) -> usize {
     }
 }
 
-/// Returns if the public wifi is currently enabled
-fn is_pub_wifi_enabled() -> Result<bool> {
+fn uci_show_wireless() -> Result<String> {
     let uci_show = Command::new("uci")
         .args(&["show", "wireless"])
         .output()
         .chain_err(|| "error running uci show wireless")?;
+    if !uci_show.status.success() {
+        bail!(
+            "uci show wireless failed: {}",
+            String::from_utf8_lossy(&uci_show.stderr).trim()
+        );
+    }
 
-    let uci_show = String::from_utf8(uci_show.stdout)
-        .chain_err(|| "error parsing uci show wireless as utf8 string")?;
+    String::from_utf8(uci_show.stdout)
+        .chain_err(|| "error parsing uci show wireless as utf8 string")
+}
 
-    Ok(is_pub_wifi_enabled_impl(&uci_show))
+/// A wifi interface and the wifi device it is on.
+#[derive(PartialEq, Debug)]
+struct WifiIface {
+    enabled: bool,
+    device: String,
 }
 
-fn is_pub_wifi_enabled_impl(output: &str) -> bool {
-    for standard in PUBLIC_WIFI_RADIOS {
-        if output.contains(&format!("wireless.wpublic{}.disabled='1'", standard)) {
-            return false;
-        }
-    }
+/// Extracts the wifi interface `name` from the output of `uci show wireless`, `None`
+/// if there is no such interface or it has no device.
+fn parse_wifi_iface(output: &str, name: &str) -> Option<WifiIface> {
+    let option = |key: &str| {
+        let prefix = format!("wireless.{}.{}=", name, key);
+        output
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| line[prefix.len()..].trim_matches('\''))
+    };
+
+    Some(WifiIface {
+        enabled: option("disabled") != Some("1"),
+        device: option("device")?.to_owned(),
+    })
+}
 
-    true
+/// The interfaces among `radios` not switched to `enable` yet with their devices,
+/// after the output of `uci show wireless`. Interfaces that do not exist are skipped.
+fn wifi_switches<'a>(output: &str, radios: &'a [String], enable: bool) -> Vec<(&'a str, String)> {
+    radios
+        .iter()
+        .filter_map(|radio| match parse_wifi_iface(output, radio) {
+            Some(iface) => if iface.enabled != enable {
+                Some((radio.as_str(), iface.device))
+            } else {
+                None
+            },
+            None => {
+                warn!("no wifi-iface {} with a device, not switching it", radio);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns if the given wifi interfaces are enabled, after the output of
+/// `uci show wireless`.
+#[cfg(test)]
+fn is_wifi_enabled_impl(output: &str, radios: &[String]) -> bool {
+    radios
+        .iter()
+        .all(|radio| parse_wifi_iface(output, radio).map_or(true, |iface| iface.enabled))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn is_pub_wifi_enabled_impl(output: &str) -> bool {
+        is_wifi_enabled_impl(output, &["wpublica".to_owned(), "wpublicg".to_owned()])
+    }
+
     const UCI_SHOW_NO_DISABLED: &str = r#"
         wireless.wpublicg=wifi-iface
         wireless.wpublicg.device='radio1'
@@ -182,5 +286,45 @@ mod tests {
     #[test]
     fn uci_show_disabled_parse() {
         assert!(!is_pub_wifi_enabled_impl(UCI_SHOW_DISABLED));
+        assert!(is_wifi_enabled_impl(UCI_SHOW_DISABLED, &["wlobby".to_owned()]));
+    }
+
+    #[test]
+    fn test_parse_wifi_iface() {
+        assert_eq!(
+            parse_wifi_iface(UCI_SHOW_DISABLED, "wpublicg"),
+            Some(WifiIface {
+                enabled: false,
+                device: "radio1".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse_wifi_iface(UCI_SHOW_ENABLED, "wpublicg").map(|iface| iface.enabled),
+            Some(true)
+        );
+        assert_eq!(parse_wifi_iface(UCI_SHOW_ENABLED, "wpublic"), None);
+    }
+
+    #[test]
+    fn test_wifi_switches() {
+        let radios = vec!["wpublicg".to_owned(), "wlobby".to_owned()];
+        assert_eq!(
+            wifi_switches(UCI_SHOW_DISABLED, &radios, true),
+            vec![("wpublicg", "radio1".to_owned())]
+        );
+        assert!(wifi_switches(UCI_SHOW_DISABLED, &radios, false).is_empty());
+        assert!(wifi_switches(UCI_SHOW_ENABLED, &radios, true).is_empty());
+    }
+
+    #[test]
+    fn test_default_up_all_day() {
+        // what the public wifi gets without readable up times
+        assert!(get_current_requested_wifi_status(&TimeControl::default(), DEFAULT_TIMEZONE).unwrap());
+    }
+
+    #[test]
+    fn test_check_wifi_without_radios() {
+        // switches nothing, there is no uci to ask in tests
+        assert!(check_wifi(&[], &TimeControl::default(), DEFAULT_TIMEZONE).is_ok());
     }
 }